@@ -7,7 +7,7 @@ use futures::TryFutureExt;
 use moka::future::{Cache, CacheBuilder};
 use quic_tunnel::counters::TunnelCounters;
 use quic_tunnel::log::configure_logging;
-use quic_tunnel::{get_tunnel_timeout, TunnelCacheKey};
+use quic_tunnel::{timeouts::Timeouts, TunnelCacheKey};
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
 use tokio::{
@@ -34,7 +34,7 @@ struct UdpTunnel {
 async fn main() -> anyhow::Result<()> {
     let command: UdpTunnel = argh::from_env();
 
-    configure_logging();
+    configure_logging(Default::default())?;
 
     let local_socket = UdpSocket::bind(command.local_addr).await?;
 
@@ -64,7 +64,7 @@ async fn main() -> anyhow::Result<()> {
     // put the sockets inside Arcs so we can share them between tasks
     let local_socket = Arc::new(local_socket);
 
-    let default_timeout = get_tunnel_timeout();
+    let default_timeout = Timeouts::default().udp_flow_idle;
 
     let counts = TunnelCounters::new();
 
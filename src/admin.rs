@@ -0,0 +1,357 @@
+//! A local Unix socket for inspecting and controlling a running tunnel.
+//!
+//! The protocol is one command per line. Every command gets one line of JSON back.
+//! `quic-tunnel ctl` speaks this for you.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, trace, warn};
+
+use crate::counters::CountersRegistry;
+use crate::{events, TunnelCache};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    /// list open connections with their RTT and stats
+    Connections,
+    /// count the open streams on each connection
+    Streams,
+    /// list the UDP flows. Clients keep them in the TunnelCache. Servers have one per stream
+    Cache,
+    /// process counters and the top talkers
+    Counters,
+    /// close a connection by id
+    Drop(usize),
+    /// forget UDP flows on clients and close their streams on servers. None flushes everything
+    Flush(Option<SocketAddr>),
+}
+
+impl FromStr for AdminCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let command = words.next().context("empty command")?;
+        let arg = words.next();
+
+        let x = match (command, arg) {
+            ("connections", None) => Self::Connections,
+            ("streams", None) => Self::Streams,
+            ("cache", None) => Self::Cache,
+            ("counters", None) => Self::Counters,
+            ("drop", Some(id)) => Self::Drop(id.parse().context("invalid connection id")?),
+            ("flush", None) => Self::Flush(None),
+            ("flush", Some(from)) => Self::Flush(Some(from.parse().context("invalid address")?)),
+            _ => anyhow::bail!(
+                "unknown command: {}. try connections, streams, cache, counters, drop <id>, or flush [from]",
+                s
+            ),
+        };
+
+        if words.next().is_some() {
+            anyhow::bail!("too many arguments: {}", s);
+        }
+
+        Ok(x)
+    }
+}
+
+/// Everything the admin socket can see.
+#[derive(Clone)]
+pub struct AdminState {
+    counters: Arc<CountersRegistry>,
+    /// keyed by tunnel name. shared so that tunnels can come and go on reload
+    tunnel_caches: Arc<RwLock<BTreeMap<String, TunnelCache>>>,
+}
+
+impl AdminState {
+    pub fn new(counters: Arc<CountersRegistry>) -> Self {
+        Self {
+            counters,
+            tunnel_caches: Default::default(),
+        }
+    }
+
+    pub fn with_tunnel_cache(self, name: &str, tunnel_cache: TunnelCache) -> Self {
+        self.add_tunnel_cache(name, tunnel_cache);
+        self
+    }
+
+    /// can be called multiple times if there are multiple UDP tunnels
+    pub fn add_tunnel_cache(&self, name: &str, tunnel_cache: TunnelCache) {
+        self.tunnel_caches
+            .write()
+            .unwrap()
+            .insert(name.to_string(), tunnel_cache);
+    }
+
+    pub fn remove_tunnel_cache(&self, name: &str) {
+        self.tunnel_caches.write().unwrap().remove(name);
+    }
+
+    /// clone the caches so that the lock isn't held across an await
+    fn tunnel_caches(&self) -> Vec<TunnelCache> {
+        self.tunnel_caches
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn handle(&self, command: AdminCommand) -> anyhow::Result<Value> {
+        let x = match command {
+            AdminCommand::Connections => {
+                let connections: Vec<_> = self
+                    .counters
+                    .connections()
+                    .into_iter()
+                    .map(|(conn, counts)| {
+                        let stats = conn.stats();
+
+                        json!({
+                            "id": conn.stable_id(),
+                            "remote": conn.remote_address(),
+                            "peer": events::peer_subject(&conn),
+                            "rtt_ms": conn.rtt().as_secs_f64() * 1000.0,
+                            "cwnd": stats.path.cwnd,
+                            "congestion_events": stats.path.congestion_events,
+                            "sent_packets": stats.path.sent_packets,
+                            "lost_packets": stats.path.lost_packets,
+                            "lost_bytes": stats.path.lost_bytes,
+                            "udp_tx_bytes": stats.udp_tx.bytes,
+                            "udp_rx_bytes": stats.udp_rx.bytes,
+                            "counts": counts.snapshot(),
+                        })
+                    })
+                    .collect();
+
+                json!({ "connections": connections })
+            }
+            AdminCommand::Streams => {
+                let streams: Vec<_> = self
+                    .counters
+                    .connections()
+                    .into_iter()
+                    .map(|(conn, counts)| {
+                        json!({
+                            "id": conn.stable_id(),
+                            "remote": conn.remote_address(),
+                            "open_streams": counts.snapshot().open_streams(),
+                        })
+                    })
+                    .collect();
+
+                json!({ "streams": streams })
+            }
+            AdminCommand::Cache => {
+                let mut keys: Vec<_> = self
+                    .tunnel_caches()
+                    .iter()
+                    .flat_map(|cache| cache.iter().map(|(key, _)| *key))
+                    .collect();
+
+                keys.extend(self.counters.stream_flows());
+
+                json!({ "cache": keys })
+            }
+            AdminCommand::Counters => {
+                json!({
+                    "process": self.counters.process().snapshot(),
+                    "top_talkers": self.counters.top_talkers(10),
+                })
+            }
+            AdminCommand::Drop(id) => {
+                let conn = self
+                    .counters
+                    .get_connection(id)
+                    .await
+                    .context("connection not found")?;
+
+                info!(id, remote=%conn.remote_address(), "dropping connection");
+
+                conn.close(0u32.into(), b"closed by admin");
+
+                json!({ "dropped": id })
+            }
+            AdminCommand::Flush(from) => {
+                let mut keys = vec![];
+
+                for cache in self.tunnel_caches().iter() {
+                    let flushed: Vec<_> = cache
+                        .iter()
+                        .map(|(key, _)| *key)
+                        .filter(|key| from.is_none_or(|from| key.from == from))
+                        .collect();
+
+                    for key in flushed.iter() {
+                        cache.invalidate(key).await;
+                        self.counters.remove_flow(key).await;
+                    }
+
+                    keys.extend(flushed);
+                }
+
+                // servers have a stream for each flow. their counters are removed once it closes
+                keys.extend(self.counters.close_stream_flows(from));
+
+                info!(?from, flushed = keys.len(), "flushed UDP flows");
+
+                json!({ "flushed": keys })
+            }
+        };
+
+        Ok(x)
+    }
+
+    async fn handle_client(self, stream: UnixStream) -> anyhow::Result<()> {
+        let (read_half, mut write_half) = stream.into_split();
+
+        let mut lines = BufReader::new(read_half).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            trace!(%line, "admin command");
+
+            let response = match line.parse::<AdminCommand>() {
+                Ok(command) => self.handle(command).await,
+                Err(err) => Err(err),
+            };
+
+            let response = response.unwrap_or_else(|err| json!({ "error": err.to_string() }));
+
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+
+            write_half.write_all(&response).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Listen on a Unix socket for admin commands.
+///
+/// The socket is only accessible by the current user. A stale socket at `path` is removed.
+///
+/// If `path` is None, the task never finishes. That way it can always be in a `select!`.
+pub fn spawn_admin_socket(
+    path: Option<PathBuf>,
+    state: AdminState,
+) -> tokio::task::JoinHandle<anyhow::Result<()>> {
+    let path = match path {
+        Some(path) => path,
+        None => return tokio::spawn(std::future::pending()),
+    };
+
+    let f = async move {
+        if let Ok(metadata) = tokio::fs::symlink_metadata(&path).await {
+            if metadata.file_type().is_socket() {
+                tokio::fs::remove_file(&path).await?;
+            } else {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+        }
+
+        let listener = bind_private(&path).await?;
+
+        info!("admin socket listening at {}", path.display());
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(err) = state.handle_client(stream).await {
+                            debug!(?err, "admin client failed");
+                        }
+                    });
+                }
+                Err(err) => warn!(?err, "admin accept failed"),
+            }
+        }
+    };
+
+    tokio::spawn(f)
+}
+
+/// Bind the socket inside a directory only we can use and then move it to `path`.
+///
+/// Binding at `path` directly would leave it open to other users until the chmod.
+async fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?;
+
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(file_name);
+    dir_name.push(".d");
+
+    let dir = path.with_file_name(dir_name);
+
+    // left behind by a crash
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .await
+        .with_context(|| format!("failed creating {}", dir.display()))?;
+
+    let tmp = dir.join("socket");
+
+    let listener = async {
+        let x = UnixListener::bind(&tmp)?;
+
+        tokio::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600)).await?;
+        tokio::fs::rename(&tmp, path).await?;
+
+        Ok::<_, std::io::Error>(x)
+    }
+    .await
+    .with_context(|| format!("failed binding {}", path.display()));
+
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+
+    listener
+}
+
+/// Send one command to an admin socket and return the response.
+pub async fn send_admin_command(path: PathBuf, command: &str) -> anyhow::Result<Value> {
+    // parse locally so typos get a nice error even if the server is old
+    command.parse::<AdminCommand>()?;
+
+    let stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("failed connecting to {}", path.display()))?;
+
+    let (read_half, mut write_half) = stream.into_split();
+
+    write_half.write_all(command.as_bytes()).await?;
+    write_half.write_all(b"\n").await?;
+    write_half.shutdown().await?;
+
+    let line = BufReader::new(read_half)
+        .lines()
+        .next_line()
+        .await?
+        .context("admin socket closed without a response")?;
+
+    let response: Value = serde_json::from_str(&line)?;
+
+    if let Some(err) = response.get("error") {
+        anyhow::bail!("admin error: {}", err);
+    }
+
+    Ok(response)
+}
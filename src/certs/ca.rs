@@ -1,39 +1,117 @@
-/// TODO: this uses blocking IO! Use tokio instead!
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use rcgen::{Certificate, CertificateParams, KeyPair, RcgenError};
-use tracing::info;
+use anyhow::Context;
+use rcgen::{
+    Certificate, CertificateParams, CertificateRevocationList, CertificateRevocationListParams,
+    CertificateSigningRequest, KeyIdMethod, KeyPair, RcgenError, RevokedCertParams, SerialNumber,
+};
+use time::{Duration, OffsetDateTime};
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
 
-use crate::certs::DEFAULT_ALG;
+use crate::certs::crl::{format_serial, revoked_certs};
+use crate::certs::{
+    cert_from_pem, cert_from_pem_async, encrypt_key_pem, key_pair_from_pem, random_serial,
+    write_atomic, write_atomic_async, write_private, write_private_async, KeyAlgo, SubjectNames,
+    TunnelEnd, Validity,
+};
+
+/// rustls doesn't check the next update time yet. Run `quick_certs revoke` again before then anyway
+const CRL_VALIDITY: Duration = Duration::days(30);
 
 pub struct CertificateAuthority {
     pub cert_gen: Certificate,
 }
 
 impl CertificateAuthority {
-    /// TODO: automated renewal if expiring soon
-    pub fn load_or_new(cert: PathBuf, key: PathBuf) -> anyhow::Result<Self> {
+    /// The CA is never renewed automatically. Every client and server would need the new one. [`crate::renew::Renewal`] warns when it's close to expiring.
+    pub fn load_or_new(
+        cert: PathBuf,
+        key: PathBuf,
+        validity: Validity,
+        passphrase: Option<&str>,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
         if cert.exists() && key.exists() {
-            Self::load(cert, key)
+            Self::load(cert, key, passphrase)
         } else {
-            Self::new(cert, key)
+            Self::new(cert, key, validity, passphrase, algo)
         }
     }
 
     /// Create a new CA.
     ///
     /// If you want a different name or whatever, you should probably use real certificate management software instead of this.
-    pub fn new(cert: PathBuf, key: PathBuf) -> anyhow::Result<Self> {
+    ///
+    /// The key is only readable by its owner. With a passphrase, it's also encrypted.
+    pub fn new(
+        cert: PathBuf,
+        key: PathBuf,
+        validity: Validity,
+        passphrase: Option<&str>,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
         info!("creating new CA cert at {}", cert.display());
 
         assert!(!cert.exists());
         assert!(!key.exists());
 
+        let x = Self::generate(validity, algo, algo.generate()?)?;
+
+        write_private(&key, Self::key_pem(&x, passphrase)?)?;
+        write_atomic(&cert, x.serialize_pem()?)?;
+
+        Ok(Self { cert_gen: x })
+    }
+
+    /// Same as [`CertificateAuthority::load_or_new`] with tokio's file IO.
+    pub async fn load_or_new_async(
+        cert: PathBuf,
+        key: PathBuf,
+        validity: Validity,
+        passphrase: Option<&str>,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
+        if tokio::fs::try_exists(&cert).await? && tokio::fs::try_exists(&key).await? {
+            Self::load_async(cert, key, passphrase).await
+        } else {
+            Self::new_async(cert, key, validity, passphrase, algo).await
+        }
+    }
+
+    /// Same as [`CertificateAuthority::new`] with tokio's file IO.
+    pub async fn new_async(
+        cert: PathBuf,
+        key: PathBuf,
+        validity: Validity,
+        passphrase: Option<&str>,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
+        info!("creating new CA cert at {}", cert.display());
+
+        assert!(!tokio::fs::try_exists(&cert).await?);
+        assert!(!tokio::fs::try_exists(&key).await?);
+
+        let x = Self::generate(validity, algo, algo.generate_async().await?)?;
+
+        write_private_async(&key, Self::key_pem(&x, passphrase)?).await?;
+        write_atomic_async(&cert, x.serialize_pem()?).await?;
+
+        Ok(Self { cert_gen: x })
+    }
+
+    fn generate(
+        validity: Validity,
+        algo: KeyAlgo,
+        key_pair: KeyPair,
+    ) -> anyhow::Result<Certificate> {
         let mut ca_params = CertificateParams::new([]);
 
-        // TODO: set expiration
+        validity.apply(&mut ca_params);
 
-        ca_params.alg = DEFAULT_ALG;
+        ca_params.serial_number = Some(random_serial()?);
+        ca_params.alg = algo.signature_algorithm();
+        ca_params.key_pair = Some(key_pair);
         ca_params
             .distinguished_name
             .push(rcgen::DnType::OrganizationName, "QUIC Tunnel");
@@ -47,24 +125,54 @@ impl CertificateAuthority {
             rcgen::KeyUsagePurpose::CrlSign,
         ];
 
-        let x = Certificate::from_params(ca_params)?;
-
-        std::fs::write(cert, x.serialize_pem()?)?;
-        std::fs::write(key, x.serialize_private_key_pem())?;
+        Ok(Certificate::from_params(ca_params)?)
+    }
 
-        Ok(Self { cert_gen: x })
+    fn key_pem(x: &Certificate, passphrase: Option<&str>) -> anyhow::Result<String> {
+        match passphrase {
+            Some(passphrase) => encrypt_key_pem(&x.serialize_private_key_der(), passphrase),
+            None => Ok(x.serialize_private_key_pem()),
+        }
     }
 
-    /// Load an existing CA.
-    pub fn load(cert: PathBuf, key: PathBuf) -> anyhow::Result<Self> {
+    /// Load an existing CA. Encrypted keys need the passphrase.
+    pub fn load(cert: PathBuf, key: PathBuf, passphrase: Option<&str>) -> anyhow::Result<Self> {
         info!("loading existing CA cert from {}", cert.display());
 
         let pem_str = std::fs::read_to_string(cert)?;
 
-        let key_str = std::fs::read_to_string(key)?;
-        let key_pair = KeyPair::from_pem(&key_str)?;
+        let key_str = std::fs::read_to_string(&key)?;
+        let key_pair = key_pair_from_pem(&key_str, passphrase)
+            .with_context(|| format!("failed loading CA key from {}", key.display()))?;
+
+        Self::from_pem(&pem_str, key_pair)
+    }
 
-        let params = CertificateParams::from_ca_cert_pem(&pem_str, key_pair)?;
+    /// Same as [`CertificateAuthority::load`] with tokio's file IO.
+    ///
+    /// Decrypting a key takes a while on purpose, so it happens off of the runtime's threads.
+    pub async fn load_async(
+        cert: PathBuf,
+        key: PathBuf,
+        passphrase: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        info!("loading existing CA cert from {}", cert.display());
+
+        let pem_str = tokio::fs::read_to_string(cert).await?;
+
+        let key_str = tokio::fs::read_to_string(&key).await?;
+        let passphrase = passphrase.map(str::to_string);
+
+        let key_pair =
+            tokio::task::spawn_blocking(move || key_pair_from_pem(&key_str, passphrase.as_deref()))
+                .await?
+                .with_context(|| format!("failed loading CA key from {}", key.display()))?;
+
+        Self::from_pem(&pem_str, key_pair)
+    }
+
+    fn from_pem(cert_pem: &str, key_pair: KeyPair) -> anyhow::Result<Self> {
+        let params = CertificateParams::from_ca_cert_pem(cert_pem, key_pair)?;
 
         let x = Certificate::from_params(params)?;
 
@@ -84,4 +192,224 @@ impl CertificateAuthority {
 
         rustls::PrivateKey(der)
     }
+
+    /// Whether this CA signed `cert`. Checks the signature because every `quick_certs new` CA has the same name.
+    pub fn issued(&self, cert: &rustls::Certificate) -> anyhow::Result<bool> {
+        let ca = self.cert()?;
+
+        let (_, ca) = X509Certificate::from_der(&ca.0).context("failed parsing CA certificate")?;
+        let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+        Ok(x.issuer() == ca.subject() && x.verify_signature(Some(ca.public_key())).is_ok())
+    }
+
+    /// Sign the certificate at `cert` again with a new serial number. The key, names, and lifetime stay the same.
+    ///
+    /// Pins on the key keep working. Only the certificate file is written.
+    pub fn renew(&self, cert: &Path, key: &Path) -> anyhow::Result<rustls::Certificate> {
+        info!("renewing certificate at \"{}\"", cert.display());
+
+        let old = cert_from_pem(cert.to_path_buf())?;
+
+        let key_pair = KeyPair::from_pem(&std::fs::read_to_string(key)?)?;
+
+        let (pem, x) = self.renewed(&old, key_pair)?;
+
+        write_atomic(cert, pem)?;
+
+        Ok(x)
+    }
+
+    /// Same as [`CertificateAuthority::renew`] with tokio's file IO.
+    pub async fn renew_async(
+        &self,
+        cert: &Path,
+        key: &Path,
+    ) -> anyhow::Result<rustls::Certificate> {
+        info!("renewing certificate at \"{}\"", cert.display());
+
+        let old = cert_from_pem_async(cert.to_path_buf()).await?;
+
+        let key_pair = KeyPair::from_pem(&tokio::fs::read_to_string(key).await?)?;
+
+        let (pem, x) = self.renewed(&old, key_pair)?;
+
+        write_atomic_async(cert, pem).await?;
+
+        Ok(x)
+    }
+
+    /// `old` signed again, as PEM and DER
+    fn renewed(
+        &self,
+        old: &rustls::Certificate,
+        key_pair: KeyPair,
+    ) -> anyhow::Result<(String, rustls::Certificate)> {
+        // the old cert's algorithm is the CA's. the new one has to match the key
+        let alg = key_pair.algorithm();
+
+        let mut params = CertificateParams::from_ca_cert_der(&old.0, key_pair)?;
+
+        Validity::lifetime(params.not_after - params.not_before).apply(&mut params);
+
+        params.serial_number = Some(random_serial()?);
+        params.alg = alg;
+
+        let x = Certificate::from_params(params)?;
+
+        let cert_der = x.serialize_der_with_signer(&self.cert_gen)?;
+
+        Ok((
+            x.serialize_pem_with_signer(&self.cert_gen)?,
+            rustls::Certificate(cert_der),
+        ))
+    }
+
+    /// Sign a certificate request from [`crate::certs::TunnelCertificate::new_request`] or any other tool.
+    ///
+    /// Only the key comes from the request. The names are `names`, and the certificate can only be used for `tunnel_end`, whatever the request asked for.
+    pub fn sign_request(
+        &self,
+        csr_pem: &str,
+        names: &SubjectNames,
+        tunnel_end: TunnelEnd,
+        validity: Validity,
+    ) -> anyhow::Result<String> {
+        let mut csr = CertificateSigningRequest::from_pem(csr_pem)
+            .context("failed parsing certificate request")?;
+
+        let params = names.params();
+
+        // a request can ask for any name. only sign the ones we chose
+        for x in csr
+            .params
+            .subject_alt_names
+            .iter()
+            .filter(|x| !params.subject_alt_names.contains(x))
+        {
+            warn!(?x, "not signing a name from the request");
+        }
+
+        csr.params.distinguished_name = params.distinguished_name;
+        csr.params.subject_alt_names = params.subject_alt_names;
+        csr.params.is_ca = rcgen::IsCa::NoCa;
+        csr.params.extended_key_usages = vec![tunnel_end.extended_key_usage()];
+
+        validity.apply(&mut csr.params);
+
+        csr.params.serial_number = Some(random_serial()?);
+
+        Ok(csr.serialize_pem_with_signer(&self.cert_gen)?)
+    }
+
+    /// Add a certificate to the CRL at `crl` and sign it again. Certificates that were already revoked stay revoked.
+    ///
+    /// Servers with `--crl` (or a `{cert_name}_crl.pem` next to their certificates) refuse the certificate once they reload.
+    pub fn revoke(&self, crl: &Path, serial: &[u8]) -> anyhow::Result<()> {
+        let mut revoked = if crl.exists() {
+            revoked_certs(&std::fs::read(crl)?)?
+        } else {
+            vec![]
+        };
+
+        if revoked.iter().any(|x| x.serial_number.to_bytes() == serial) {
+            info!(serial = format_serial(serial), "already revoked");
+        } else {
+            info!(serial = format_serial(serial), "revoking");
+
+            revoked.push(RevokedCertParams {
+                serial_number: SerialNumber::from_slice(serial),
+                revocation_time: OffsetDateTime::now_utc(),
+                reason_code: None,
+                invalidity_date: None,
+            });
+        }
+
+        self.publish_crl(crl, revoked)
+    }
+
+    /// Sign a CRL with these revoked certificates and write it to `crl`.
+    pub fn publish_crl(&self, crl: &Path, revoked: Vec<RevokedCertParams>) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+
+        let params = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + CRL_VALIDITY,
+            // only needs to go up each time
+            crl_number: SerialNumber::from_slice(&now.unix_timestamp().to_be_bytes()),
+            issuing_distribution_point: None,
+            revoked_certs: revoked,
+            alg: self.cert_gen.get_params().alg,
+            key_identifier_method: KeyIdMethod::Sha256,
+        };
+
+        let x = CertificateRevocationList::from_params(params)?;
+
+        write_atomic(crl, x.serialize_pem_with_signer(&self.cert_gen)?)?;
+
+        info!("saved CRL to \"{}\"", crl.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certs::{cert_from_pem_bytes, dns_names, uri_names};
+
+    fn ca() -> CertificateAuthority {
+        CertificateAuthority {
+            cert_gen: CertificateAuthority::generate(
+                Validity::days(1),
+                KeyAlgo::EcdsaP256,
+                KeyAlgo::EcdsaP256.generate().unwrap(),
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn issued_checks_the_signature() {
+        let ca_a = ca();
+        let ca_b = ca();
+
+        let x =
+            Certificate::from_params(CertificateParams::new(["example.com".to_string()])).unwrap();
+        let x = rustls::Certificate(x.serialize_der_with_signer(&ca_a.cert_gen).unwrap());
+
+        assert!(ca_a.issued(&x).unwrap());
+
+        // same name, different key
+        assert!(!ca_b.issued(&x).unwrap());
+    }
+
+    #[test]
+    fn sign_request_ignores_the_requested_names() {
+        let ca = ca();
+
+        let requested = SubjectNames::for_tunnel("other", TunnelEnd::Server)
+            .with_uris(["quic-tunnel://other/client".to_string()]);
+
+        let mut params = requested.params();
+        params.alg = KeyAlgo::EcdsaP256.signature_algorithm();
+        params.key_pair = Some(KeyAlgo::EcdsaP256.generate().unwrap());
+
+        let csr = Certificate::from_params(params)
+            .unwrap()
+            .serialize_request_pem()
+            .unwrap();
+
+        let names = SubjectNames::for_tunnel("first", TunnelEnd::Client);
+
+        let pem = ca
+            .sign_request(&csr, &names, TunnelEnd::Client, Validity::days(1))
+            .unwrap();
+
+        let x = cert_from_pem_bytes(pem.as_bytes()).unwrap();
+
+        assert!(ca.issued(&x).unwrap());
+        assert_eq!(uri_names(&x).unwrap(), ["quic-tunnel://first/client"]);
+        assert!(dns_names(&x).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,111 @@
+//! Certificate revocation lists. Servers load them to refuse client certificates that were revoked, like the one on a lost laptop.
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::Context;
+use rcgen::{RevokedCertParams, SerialNumber};
+use time::OffsetDateTime;
+use tracing::info;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::revocation_list::CertificateRevocationList;
+
+/// get every CRL from a PEM file, as DER.
+pub fn crls_from_pem(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    info!("loading CRLs from \"{}\"", path.display());
+
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("failed opening {}", path.display()))?,
+    );
+
+    let crls = rustls_pemfile::crls(&mut reader)
+        .map(|x| Ok(x?.as_ref().to_vec()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if crls.is_empty() {
+        anyhow::bail!("no CRL found in {}", path.display());
+    }
+
+    Ok(crls)
+}
+
+/// Same as [`crls_from_pem`] with tokio's file IO.
+pub async fn crls_from_pem_async(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    info!("loading CRLs from \"{}\"", path.display());
+
+    let pem = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed opening {}", path.display()))?;
+
+    let crls = rustls_pemfile::crls(&mut BufReader::new(pem.as_slice()))
+        .map(|x| Ok(x?.as_ref().to_vec()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if crls.is_empty() {
+        anyhow::bail!("no CRL found in {}", path.display());
+    }
+
+    Ok(crls)
+}
+
+/// the certificates that are already revoked in a PEM CRL
+pub fn revoked_certs(crl_pem: &[u8]) -> anyhow::Result<Vec<RevokedCertParams>> {
+    let mut revoked = vec![];
+
+    for der in rustls_pemfile::crls(&mut BufReader::new(crl_pem)) {
+        let der = der?;
+
+        let (_, crl) =
+            CertificateRevocationList::from_der(der.as_ref()).context("failed parsing CRL")?;
+
+        for x in crl.iter_revoked_certificates() {
+            revoked.push(RevokedCertParams {
+                serial_number: SerialNumber::from_slice(&x.user_certificate.to_bytes_be()),
+                revocation_time: OffsetDateTime::from_unix_timestamp(
+                    x.revocation_date.timestamp(),
+                )?,
+                reason_code: None,
+                invalidity_date: None,
+            });
+        }
+    }
+
+    Ok(revoked)
+}
+
+/// Parse a serial number the way openssl and most tools print it. `c0:ff:ee` or `c0ffee`.
+pub fn parse_serial(s: &str) -> anyhow::Result<Vec<u8>> {
+    let hex: String = s.chars().filter(|x| *x != ':').collect();
+
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.chars().all(|x| x.is_ascii_hexdigit())
+    {
+        anyhow::bail!("{} is not a hex serial number", s);
+    }
+
+    let mut serial = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // the leading zero that keeps a DER integer positive isn't part of the number
+    while serial.len() > 1 && serial[0] == 0 {
+        serial.remove(0);
+    }
+
+    Ok(serial)
+}
+
+/// the serial number of a certificate, without any leading zeros
+pub fn cert_serial(cert: &rustls::Certificate) -> anyhow::Result<Vec<u8>> {
+    let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+    Ok(x.tbs_certificate.serial.to_bytes_be())
+}
+
+/// `c0:ff:ee`
+pub fn format_serial(serial: &[u8]) -> String {
+    serial
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,89 @@
+//! Passphrase-encrypted PKCS#8 keys, so a copied CA key is useless without the passphrase.
+//!
+//! The key is encrypted with scrypt and AES-256-CBC. `openssl pkey` reads it too. It refuses scrypt parameters that need more than 32 MiB.
+
+use std::path::Path;
+
+use anyhow::Context;
+use pkcs8::der::pem::LineEnding;
+use pkcs8::pkcs5::{pbes2, scrypt};
+use pkcs8::{EncryptedPrivateKeyInfo, PrivateKeyInfo, SecretDocument};
+use rand_core::{OsRng, RngCore};
+use rcgen::KeyPair;
+
+const ENCRYPTED_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// 16 MiB. The pkcs8 crate's default is twice that
+const SCRYPT_LOG_N: u8 = 14;
+
+/// Read a passphrase from the first line of a file.
+pub fn read_passphrase(path: &Path) -> anyhow::Result<String> {
+    let x = std::fs::read_to_string(path)
+        .with_context(|| format!("failed reading passphrase from {}", path.display()))?;
+
+    first_line(path, &x)
+}
+
+/// Same as [`read_passphrase`] with tokio's file IO.
+pub async fn read_passphrase_async(path: &Path) -> anyhow::Result<String> {
+    let x = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed reading passphrase from {}", path.display()))?;
+
+    first_line(path, &x)
+}
+
+fn first_line(path: &Path, contents: &str) -> anyhow::Result<String> {
+    let x = contents.lines().next().unwrap_or_default().to_string();
+
+    if x.is_empty() {
+        anyhow::bail!("{} has an empty passphrase", path.display());
+    }
+
+    Ok(x)
+}
+
+/// Encrypt a PKCS#8 key with a passphrase.
+pub fn encrypt_key_pem(pkcs8_der: &[u8], passphrase: &str) -> anyhow::Result<String> {
+    let key = PrivateKeyInfo::try_from(pkcs8_der).context("failed parsing key")?;
+
+    let mut salt = [0; 16];
+    let mut iv = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut iv);
+
+    let scrypt_params = scrypt::Params::new(SCRYPT_LOG_N, 8, 1, 32)
+        .map_err(|err| anyhow::anyhow!("bad scrypt parameters: {}", err))?;
+
+    let params = pbes2::Parameters::scrypt_aes256cbc(scrypt_params, &salt, &iv)
+        .map_err(|err| anyhow::anyhow!("bad encryption parameters: {}", err))?;
+
+    let x = key
+        .encrypt_with_params(params, passphrase)
+        .context("failed encrypting key")?;
+
+    Ok(x.to_pem(ENCRYPTED_LABEL, LineEnding::LF)?.to_string())
+}
+
+/// whether a PEM file has an encrypted key
+pub fn is_encrypted_pem(pem: &str) -> bool {
+    pem.contains(&format!("-----BEGIN {}-----", ENCRYPTED_LABEL))
+}
+
+/// Read a key pair from PEM. Encrypted keys need the passphrase.
+pub fn key_pair_from_pem(pem: &str, passphrase: Option<&str>) -> anyhow::Result<KeyPair> {
+    if !is_encrypted_pem(pem) {
+        return Ok(KeyPair::from_pem(pem)?);
+    }
+
+    let passphrase = passphrase.context("the key is encrypted and no passphrase was given")?;
+
+    let (_, doc) = SecretDocument::from_pem(pem).context("failed parsing encrypted key")?;
+
+    let key = EncryptedPrivateKeyInfo::try_from(doc.as_bytes())
+        .context("failed parsing encrypted key")?
+        .decrypt(passphrase)
+        .context("failed decrypting key. wrong passphrase?")?;
+
+    Ok(KeyPair::from_der(key.as_bytes())?)
+}
@@ -0,0 +1,37 @@
+use anyhow::Context;
+use time::{Duration, OffsetDateTime};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Renew certificates this close to expiring. Short-lived certificates are renewed a third of their lifetime before instead.
+pub const DEFAULT_RENEW_WITHIN_DAYS: u32 = 10;
+
+/// Warn this long before the CA expires. A new CA has to be copied to every client and server, so this needs a head start.
+pub const CA_WARN_DAYS: i64 = 60;
+
+/// when a certificate stops being valid
+pub fn not_after(cert: &rustls::Certificate) -> anyhow::Result<OffsetDateTime> {
+    let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+    let x = OffsetDateTime::from_unix_timestamp(x.validity().not_after.timestamp())?;
+
+    Ok(x)
+}
+
+/// how long until a certificate expires. Negative if it already has
+pub fn expires_in(cert: &rustls::Certificate) -> anyhow::Result<Duration> {
+    Ok(not_after(cert)? - OffsetDateTime::now_utc())
+}
+
+/// Whether a certificate has less than `within` left, or less than a third of its lifetime if that is shorter.
+///
+/// Without the third, a 7 day cert would be renewed on every check.
+pub fn needs_renewal(cert: &rustls::Certificate, within: Duration) -> anyhow::Result<bool> {
+    let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+    let validity = x.validity();
+
+    let lifetime =
+        Duration::seconds(validity.not_after.timestamp() - validity.not_before.timestamp());
+
+    Ok(expires_in(cert)? < within.min(lifetime / 3))
+}
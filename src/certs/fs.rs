@@ -0,0 +1,124 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+
+/// Write a file so that readers see either the old contents or the new ones. Never half of a certificate.
+///
+/// The new contents go to a temporary file in the same directory that is then renamed over `path`.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    write_atomic_mode(path, contents.as_ref(), 0o644)
+}
+
+/// Same as [`write_atomic`] but only the owner can read the file. For private keys.
+///
+/// The file is never readable by anyone else, not even before the rename.
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    write_atomic_mode(path, contents.as_ref(), 0o600)
+}
+
+/// Same as [`write_atomic`] with tokio's file IO.
+pub async fn write_atomic_async(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    write_atomic_mode_async(path, contents.as_ref(), 0o644).await
+}
+
+/// Same as [`write_private`] with tokio's file IO.
+pub async fn write_private_async(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    write_atomic_mode_async(path, contents.as_ref(), 0o600).await
+}
+
+fn write_atomic_mode(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let tmp = tmp_path(path)?;
+
+    // a leftover from a crash would keep its old mode
+    let _ = std::fs::remove_file(&tmp);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .with_context(|| format!("failed creating {}", tmp.display()))?;
+
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("failed writing {}", tmp.display()))?;
+
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("failed renaming {} to {}", tmp.display(), path.display()))?;
+
+    Ok(())
+}
+
+async fn write_atomic_mode_async(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let tmp = tmp_path(path)?;
+
+    let _ = tokio::fs::remove_file(&tmp).await;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .await
+        .with_context(|| format!("failed creating {}", tmp.display()))?;
+
+    async {
+        file.write_all(contents).await?;
+        file.sync_all().await
+    }
+    .await
+    .with_context(|| format!("failed writing {}", tmp.display()))?;
+
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("failed renaming {} to {}", tmp.display(), path.display()))?;
+
+    Ok(())
+}
+
+/// `dir/name` -> `dir/.name.tmp`
+fn tmp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?;
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+
+    Ok(path.with_file_name(tmp_name))
+}
+
+/// Refuse private keys that the group or everyone else can read or write.
+pub fn check_key_permissions(path: &Path) -> anyhow::Result<()> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("failed reading {}", path.display()))?;
+
+    check_mode(path, metadata.permissions().mode())
+}
+
+/// Same as [`check_key_permissions`] with tokio's file IO.
+pub async fn check_key_permissions_async(path: &Path) -> anyhow::Result<()> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("failed reading {}", path.display()))?;
+
+    check_mode(path, metadata.permissions().mode())
+}
+
+fn check_mode(path: &Path, mode: u32) -> anyhow::Result<()> {
+    if mode & 0o077 != 0 {
+        anyhow::bail!(
+            "{} can be read by other users (mode {:o}). run \"chmod 600 {}\" or pass --insecure-key-permissions",
+            path.display(),
+            mode & 0o777,
+            path.display()
+        );
+    }
+
+    Ok(())
+}
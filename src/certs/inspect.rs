@@ -0,0 +1,249 @@
+//! What's in a directory of certificates, and what's wrong with it.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use rcgen::KeyPair;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::certs::{format_serial, SpkiPin, CA_WARN_DAYS, DEFAULT_RENEW_WITHIN_DAYS};
+
+/// One certificate from a PEM file.
+#[derive(Debug, Serialize)]
+pub struct CertReport {
+    pub file: PathBuf,
+    /// position in the file. CA bundles and chains have more than one
+    pub index: usize,
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    /// RFC 3339
+    pub not_before: String,
+    /// RFC 3339
+    pub not_after: String,
+    /// subject alternative names like `DNS:first_server` and `IP:127.0.0.1`
+    pub names: Vec<String>,
+    /// `serverAuth` and `clientAuth`
+    pub extended_key_usages: Vec<String>,
+    pub is_ca: bool,
+    pub pin_sha256: String,
+    /// the certificate in the directory whose key signed this one. `self` if it is self-signed
+    pub signed_by: Option<String>,
+    /// the key file in the directory for this certificate
+    pub key: Option<PathBuf>,
+    pub problems: Vec<String>,
+}
+
+/// a parsed certificate and where it came from
+struct Found {
+    file: PathBuf,
+    index: usize,
+    der: Vec<u8>,
+}
+
+impl Found {
+    fn label(&self) -> String {
+        if self.index == 0 {
+            self.file.display().to_string()
+        } else {
+            format!("{}#{}", self.file.display(), self.index)
+        }
+    }
+}
+
+/// Every certificate in every `.pem` file in `dir`. Keys are matched up with their certificates. Other PEM blocks are skipped.
+pub fn inspect_dir(dir: &Path) -> anyhow::Result<Vec<CertReport>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("failed reading {}", dir.display()))?
+        .map(|x| Ok(x?.path()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    paths.retain(|x| x.extension().is_some_and(|x| x == "pem"));
+    paths.sort();
+
+    let mut found = vec![];
+    let mut keys = vec![];
+
+    for path in paths {
+        let mut reader = BufReader::new(File::open(&path)?);
+
+        let mut index = 0;
+
+        for item in rustls_pemfile::read_all(&mut reader) {
+            let item = item.with_context(|| format!("failed parsing {}", path.display()))?;
+
+            match item {
+                rustls_pemfile::Item::X509Certificate(der) => {
+                    found.push(Found {
+                        file: path.clone(),
+                        index,
+                        der: der.as_ref().to_vec(),
+                    });
+                    index += 1;
+                }
+                rustls_pemfile::Item::Pkcs8Key(der) => {
+                    // rcgen only reads PKCS#8. Other keys can't be matched
+                    if let Ok(x) = KeyPair::from_der(der.secret_pkcs8_der()) {
+                        keys.push((path.clone(), x.public_key_raw().to_vec()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let parsed = found
+        .iter()
+        .map(|x| {
+            let (_, cert) = X509Certificate::from_der(&x.der)
+                .with_context(|| format!("failed parsing a certificate in {}", x.label()))?;
+            Ok(cert)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut reports = vec![];
+
+    for (x, cert) in found.iter().zip(parsed.iter()) {
+        reports.push(report(x, cert, &found, &parsed, &keys)?);
+    }
+
+    Ok(reports)
+}
+
+fn report(
+    x: &Found,
+    cert: &X509Certificate,
+    found: &[Found],
+    parsed: &[X509Certificate],
+    keys: &[(PathBuf, Vec<u8>)],
+) -> anyhow::Result<CertReport> {
+    let mut problems = vec![];
+
+    let not_before = OffsetDateTime::from_unix_timestamp(cert.validity().not_before.timestamp())?;
+    let not_after = OffsetDateTime::from_unix_timestamp(cert.validity().not_after.timestamp())?;
+
+    let now = OffsetDateTime::now_utc();
+
+    let warn_days = if cert.is_ca() {
+        CA_WARN_DAYS
+    } else {
+        DEFAULT_RENEW_WITHIN_DAYS.into()
+    };
+
+    if not_after < now {
+        problems.push("expired".to_string());
+    } else if not_before > now {
+        problems.push("not valid yet".to_string());
+    } else if not_after - now < Duration::days(warn_days) {
+        problems.push(format!(
+            "expires in {} days",
+            (not_after - now).whole_days()
+        ));
+    }
+
+    let names = match cert.subject_alternative_name()? {
+        Some(san) => san.value.general_names.iter().map(format_name).collect(),
+        None => vec![],
+    };
+
+    let extended_key_usages = match cert.extended_key_usage()? {
+        Some(x) => {
+            let mut usages = vec![];
+            if x.value.server_auth {
+                usages.push("serverAuth".to_string());
+            }
+            if x.value.client_auth {
+                usages.push("clientAuth".to_string());
+            }
+            if x.value.any {
+                usages.push("any".to_string());
+            }
+            usages
+        }
+        None => vec![],
+    };
+
+    if !cert.is_ca() && extended_key_usages.is_empty() {
+        problems.push("no serverAuth or clientAuth extended key usage".to_string());
+    }
+
+    let self_signed = cert.issuer() == cert.subject() && cert.verify_signature(None).is_ok();
+
+    let signed_by = if self_signed {
+        Some("self".to_string())
+    } else {
+        found
+            .iter()
+            .zip(parsed.iter())
+            .find(|(_, ca)| {
+                ca.is_ca()
+                    && ca.subject() == cert.issuer()
+                    && cert.verify_signature(Some(ca.public_key())).is_ok()
+            })
+            .map(|(ca, _)| ca.label())
+    };
+
+    if signed_by.is_none() {
+        problems.push(format!("not signed by any CA here ({})", cert.issuer()));
+    }
+
+    let public_key = cert.public_key().subject_public_key.data.as_ref();
+
+    let key = keys
+        .iter()
+        .find(|(_, x)| x == public_key)
+        .map(|(path, _)| path.clone());
+
+    // only the first cert in a file is the one with a key. the rest are intermediates
+    if x.index == 0 && !cert.is_ca() {
+        let expected = key_path(&x.file);
+
+        if expected.exists() && key.as_ref() != Some(&expected) {
+            problems.push(format!("doesn't match the key in {}", expected.display()));
+        }
+    }
+
+    Ok(CertReport {
+        file: x.file.clone(),
+        index: x.index,
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        serial: format_serial(&cert.tbs_certificate.serial.to_bytes_be()),
+        not_before: not_before.format(&Rfc3339)?,
+        not_after: not_after.format(&Rfc3339)?,
+        names,
+        extended_key_usages,
+        is_ca: cert.is_ca(),
+        pin_sha256: SpkiPin::from_cert(&rustls::Certificate(x.der.clone()))?.to_string(),
+        signed_by,
+        key,
+        problems,
+    })
+}
+
+/// `first_server.pem` -> `first_server.key.pem`
+fn key_path(cert: &Path) -> PathBuf {
+    let stem = cert.file_stem().unwrap_or_default().to_string_lossy();
+
+    cert.with_file_name(format!("{}.key.pem", stem))
+}
+
+fn format_name(x: &GeneralName) -> String {
+    match x {
+        GeneralName::DNSName(x) => format!("DNS:{}", x),
+        GeneralName::URI(x) => format!("URI:{}", x),
+        GeneralName::RFC822Name(x) => format!("email:{}", x),
+        GeneralName::IPAddress(x) => match (<[u8; 4]>::try_from(*x), <[u8; 16]>::try_from(*x)) {
+            (Ok(x), _) => format!("IP:{}", IpAddr::from(x)),
+            (_, Ok(x)) => format!("IP:{}", IpAddr::from(x)),
+            _ => format!("IP:{:02x?}", x),
+        },
+        x => format!("{:?}", x),
+    }
+}
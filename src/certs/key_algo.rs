@@ -0,0 +1,72 @@
+use rcgen::{KeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ED25519, PKCS_RSA_SHA256};
+use rsa::pkcs8::EncodePrivateKey;
+use serde::Deserialize;
+use strum::{Display, EnumString};
+
+/// RSA keys smaller than this aren't allowed on some hosts
+const RSA_BITS: usize = 3072;
+
+/// The kind of key for new certificates. rustls can use all of them.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, EnumString, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum KeyAlgo {
+    /// works everywhere
+    #[default]
+    #[serde(rename = "ecdsa-p256")]
+    #[strum(serialize = "ecdsa-p256")]
+    EcdsaP256,
+    /// the smallest certificates and signatures. good for slow links
+    #[strum(serialize = "ed25519")]
+    Ed25519,
+    /// for hosts that require RSA. much bigger handshakes and slow to generate
+    #[serde(rename = "rsa-3072")]
+    #[strum(serialize = "rsa-3072")]
+    Rsa3072,
+}
+
+impl KeyAlgo {
+    /// What certificates with this kind of key are signed with.
+    pub fn signature_algorithm(&self) -> &'static SignatureAlgorithm {
+        match self {
+            Self::EcdsaP256 => &PKCS_ECDSA_P256_SHA256,
+            Self::Ed25519 => &PKCS_ED25519,
+            Self::Rsa3072 => &PKCS_RSA_SHA256,
+        }
+    }
+
+    /// Make a new key.
+    pub fn generate(&self) -> anyhow::Result<KeyPair> {
+        match self {
+            Self::EcdsaP256 | Self::Ed25519 => Ok(KeyPair::generate(self.signature_algorithm())?),
+            Self::Rsa3072 => {
+                // rcgen can't make RSA keys
+                let key = rsa::RsaPrivateKey::new(&mut rand_core::OsRng, RSA_BITS)?;
+
+                Ok(KeyPair::from_der(key.to_pkcs8_der()?.as_bytes())?)
+            }
+        }
+    }
+
+    /// Same as [`KeyAlgo::generate`] on a blocking thread. RSA keys can take seconds.
+    pub async fn generate_async(self) -> anyhow::Result<KeyPair> {
+        tokio::task::spawn_blocking(move || self.generate()).await?
+    }
+
+    /// What kind of key a PKCS#8 key is. None for any other kind of key, or if it isn't PKCS#8.
+    pub fn of_key(der: &[u8]) -> Option<Self> {
+        let alg = KeyPair::from_der(der).ok()?.algorithm();
+
+        if alg == &PKCS_ECDSA_P256_SHA256 {
+            Some(Self::EcdsaP256)
+        } else if alg == &PKCS_ED25519 {
+            Some(Self::Ed25519)
+        } else if alg == &PKCS_RSA_SHA256 {
+            let x = ring::signature::RsaKeyPair::from_pkcs8(der).ok()?;
+
+            (x.public().modulus_len() * 8 == RSA_BITS).then_some(Self::Rsa3072)
+        } else {
+            None
+        }
+    }
+}
@@ -1,7 +1,42 @@
+//! Certificates, keys, and a small CA for making them.
+//!
+//! Functions that read or write files have an `_async` version on tokio's file IO for code running on the runtime, like reloads and renewal. The blocking ones are for `quick_certs` and startup.
+
 mod ca;
+mod crl;
+mod encrypted_key;
+mod expiry;
+mod fs;
+mod inspect;
+mod key_algo;
+mod names;
+mod pin;
+mod server_name;
 mod tunnel;
+mod validity;
 
 pub use ca::CertificateAuthority;
-pub use tunnel::{cert_from_pem, key_from_pem, TunnelCertificate, TunnelEnd};
-
-pub static DEFAULT_ALG: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECDSA_P256_SHA256;
+pub use crl::{
+    cert_serial, crls_from_pem, crls_from_pem_async, format_serial, parse_serial, revoked_certs,
+};
+pub use encrypted_key::{
+    encrypt_key_pem, is_encrypted_pem, key_pair_from_pem, read_passphrase, read_passphrase_async,
+};
+pub use expiry::{expires_in, needs_renewal, not_after, CA_WARN_DAYS, DEFAULT_RENEW_WITHIN_DAYS};
+pub use fs::{
+    check_key_permissions, check_key_permissions_async, write_atomic, write_atomic_async,
+    write_private, write_private_async,
+};
+pub use inspect::{inspect_dir, CertReport};
+pub use key_algo::KeyAlgo;
+pub use names::{uri_names, SubjectNames};
+pub use pin::SpkiPin;
+pub use server_name::{dns_names, server_name_from_ca_pem, write_ca_server_name};
+pub use tunnel::{
+    cert_from_pem, cert_from_pem_async, cert_from_pem_bytes, certs_from_pem, certs_from_pem_async,
+    certs_from_pem_bytes, key_from_pem, key_from_pem_async, key_from_pem_bytes, TunnelCertificate,
+    TunnelEnd,
+};
+pub use validity::{
+    random_serial, Validity, DEFAULT_CA_DAYS, DEFAULT_CLIENT_DAYS, DEFAULT_SERVER_DAYS,
+};
@@ -0,0 +1,119 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::Context;
+use rcgen::{CertificateParams, SanType};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::certs::TunnelEnd;
+
+/// Who a certificate belongs to. The common name and the subject alternative names.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubjectNames {
+    pub common_name: String,
+    /// names clients can check the server with
+    pub dns: Vec<String>,
+    /// addresses clients can check the server with when they connect to an IP address
+    pub ips: Vec<IpAddr>,
+    /// a stable identity that doesn't depend on the address. Servers can authorize clients by it
+    pub uris: Vec<String>,
+}
+
+impl SubjectNames {
+    /// What `quick_certs` uses for a tunnel. The common name is the tunnel's name.
+    ///
+    /// Servers get the DNS name `{name}_server`. Clients get the URI `quic-tunnel://{name}/client`.
+    pub fn for_tunnel(name: &str, tunnel_end: TunnelEnd) -> Self {
+        let mut x = Self {
+            common_name: name.to_string(),
+            ..Default::default()
+        };
+
+        match tunnel_end {
+            TunnelEnd::Server => x.dns.push(format!("{}_server", name)),
+            TunnelEnd::Client => x.uris.push(format!("quic-tunnel://{}/client", name)),
+        }
+
+        x
+    }
+
+    /// Add more DNS names.
+    pub fn with_dns(mut self, dns: impl IntoIterator<Item = String>) -> Self {
+        self.dns.extend(dns);
+        self
+    }
+
+    /// Add IP addresses.
+    pub fn with_ips(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.ips.extend(ips);
+        self
+    }
+
+    /// Add URIs.
+    pub fn with_uris(mut self, uris: impl IntoIterator<Item = String>) -> Self {
+        self.uris.extend(uris);
+        self
+    }
+
+    /// params with only the names set
+    pub(crate) fn params(&self) -> CertificateParams {
+        let mut params = CertificateParams::new([]);
+
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, self.common_name.clone());
+
+        params.subject_alt_names = self
+            .dns
+            .iter()
+            .cloned()
+            .map(SanType::DnsName)
+            .chain(self.ips.iter().copied().map(SanType::IpAddress))
+            .chain(self.uris.iter().cloned().map(SanType::URI))
+            .collect();
+
+        params
+    }
+}
+
+/// like `CN=first, DNS:first_server, IP:127.0.0.1`
+impl fmt::Display for SubjectNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CN={}", self.common_name)?;
+
+        for x in self.dns.iter() {
+            write!(f, ", DNS:{}", x)?;
+        }
+
+        for x in self.ips.iter() {
+            write!(f, ", IP:{}", x)?;
+        }
+
+        for x in self.uris.iter() {
+            write!(f, ", URI:{}", x)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// the URIs in a certificate's subject alternative names
+pub fn uri_names(cert: &rustls::Certificate) -> anyhow::Result<Vec<String>> {
+    let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+    let Some(san) = x.subject_alternative_name()? else {
+        return Ok(vec![]);
+    };
+
+    let names = san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|x| match x {
+            GeneralName::URI(x) => Some(x.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    Ok(names)
+}
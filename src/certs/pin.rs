@@ -0,0 +1,72 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// The SHA-256 hash of a certificate's public key (its SubjectPublicKeyInfo). Written as `sha256/<base64>`, like curl's `--pinnedpubkey`.
+///
+/// The key stays the same when a certificate is renewed with the same key, so a pin doesn't need updating as often as the certificate.
+#[derive(Clone, Copy, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
+pub struct SpkiPin([u8; 32]);
+
+impl SpkiPin {
+    pub fn from_cert(cert: &rustls::Certificate) -> anyhow::Result<Self> {
+        let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, x.public_key().raw);
+
+        let mut pin = [0; 32];
+
+        pin.copy_from_slice(digest.as_ref());
+
+        Ok(Self(pin))
+    }
+
+    pub fn matches(&self, cert: &rustls::Certificate) -> bool {
+        Self::from_cert(cert).is_ok_and(|x| x == *self)
+    }
+}
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256/{}", STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Debug for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for SpkiPin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let b64 = s
+            .strip_prefix("sha256/")
+            .with_context(|| format!("{} should start with sha256/", s))?;
+
+        let x = STANDARD
+            .decode(b64)
+            .with_context(|| format!("{} is not valid base64", b64))?;
+
+        let pin = x
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("{} is not a SHA-256 hash", s))?;
+
+        Ok(Self(pin))
+    }
+}
+
+impl TryFrom<String> for SpkiPin {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
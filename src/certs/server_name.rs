@@ -0,0 +1,94 @@
+//! Finding the name on the server's certificate without guessing from file names.
+//!
+//! A CA file can say which name its servers use with a line outside of the PEM block:
+//!
+//! ```text
+//! server_name: first_server
+//! -----BEGIN CERTIFICATE-----
+//! ...
+//! ```
+//!
+//! PEM parsers (including openssl) ignore text outside of the blocks, so the file still works everywhere else.
+
+use std::path::Path;
+
+use anyhow::Context;
+use tracing::info;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::certs::write_atomic;
+
+const SERVER_NAME_PREFIX: &str = "server_name:";
+
+/// the `server_name:` line from a CA file
+pub fn server_name_from_ca_pem(pem: &[u8]) -> Option<String> {
+    let pem = String::from_utf8_lossy(pem);
+
+    let mut in_block = false;
+
+    for line in pem.lines() {
+        let line = line.trim();
+
+        if line.starts_with("-----BEGIN ") {
+            in_block = true;
+        } else if line.starts_with("-----END ") {
+            in_block = false;
+        } else if !in_block {
+            if let Some(x) = line.strip_prefix(SERVER_NAME_PREFIX) {
+                let x = x.trim();
+
+                if !x.is_empty() {
+                    return Some(x.to_string());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Add a `server_name:` line to a CA file. Does nothing if the file already has one.
+pub fn write_ca_server_name(path: &Path, server_name: &str) -> anyhow::Result<()> {
+    let pem = std::fs::read(path).with_context(|| format!("failed reading {}", path.display()))?;
+
+    if let Some(existing) = server_name_from_ca_pem(&pem) {
+        if existing != server_name {
+            info!(
+                existing,
+                server_name,
+                "\"{}\" already has a different server name. leaving it alone",
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let mut x = format!("{} {}\n", SERVER_NAME_PREFIX, server_name).into_bytes();
+
+    x.extend(pem);
+
+    write_atomic(path, x)?;
+
+    Ok(())
+}
+
+/// the DNS names in a certificate's subject alternative names
+pub fn dns_names(cert: &rustls::Certificate) -> anyhow::Result<Vec<String>> {
+    let (_, x) = X509Certificate::from_der(&cert.0).context("failed parsing certificate")?;
+
+    let Some(san) = x.subject_alternative_name()? else {
+        return Ok(vec![]);
+    };
+
+    let names = san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|x| match x {
+            GeneralName::DNSName(x) => Some(x.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    Ok(names)
+}
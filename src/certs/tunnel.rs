@@ -1,12 +1,18 @@
-/// TODO: this uses blocking IO! Use tokio instead!
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
-use rcgen::{Certificate, CertificateParams};
+use rcgen::{Certificate, KeyPair};
 use strum::EnumString;
 use tracing::info;
 
-use crate::certs::DEFAULT_ALG;
+use crate::certs::{
+    random_serial, write_atomic, write_atomic_async, write_private, write_private_async, KeyAlgo,
+    SubjectNames, Validity,
+};
 
 pub struct TunnelCertificate {
     pub cert: rustls::Certificate,
@@ -19,87 +25,162 @@ pub enum TunnelEnd {
     Client,
 }
 
+impl TunnelEnd {
+    /// The only thing this end's certificate is allowed to be used for.
+    pub fn extended_key_usage(&self) -> rcgen::ExtendedKeyUsagePurpose {
+        match self {
+            Self::Client => rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+            Self::Server => rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+        }
+    }
+}
+
 impl TunnelCertificate {
-    /// TODO: automated renewal if expiring soon
+    /// Existing certificates are loaded even if they are expiring. See [`crate::renew::Renewal`].
     pub fn load_or_new(
         ca: &Certificate,
         cert: PathBuf,
         key: PathBuf,
+        names: &SubjectNames,
         tunnel_end: TunnelEnd,
+        validity: Validity,
+        algo: KeyAlgo,
     ) -> anyhow::Result<Self> {
         if cert.exists() && key.exists() {
             Self::load_with_key(cert, key)
         } else {
-            let subject_name = cert
-                .file_stem()
-                .context("no server name detected")?
-                .to_str()
-                .context("server name is not valid utf8")?
-                .to_string();
-
-            Self::new(ca, cert, key, subject_name, tunnel_end)
+            Self::new(ca, cert, key, names, tunnel_end, validity, algo)
         }
     }
 
-    /// Create a new certificate and key signed by a CA.
+    /// Create a new certificate and key signed by a CA. The key is only readable by its owner.
     pub fn new(
         ca: &Certificate,
         cert: PathBuf,
         key: PathBuf,
-        subject_name: String,
+        names: &SubjectNames,
         tunnel_end: TunnelEnd,
+        validity: Validity,
+        algo: KeyAlgo,
     ) -> anyhow::Result<Self> {
         info!("creating new certificate at \"{}\"", cert.display());
 
         assert!(!cert.exists());
         assert!(!key.exists());
 
-        // TODO: set expiration
-        // TODO: limit server/client certs with extenstions
+        let (cert_pem, key_pem, x) =
+            Self::generate(ca, names, tunnel_end, validity, algo, algo.generate()?)?;
+
+        write_private(&key, key_pem)?;
+        write_atomic(&cert, cert_pem)?;
+
+        Ok(x)
+    }
+
+    /// Same as [`TunnelCertificate::load_or_new`] with tokio's file IO.
+    pub async fn load_or_new_async(
+        ca: &Certificate,
+        cert: PathBuf,
+        key: PathBuf,
+        names: &SubjectNames,
+        tunnel_end: TunnelEnd,
+        validity: Validity,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
+        if tokio::fs::try_exists(&cert).await? && tokio::fs::try_exists(&key).await? {
+            Self::load_with_key_async(cert, key).await
+        } else {
+            Self::new_async(ca, cert, key, names, tunnel_end, validity, algo).await
+        }
+    }
+
+    /// Same as [`TunnelCertificate::new`] with tokio's file IO.
+    pub async fn new_async(
+        ca: &Certificate,
+        cert: PathBuf,
+        key: PathBuf,
+        names: &SubjectNames,
+        tunnel_end: TunnelEnd,
+        validity: Validity,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<Self> {
+        info!("creating new certificate at \"{}\"", cert.display());
 
-        let mut params = match tunnel_end {
-            TunnelEnd::Client => {
-                let mut params = CertificateParams::new([]);
+        assert!(!tokio::fs::try_exists(&cert).await?);
+        assert!(!tokio::fs::try_exists(&key).await?);
 
-                params
-                    .distinguished_name
-                    .push(rcgen::DnType::CommonName, subject_name);
+        let key_pair = algo.generate_async().await?;
 
-                params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let (cert_pem, key_pem, x) =
+            Self::generate(ca, names, tunnel_end, validity, algo, key_pair)?;
 
-                // TODO: what serial? 0xc0ffee just a placeholder
-                params.serial_number = Some(rcgen::SerialNumber::from(vec![0xC0, 0xFF, 0xEE]));
+        write_private_async(&key, key_pem).await?;
+        write_atomic_async(&cert, cert_pem).await?;
 
-                params
-            }
-            TunnelEnd::Server => {
-                let mut params = CertificateParams::new([subject_name]);
+        Ok(x)
+    }
 
-                params
-                    .distinguished_name
-                    .push(rcgen::DnType::CommonName, "Example Client");
+    /// the certificate and key as PEM, and loaded
+    fn generate(
+        ca: &Certificate,
+        names: &SubjectNames,
+        tunnel_end: TunnelEnd,
+        validity: Validity,
+        algo: KeyAlgo,
+        key_pair: KeyPair,
+    ) -> anyhow::Result<(String, String, Self)> {
+        let mut params = names.params();
 
-                params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+        params.extended_key_usages = vec![tunnel_end.extended_key_usage()];
 
-                params
-            }
-        };
+        validity.apply(&mut params);
 
-        params.alg = DEFAULT_ALG;
+        // unique so that revoking one cert doesn't revoke the others
+        params.serial_number = Some(random_serial()?);
+        params.alg = algo.signature_algorithm();
+        params.key_pair = Some(key_pair);
         params.is_ca = rcgen::IsCa::NoCa;
 
         let x = Certificate::from_params(params)?;
 
-        std::fs::write(cert, x.serialize_pem_with_signer(ca)?)?;
-        std::fs::write(key, x.serialize_private_key_pem())?;
-
         let cert_der = x.serialize_der_with_signer(ca)?;
         let key_der = x.serialize_private_key_der();
 
-        Ok(Self {
-            cert: rustls::Certificate(cert_der),
-            key: Some(rustls::PrivateKey(key_der)),
-        })
+        Ok((
+            x.serialize_pem_with_signer(ca)?,
+            x.serialize_private_key_pem(),
+            Self {
+                cert: rustls::Certificate(cert_der),
+                key: Some(rustls::PrivateKey(key_der)),
+            },
+        ))
+    }
+
+    /// Create a new key and a certificate signing request for it. The key never has to leave this machine.
+    ///
+    /// Only the names are requested. The CA decides everything else. See [`crate::certs::CertificateAuthority::sign_request`].
+    pub fn new_request(
+        key: PathBuf,
+        csr: PathBuf,
+        names: &SubjectNames,
+        algo: KeyAlgo,
+    ) -> anyhow::Result<()> {
+        info!("creating new certificate request at \"{}\"", csr.display());
+
+        assert!(!key.exists());
+        assert!(!csr.exists());
+
+        let mut params = names.params();
+
+        params.alg = algo.signature_algorithm();
+        params.key_pair = Some(algo.generate()?);
+
+        let x = Certificate::from_params(params)?;
+
+        write_private(&key, x.serialize_private_key_pem())?;
+        write_atomic(&csr, x.serialize_request_pem()?)?;
+
+        Ok(())
     }
 
     /// Load an existing cert.
@@ -119,6 +200,24 @@ impl TunnelCertificate {
 
         Ok(Self { cert, key })
     }
+
+    /// Same as [`TunnelCertificate::load`] with tokio's file IO.
+    pub async fn load_async(cert: PathBuf) -> anyhow::Result<Self> {
+        let cert = cert_from_pem_async(cert).await?;
+        let key = None;
+
+        Ok(Self { cert, key })
+    }
+
+    /// Same as [`TunnelCertificate::load_with_key`] with tokio's file IO.
+    pub async fn load_with_key_async(cert: PathBuf, key: PathBuf) -> anyhow::Result<Self> {
+        info!("loading existing certificate from \"{}\"", cert.display());
+
+        let cert = cert_from_pem_async(cert).await?;
+        let key = Some(key_from_pem_async(key).await?);
+
+        Ok(Self { cert, key })
+    }
 }
 
 /// get the first cert from a PEM file.
@@ -130,13 +229,77 @@ pub fn cert_from_pem(path: PathBuf) -> anyhow::Result<rustls::Certificate> {
         path.display()
     ))?);
 
-    let der = rustls_pemfile::certs(&mut reader)
+    cert_from_reader(&mut reader)
+}
+
+/// Same as [`cert_from_pem`] with tokio's file IO.
+pub async fn cert_from_pem_async(path: PathBuf) -> anyhow::Result<rustls::Certificate> {
+    info!("loading certificate from \"{}\"", path.display());
+
+    cert_from_pem_bytes(&read_pem(&path).await?)
+}
+
+/// read a whole PEM file. They're small
+async fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path).await.with_context(|| {
+        format!(
+            "failed opening {}. maybe run the 'certs' command?",
+            path.display()
+        )
+    })
+}
+
+/// get the first cert from PEM bytes that are already in memory.
+pub fn cert_from_pem_bytes(pem: &[u8]) -> anyhow::Result<rustls::Certificate> {
+    cert_from_reader(&mut BufReader::new(pem))
+}
+
+fn cert_from_reader(reader: &mut dyn std::io::BufRead) -> anyhow::Result<rustls::Certificate> {
+    let der = rustls_pemfile::certs(reader)
         .next()
-        .context("no key found")??;
+        .context("no certificate found")??;
 
-    let key = rustls::Certificate(der.as_ref().to_vec());
+    let cert = rustls::Certificate(der.as_ref().to_vec());
 
-    Ok(key)
+    Ok(cert)
+}
+
+/// get every cert from a PEM file. For CA bundles.
+pub fn certs_from_pem(path: PathBuf) -> anyhow::Result<Vec<rustls::Certificate>> {
+    info!("loading certificates from \"{}\"", path.display());
+
+    let mut reader = BufReader::new(File::open(path.clone()).context(format!(
+        "failed opening {}. maybe run the 'certs' command?",
+        path.display()
+    ))?);
+
+    certs_from_reader(&mut reader)
+}
+
+/// Same as [`certs_from_pem`] with tokio's file IO.
+pub async fn certs_from_pem_async(path: PathBuf) -> anyhow::Result<Vec<rustls::Certificate>> {
+    info!("loading certificates from \"{}\"", path.display());
+
+    certs_from_pem_bytes(&read_pem(&path).await?)
+}
+
+/// get every cert from PEM bytes that are already in memory.
+pub fn certs_from_pem_bytes(pem: &[u8]) -> anyhow::Result<Vec<rustls::Certificate>> {
+    certs_from_reader(&mut BufReader::new(pem))
+}
+
+fn certs_from_reader(
+    reader: &mut dyn std::io::BufRead,
+) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let certs = rustls_pemfile::certs(reader)
+        .map(|x| Ok(rustls::Certificate(x?.as_ref().to_vec())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found");
+    }
+
+    Ok(certs)
 }
 
 /// get the first key from a PEM file.
@@ -145,7 +308,27 @@ pub fn key_from_pem(path: PathBuf) -> anyhow::Result<rustls::PrivateKey> {
 
     let mut reader = BufReader::new(File::open(path)?);
 
-    let der = rustls_pemfile::private_key(&mut reader)?.context("no key found")?;
+    key_from_reader(&mut reader)
+}
+
+/// Same as [`key_from_pem`] with tokio's file IO.
+pub async fn key_from_pem_async(path: PathBuf) -> anyhow::Result<rustls::PrivateKey> {
+    info!("loading key from \"{}\"", path.display());
+
+    let pem = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("failed opening {}", path.display()))?;
+
+    key_from_pem_bytes(&pem)
+}
+
+/// get the first key from PEM bytes that are already in memory.
+pub fn key_from_pem_bytes(pem: &[u8]) -> anyhow::Result<rustls::PrivateKey> {
+    key_from_reader(&mut BufReader::new(pem))
+}
+
+fn key_from_reader(reader: &mut dyn std::io::BufRead) -> anyhow::Result<rustls::PrivateKey> {
+    let der = rustls_pemfile::private_key(reader)?.context("no key found")?;
 
     let key = rustls::PrivateKey(der.secret_der().to_vec());
 
@@ -0,0 +1,61 @@
+use rcgen::{CertificateParams, SerialNumber};
+use ring::rand::{SecureRandom, SystemRandom};
+use time::{Duration, OffsetDateTime};
+
+pub const DEFAULT_CA_DAYS: u32 = 3650;
+pub const DEFAULT_SERVER_DAYS: u32 = 365;
+/// Client certs are the ones on laptops that get lost. Keep them short.
+pub const DEFAULT_CLIENT_DAYS: u32 = 30;
+
+/// so that peers with clocks that are a little behind still accept a brand new certificate
+const BACKDATE: Duration = Duration::hours(1);
+
+/// When a certificate is valid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Validity {
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+}
+
+impl Validity {
+    /// Valid from an hour ago for this many days.
+    pub fn days(days: u32) -> Self {
+        Self::starting(OffsetDateTime::now_utc() - BACKDATE, days)
+    }
+
+    /// Valid from an hour ago for this long. For renewing a certificate with the same lifetime it had.
+    pub fn lifetime(lifetime: Duration) -> Self {
+        let not_before = OffsetDateTime::now_utc() - BACKDATE;
+
+        Self {
+            not_before,
+            not_after: not_before + lifetime,
+        }
+    }
+
+    pub fn starting(not_before: OffsetDateTime, days: u32) -> Self {
+        Self {
+            not_before,
+            not_after: not_before + Duration::days(days.into()),
+        }
+    }
+
+    pub fn apply(&self, params: &mut CertificateParams) {
+        params.not_before = self.not_before;
+        params.not_after = self.not_after;
+    }
+}
+
+/// A random 128-bit serial number. Unique enough that revoking one certificate doesn't revoke any others.
+pub fn random_serial() -> anyhow::Result<SerialNumber> {
+    let mut x = [0; 16];
+
+    SystemRandom::new()
+        .fill(&mut x)
+        .map_err(|_| anyhow::anyhow!("failed generating a random serial number"))?;
+
+    // DER integers are signed. keep it positive without needing a 17th byte
+    x[0] &= 0x7f;
+
+    Ok(SerialNumber::from_slice(&x))
+}
@@ -1,11 +1,16 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
 use strum::EnumString;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
 use tracing::trace;
 
+use crate::counters::TunnelCounters;
 use crate::stream::Stream;
 
-#[derive(Copy, Clone, Debug, Default, EnumString, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, PartialEq)]
+#[serde(rename_all = "lowercase")]
 #[strum(ascii_case_insensitive)]
 pub enum CompressAlgo {
     #[default]
@@ -14,40 +19,49 @@ pub enum CompressAlgo {
 }
 
 /// this could be generic, but we don't need it to be
+///
+/// `counts` should be a child of the connection's counters. Sent is from the stream to QUIC. Received is from QUIC to the stream.
+///
+/// Returns the uncompressed bytes copied from QUIC to the stream and from the stream to QUIC.
 pub async fn copy_bidirectional_with_compression(
     compress_algo: CompressAlgo,
     mut recv_q: quinn::RecvStream,
     mut send_q: quinn::SendStream,
     t: Stream,
+    counts: Arc<TunnelCounters>,
 ) -> anyhow::Result<(u64, u64)> {
     // TODO: if no compression, use copy_bidirectional here
 
-    // // TODO: use counters type
-    // let mut a_to_b = AtomicU64::new(0);
-    // let mut b_to_a = AtomicU64::new(0);
-    // let mut compressed_a_to_b = AtomicU64::new(0);
-    // let mut compressed_b_to_a = AtomicU64::new(0);
-
     let (mut recv_t, mut send_t) = t.into_split();
 
     // read from a, compress, write to b
-    let a_to_b_f = async move {
-        copy_with_compression(
-            &mut recv_q,
-            &mut send_t,
-            CompressDirection::Decompress(compress_algo),
-        )
-        .await
+    let a_to_b_f = {
+        let counts = counts.clone();
+
+        async move {
+            copy_with_compression(
+                &mut recv_q,
+                &mut send_t,
+                CompressDirection::Decompress(compress_algo),
+                &counts,
+            )
+            .await
+        }
     };
 
     // read from b, decompress, write to a
-    let b_to_a_f = async move {
-        copy_with_compression(
-            &mut recv_t,
-            &mut send_q,
-            CompressDirection::Compress(compress_algo),
-        )
-        .await
+    let b_to_a_f = {
+        let counts = counts.clone();
+
+        async move {
+            copy_with_compression(
+                &mut recv_t,
+                &mut send_q,
+                CompressDirection::Compress(compress_algo),
+                &counts,
+            )
+            .await
+        }
     };
 
     let a_to_b_f = tokio::spawn(a_to_b_f);
@@ -62,8 +76,9 @@ pub async fn copy_bidirectional_with_compression(
         },
     }
 
-    // TODO: get this
-    let (a_to_b, b_to_a) = (0, 0);
+    let counts = counts.snapshot();
+
+    let (a_to_b, b_to_a) = (counts.bytes_recv as u64, counts.bytes_sent as u64);
 
     Ok((a_to_b, b_to_a))
 }
@@ -79,6 +94,7 @@ async fn copy_with_compression<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Un
     r: &mut R,
     w: &mut W,
     d: CompressDirection,
+    counts: &TunnelCounters,
 ) -> anyhow::Result<()> {
     // if compression is disabled, just use copy_bidirectional to avoid buffering
 
@@ -97,36 +113,42 @@ async fn copy_with_compression<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Un
         } else {
             // TODO: if n < 5 (or whatever the min size is): don't compress
             match d {
-                CompressDirection::None
-                | CompressDirection::Compress(CompressAlgo::None)
-                | CompressDirection::Decompress(CompressAlgo::None) => {
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+                CompressDirection::None => {
+                    w.write_all(&read_buf[..n]).await?;
 
+                    n
+                }
+                CompressDirection::Compress(CompressAlgo::None) => {
                     w.write_all(&read_buf[..n]).await?;
 
+                    counts.sent(n, 0);
+
                     n
                 }
-                CompressDirection::Compress(CompressAlgo::Lz4) => {
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+                CompressDirection::Decompress(CompressAlgo::None) => {
+                    w.write_all(&read_buf[..n]).await?;
 
-                    let compressed = lz4_flex::compress_prepend_size(&read_buf[..n]);
+                    counts.recv(n, 0);
 
-                    // compressed_a_to_b.fetch_add(compressed.len() as u64, atomic::Ordering::SeqCst);
+                    n
+                }
+                CompressDirection::Compress(CompressAlgo::Lz4) => {
+                    let compressed = lz4_flex::compress_prepend_size(&read_buf[..n]);
 
                     w.write_all(&compressed).await?;
 
+                    counts.sent(n, compressed.len());
+
                     compressed.len()
                 }
                 CompressDirection::Decompress(CompressAlgo::Lz4) => {
-                    // compressed_a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
-
                     let decompressed = lz4_flex::decompress_size_prepended(&read_buf[..n])
                         .map_err(|err| anyhow::anyhow!("decompress err: {:?}", err))?;
 
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
-
                     w.write_all(&decompressed).await?;
 
+                    counts.recv(decompressed.len(), n);
+
                     decompressed.len()
                 }
             }
@@ -0,0 +1,485 @@
+//! Configuration for running multiple tunnels from one process with the `run` subcommand.
+//!
+//! ```toml
+//! admin_socket = "/run/quic-tunnel.sock"
+//!
+//! [endpoint]
+//! cert_name = "data/first"
+//! listen = "0.0.0.0:8443"
+//! # or point at certificates anywhere. these override cert_name
+//! # ca = ["/etc/ssl/quic-tunnel/ca-bundle.pem"]
+//! # or different CAs for each direction
+//! # client_ca = ["/etc/ssl/quic-tunnel/client-ca.pem"]
+//! # server_ca = ["/etc/ssl/quic-tunnel/server-ca.pem"]
+//! # cert = "/etc/ssl/quic-tunnel/server.pem"
+//! # key = "/etc/ssl/quic-tunnel/server.key.pem"
+//! # trust servers by their public key instead of by the CA. `quick_certs` prints the pin
+//! # pin_sha256 = ["sha256/IwvoGaVzRre4coJbhBtns86BBjJduGt7VBJtN9/Gmhk="]
+//! # renew the certificate from the CA when it has less than this many days left. needs {cert_name}_ca.key.pem or ca_key
+//! # renew_within_days = 10
+//! # the passphrase for a CA key made with `quick_certs new --ca-passphrase-file`
+//! # ca_passphrase_file = "/etc/quic-tunnel/ca-passphrase"
+//! # refuse keys that aren't this kind. `quick_certs new --alg` makes them
+//! # key_alg = "ed25519"
+//!
+//! [transport]
+//! congestion_mode = "newreno"
+//! keep_alive = true
+//!
+//! # see TransportTuning for everything that can go here
+//! [transport.tuning]
+//! preset = "airplane"
+//! initial_rtt_ms = 500
+//!
+//! # seconds. anything not set uses the defaults
+//! [timeouts]
+//! idle = 300
+//! keep_alive = 100
+//! udp_flow_idle = 300
+//! handshake = 30
+//!
+//! [[udp_server]]
+//! name = "dns"
+//! forward = "1.1.1.1:53"
+//!
+//! [[udp_client]]
+//! name = "wireguard"
+//! listen = "127.0.0.1:51818"
+//! remote = "203.0.113.1:8443"
+//! server_name = "first_server"
+//!
+//! [[udp_client]]
+//! name = "dns_out"
+//! listen = "127.0.0.1:18053"
+//! # the server name defaults to the hostname
+//! remote = "first_server.example.com:8443"
+//! # one query, one response. don't hold on to the flow
+//! timeouts = { udp_flow_idle = 5 }
+//! ```
+//!
+//! Client tunnels can override the top level `[timeouts]`. Server tunnels can't because the tunnel isn't known until after the handshake.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use quinn::TransportConfig;
+use serde::Deserialize;
+
+use crate::certs::{KeyAlgo, SpkiPin, DEFAULT_RENEW_WITHIN_DAYS};
+use crate::compress::CompressAlgo;
+use crate::quic::{build_transport_config, CongestionMode, TransportTuning};
+use crate::resolve::RemoteAddr;
+use crate::timeouts::{TimeoutOverrides, Timeouts};
+
+/// Every tunnel in one file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelsConfig {
+    /// listen on this Unix socket for admin commands
+    pub admin_socket: Option<PathBuf>,
+
+    /// seconds to wait for open streams to finish after ctrl-c or SIGTERM
+    pub drain_timeout: Option<u64>,
+
+    pub endpoint: EndpointConfig,
+
+    #[serde(default)]
+    pub transport: TransportSettings,
+
+    /// defaults for every tunnel
+    #[serde(default)]
+    pub timeouts: TimeoutOverrides,
+
+    #[serde(default)]
+    pub udp_client: Vec<UdpClientConfig>,
+
+    #[serde(default)]
+    pub udp_server: Vec<UdpServerConfig>,
+
+    #[serde(default)]
+    pub reverse_proxy_client: Vec<ReverseProxyClientConfig>,
+
+    #[serde(default)]
+    pub reverse_proxy_server: Vec<ReverseProxyServerConfig>,
+}
+
+/// The one QUIC endpoint that all the tunnels share.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// prefix for the certificates that aren't set with `ca`, `cert`, or `key`
+    pub cert_name: Option<String>,
+
+    /// CAs that signed the other ends' certificates. Every cert in a PEM bundle is trusted. Defaults to `{cert_name}_ca.pem`
+    #[serde(default)]
+    pub ca: Vec<PathBuf>,
+
+    /// CAs that server tunnels trust for client certificates. Replaces `ca` for that direction
+    #[serde(default)]
+    pub client_ca: Vec<PathBuf>,
+
+    /// CAs that client tunnels trust for server certificates. Replaces `ca` for that direction
+    #[serde(default)]
+    pub server_ca: Vec<PathBuf>,
+
+    /// Defaults to `{cert_name}_server.pem` for server tunnels and `{cert_name}_client.pem` for client tunnels. Intermediate CAs can follow it in the same file.
+    ///
+    /// If both kinds of tunnels are in one file, this one cert is used for both. It needs both extended key usages
+    pub cert: Option<PathBuf>,
+
+    /// the key for `cert`. Defaults like `cert` does, with `.key.pem`
+    pub key: Option<PathBuf>,
+
+    /// PEM files of revoked client certificates. Defaults to `{cert_name}_crl.pem` if it exists. Reloaded when they change
+    #[serde(default)]
+    pub crl: Vec<PathBuf>,
+
+    /// the CA's key, for renewing `cert` before it expires. Defaults to `{cert_name}_ca.key.pem`. Renewal is skipped if it doesn't exist
+    pub ca_key: Option<PathBuf>,
+
+    /// renew `cert` when it has less than this many days left
+    #[serde(default = "default_renew_within_days")]
+    pub renew_within_days: u32,
+
+    /// the file with the passphrase for an encrypted `ca_key`
+    pub ca_passphrase_file: Option<PathBuf>,
+
+    /// load keys that other users can read instead of refusing to start
+    #[serde(default)]
+    pub insecure_key_permissions: bool,
+
+    /// refuse to start unless `key` is this kind: `ecdsa-p256`, `ed25519`, or `rsa-3072`
+    pub key_alg: Option<KeyAlgo>,
+
+    /// the local address to listen on with QUIC. Required if there are any server tunnels
+    pub listen: Option<SocketAddr>,
+
+    /// the local address for an endpoint that only makes connections. Defaults to `[::]:0`, which also reaches IPv4 servers
+    pub bind: Option<SocketAddr>,
+
+    /// Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
+    #[serde(default = "default_true")]
+    pub stateless_retry: bool,
+
+    /// client tunnels trust servers with these public keys instead of checking the CA and the name. `sha256/<base64>`
+    #[serde(default)]
+    pub pin_sha256: Vec<SpkiPin>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TransportSettings {
+    #[serde(default)]
+    pub congestion_mode: CongestionMode,
+
+    /// only one side needs keep alive. usually the client
+    #[serde(default = "default_true")]
+    pub keep_alive: bool,
+
+    #[serde(default)]
+    pub tuning: TransportTuning,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            congestion_mode: Default::default(),
+            keep_alive: true,
+            tuning: Default::default(),
+        }
+    }
+}
+
+impl TransportSettings {
+    pub fn build(&self, timeouts: &Timeouts) -> Arc<TransportConfig> {
+        build_transport_config(
+            self.keep_alive,
+            self.congestion_mode,
+            timeouts,
+            &self.tuning,
+        )
+    }
+}
+
+/// Listen for UDP locally and forward it through a QUIC tunnel.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UdpClientConfig {
+    pub name: String,
+    /// the local address to listen on
+    pub listen: SocketAddr,
+    /// the remote QUIC server to connect to. host:port or ip:port
+    pub remote: RemoteAddr,
+    /// the name on the remote server's certificate. See `EndpointBuilder::expected_server_name` for the defaults
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub timeouts: TimeoutOverrides,
+}
+
+/// Forward UDP from QUIC clients to a remote address.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UdpServerConfig {
+    pub name: String,
+    /// the remote address to forward client data to
+    pub forward: SocketAddr,
+}
+
+/// Forward streams from a reverse proxy server to a nearby service.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseProxyClientConfig {
+    pub name: String,
+    /// the remote QUIC server to connect to. host:port or ip:port
+    pub remote: RemoteAddr,
+    /// the name on the remote server's certificate. See `EndpointBuilder::expected_server_name` for the defaults
+    pub server_name: Option<String>,
+    pub tcp_connect: Option<SocketAddr>,
+    pub unix_connect: Option<PathBuf>,
+    #[serde(default)]
+    pub compress: CompressAlgo,
+    #[serde(default)]
+    pub timeouts: TimeoutOverrides,
+}
+
+/// Listen for users and forward them to any connected reverse proxy clients.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseProxyServerConfig {
+    pub name: String,
+    pub tcp_listen: Option<SocketAddr>,
+    pub unix_listen: Option<PathBuf>,
+    #[serde(default)]
+    pub compress: CompressAlgo,
+}
+
+/// Any one of the tunnels. Used to tell which tunnels changed when the config is reloaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunnelConfig {
+    UdpClient(UdpClientConfig),
+    UdpServer(UdpServerConfig),
+    ReverseProxyClient(ReverseProxyClientConfig),
+    ReverseProxyServer(ReverseProxyServerConfig),
+}
+
+impl TunnelConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::UdpClient(x) => &x.name,
+            Self::UdpServer(x) => &x.name,
+            Self::ReverseProxyClient(x) => &x.name,
+            Self::ReverseProxyServer(x) => &x.name,
+        }
+    }
+
+    /// true if the tunnel makes QUIC connections
+    pub fn is_client(&self) -> bool {
+        matches!(self, Self::UdpClient(_) | Self::ReverseProxyClient(_))
+    }
+
+    /// the timeout overrides for client tunnels. Server tunnels use the top level timeouts
+    pub fn timeouts(&self) -> Option<&TimeoutOverrides> {
+        match self {
+            Self::UdpClient(x) => Some(&x.timeouts),
+            Self::ReverseProxyClient(x) => Some(&x.timeouts),
+            _ => None,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_renew_within_days() -> u32 {
+    DEFAULT_RENEW_WITHIN_DAYS
+}
+
+/// The ALPN protocol for a tunnel. Connections on the shared endpoint are sent to the tunnel with the matching name.
+pub fn tunnel_alpn(name: &str) -> Vec<u8> {
+    format!("quic-tunnel/{}", name).into_bytes()
+}
+
+impl TunnelsConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed reading {}", path.display()))?;
+
+        let x: Self = toml::from_str(&contents)
+            .with_context(|| format!("failed parsing {}", path.display()))?;
+
+        x.validate()?;
+
+        Ok(x)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.timeouts().validate().context("timeouts")?;
+
+        // only the tunnels with overrides. otherwise the same warnings get logged over and over
+        for x in self.udp_client.iter() {
+            if x.timeouts != Default::default() {
+                self.tunnel_timeouts(&x.timeouts)
+                    .validate()
+                    .with_context(|| format!("udp_client {} timeouts", x.name))?;
+            }
+        }
+
+        for x in self.reverse_proxy_client.iter() {
+            if x.timeouts != Default::default() {
+                self.tunnel_timeouts(&x.timeouts)
+                    .validate()
+                    .with_context(|| format!("reverse_proxy_client {} timeouts", x.name))?;
+            }
+        }
+
+        let mut names = HashSet::new();
+
+        for name in self.names() {
+            if !names.insert(name) {
+                anyhow::bail!("duplicate tunnel name: {}", name);
+            }
+        }
+
+        if self.endpoint.cert_name.is_none() {
+            let x = &self.endpoint;
+
+            if x.cert.is_none() || x.key.is_none() {
+                anyhow::bail!(
+                    "endpoint.cert_name is required unless endpoint.cert and endpoint.key are set"
+                );
+            }
+
+            if self.has_servers() && x.ca.is_empty() && x.client_ca.is_empty() {
+                anyhow::bail!("server tunnels need endpoint.ca or endpoint.client_ca when endpoint.cert_name isn't set");
+            }
+
+            if self.has_clients() && x.ca.is_empty() && x.server_ca.is_empty() {
+                anyhow::bail!("client tunnels need endpoint.ca or endpoint.server_ca when endpoint.cert_name isn't set");
+            }
+        }
+
+        if self.endpoint.listen.is_some() && self.endpoint.bind.is_some() {
+            anyhow::bail!("endpoint.listen and endpoint.bind can't both be set. clients use the listen address too");
+        }
+
+        if self.has_servers() && self.endpoint.listen.is_none() {
+            anyhow::bail!("endpoint.listen is required for udp_server and reverse_proxy_server");
+        }
+
+        for x in self.reverse_proxy_client.iter() {
+            if x.tcp_connect.is_some() == x.unix_connect.is_some() {
+                anyhow::bail!(
+                    "reverse_proxy_client {} needs either tcp_connect or unix_connect. not none. not both",
+                    x.name
+                );
+            }
+        }
+
+        for x in self.reverse_proxy_server.iter() {
+            if x.tcp_listen.is_none() && x.unix_listen.is_none() {
+                anyhow::bail!(
+                    "reverse_proxy_server {} needs tcp_listen or unix_listen or both",
+                    x.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the top level timeouts. used by the endpoint and server tunnels
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts.apply(Default::default())
+    }
+
+    /// a client tunnel's timeouts on top of the top level timeouts
+    pub fn tunnel_timeouts(&self, overrides: &TimeoutOverrides) -> Timeouts {
+        overrides.apply(self.timeouts())
+    }
+
+    /// the names of every tunnel
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.udp_client
+            .iter()
+            .map(|x| x.name.as_str())
+            .chain(self.udp_server.iter().map(|x| x.name.as_str()))
+            .chain(self.reverse_proxy_client.iter().map(|x| x.name.as_str()))
+            .chain(self.reverse_proxy_server.iter().map(|x| x.name.as_str()))
+    }
+
+    /// every tunnel keyed by name
+    pub fn tunnels(&self) -> BTreeMap<String, TunnelConfig> {
+        self.udp_client
+            .iter()
+            .cloned()
+            .map(TunnelConfig::UdpClient)
+            .chain(self.udp_server.iter().cloned().map(TunnelConfig::UdpServer))
+            .chain(
+                self.reverse_proxy_client
+                    .iter()
+                    .cloned()
+                    .map(TunnelConfig::ReverseProxyClient),
+            )
+            .chain(
+                self.reverse_proxy_server
+                    .iter()
+                    .cloned()
+                    .map(TunnelConfig::ReverseProxyServer),
+            )
+            .map(|x| (x.name().to_string(), x))
+            .collect()
+    }
+
+    /// true if any tunnels accept QUIC connections
+    pub fn has_servers(&self) -> bool {
+        !self.udp_server.is_empty() || !self.reverse_proxy_server.is_empty()
+    }
+
+    /// true if any tunnels make QUIC connections
+    pub fn has_clients(&self) -> bool {
+        !self.udp_client.is_empty() || !self.reverse_proxy_client.is_empty()
+    }
+
+    /// ALPN protocols for the server side of the shared endpoint
+    pub fn server_alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.udp_server
+            .iter()
+            .map(|x| tunnel_alpn(&x.name))
+            .chain(
+                self.reverse_proxy_server
+                    .iter()
+                    .map(|x| tunnel_alpn(&x.name)),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the TOML at the top of this file
+    fn doc_example() -> String {
+        include_str!("config.rs")
+            .lines()
+            .skip_while(|x| *x != "//! ```toml")
+            .skip(1)
+            .take_while(|x| *x != "//! ```")
+            .map(|x| x.strip_prefix("//!").unwrap().trim_start())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn doc_example_is_valid() {
+        let x: TunnelsConfig = toml::from_str(&doc_example()).unwrap();
+
+        x.validate().unwrap();
+
+        assert_eq!(x.names().count(), 3);
+    }
+}
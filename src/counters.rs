@@ -1,12 +1,22 @@
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::sync::atomic::{self, AtomicUsize};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::watch;
+use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
 use tokio::time::interval;
 use tracing::{info, warn};
 
+use crate::{events, TunnelCacheKey};
+
+/// Counters for a process, a connection, or a single flow.
+///
+/// Counters can have a parent. Anything counted on a child is also counted on all of its parents.
 pub struct TunnelCounters {
     packets_sent: AtomicUsize,
     packets_recv: AtomicUsize,
@@ -14,11 +24,29 @@ pub struct TunnelCounters {
     bytes_recv: AtomicUsize,
     compressed_bytes_sent: AtomicUsize,
     compressed_bytes_recv: AtomicUsize,
+    streams_opened: AtomicUsize,
+    streams_closed: AtomicUsize,
+    flow_streams_opened: AtomicUsize,
+    flow_streams_closed: AtomicUsize,
+    parent: Option<Arc<TunnelCounters>>,
     watch: watch::Sender<()>,
 }
 
 impl TunnelCounters {
     pub fn new() -> Arc<Self> {
+        Self::with_parent(None)
+    }
+
+    /// Create counters that also count towards `self`.
+    pub fn child(self: &Arc<Self>) -> Arc<Self> {
+        Self::with_parent(Some(self.clone()))
+    }
+
+    fn is_child_of(&self, parent: &Arc<TunnelCounters>) -> bool {
+        self.parent.as_ref().is_some_and(|x| Arc::ptr_eq(x, parent))
+    }
+
+    fn with_parent(parent: Option<Arc<TunnelCounters>>) -> Arc<Self> {
         // there are probably more efficient ways to do this, but it works for now
         let (watch, _) = watch::channel(());
 
@@ -29,6 +57,11 @@ impl TunnelCounters {
             bytes_recv: AtomicUsize::new(0),
             compressed_bytes_sent: AtomicUsize::new(0),
             compressed_bytes_recv: AtomicUsize::new(0),
+            streams_opened: AtomicUsize::new(0),
+            streams_closed: AtomicUsize::new(0),
+            flow_streams_opened: AtomicUsize::new(0),
+            flow_streams_closed: AtomicUsize::new(0),
+            parent,
             watch,
         };
 
@@ -67,6 +100,23 @@ impl Debug for TunnelCounters {
             &self.compressed_bytes_recv.load(atomic::Ordering::SeqCst),
         );
 
+        state.field(
+            "streams_opened",
+            &self.streams_opened.load(atomic::Ordering::SeqCst),
+        );
+        state.field(
+            "streams_closed",
+            &self.streams_closed.load(atomic::Ordering::SeqCst),
+        );
+        state.field(
+            "flow_streams_opened",
+            &self.flow_streams_opened.load(atomic::Ordering::SeqCst),
+        );
+        state.field(
+            "flow_streams_closed",
+            &self.flow_streams_closed.load(atomic::Ordering::SeqCst),
+        );
+
         state.finish()
     }
 }
@@ -79,6 +129,10 @@ impl TunnelCounters {
             .fetch_add(compressed, atomic::Ordering::SeqCst);
 
         self.watch.send_replace(());
+
+        if let Some(parent) = &self.parent {
+            parent.sent(n, compressed);
+        }
     }
 
     pub fn recv(&self, n: usize, compressed: usize) {
@@ -88,6 +142,65 @@ impl TunnelCounters {
             .fetch_add(compressed, atomic::Ordering::SeqCst);
 
         self.watch.send_replace(());
+
+        if let Some(parent) = &self.parent {
+            parent.recv(n, compressed);
+        }
+    }
+
+    pub fn stream_opened(&self) {
+        self.streams_opened.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if let Some(parent) = &self.parent {
+            parent.stream_opened();
+        }
+    }
+
+    pub fn stream_closed(&self) {
+        self.streams_closed.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if let Some(parent) = &self.parent {
+            parent.stream_closed();
+        }
+    }
+
+    /// A UDP flow's stream. It counts as a stream too.
+    ///
+    /// Flows never finish on their own, so shutdown doesn't wait for them.
+    pub fn flow_stream_opened(&self) {
+        self.streams_opened.fetch_add(1, atomic::Ordering::SeqCst);
+        self.flow_streams_opened
+            .fetch_add(1, atomic::Ordering::SeqCst);
+
+        if let Some(parent) = &self.parent {
+            parent.flow_stream_opened();
+        }
+    }
+
+    pub fn flow_stream_closed(&self) {
+        self.streams_closed.fetch_add(1, atomic::Ordering::SeqCst);
+        self.flow_streams_closed
+            .fetch_add(1, atomic::Ordering::SeqCst);
+
+        if let Some(parent) = &self.parent {
+            parent.flow_stream_closed();
+        }
+    }
+
+    /// this doesn't lock the counters, so requests while reading may be missed
+    pub fn snapshot(&self) -> CountersSnapshot {
+        CountersSnapshot {
+            packets_sent: self.packets_sent.load(atomic::Ordering::SeqCst),
+            packets_recv: self.packets_recv.load(atomic::Ordering::SeqCst),
+            bytes_sent: self.bytes_sent.load(atomic::Ordering::SeqCst),
+            bytes_recv: self.bytes_recv.load(atomic::Ordering::SeqCst),
+            compressed_bytes_sent: self.compressed_bytes_sent.load(atomic::Ordering::SeqCst),
+            compressed_bytes_recv: self.compressed_bytes_recv.load(atomic::Ordering::SeqCst),
+            streams_opened: self.streams_opened.load(atomic::Ordering::SeqCst),
+            streams_closed: self.streams_closed.load(atomic::Ordering::SeqCst),
+            flow_streams_opened: self.flow_streams_opened.load(atomic::Ordering::SeqCst),
+            flow_streams_closed: self.flow_streams_closed.load(atomic::Ordering::SeqCst),
+        }
     }
 
     pub fn spawn_stats_loop(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
@@ -116,3 +229,457 @@ impl TunnelCounters {
         tokio::spawn(f)
     }
 }
+
+/// A point-in-time copy of some [`TunnelCounters`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CountersSnapshot {
+    pub packets_sent: usize,
+    pub packets_recv: usize,
+    pub bytes_sent: usize,
+    pub bytes_recv: usize,
+    pub compressed_bytes_sent: usize,
+    pub compressed_bytes_recv: usize,
+    pub streams_opened: usize,
+    pub streams_closed: usize,
+    pub flow_streams_opened: usize,
+    pub flow_streams_closed: usize,
+}
+
+impl CountersSnapshot {
+    pub fn total_bytes(&self) -> usize {
+        self.bytes_sent + self.bytes_recv
+    }
+
+    pub fn open_streams(&self) -> usize {
+        self.streams_opened.saturating_sub(self.streams_closed)
+    }
+
+    /// the open streams that carry UDP flows. included in `open_streams`
+    pub fn open_flow_streams(&self) -> usize {
+        self.flow_streams_opened
+            .saturating_sub(self.flow_streams_closed)
+    }
+}
+
+/// What a set of counters in the [`CountersRegistry`] is counting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CountersScope {
+    /// a QUIC connection. the id is [`quinn::Connection::stable_id`]
+    Connection { id: usize, remote: SocketAddr },
+    /// a UDP flow through a tunnel
+    Flow(TunnelCacheKey),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TopTalker {
+    pub scope: CountersScope,
+    pub counts: CountersSnapshot,
+}
+
+/// A UDP flow's counters. Flows with a close handle last as long as their stream instead of expiring when idle.
+#[derive(Clone)]
+struct FlowEntry {
+    counts: Arc<TunnelCounters>,
+    close: Option<Arc<Notify>>,
+}
+
+/// Expires flows from [`CountersRegistry::flow`] after they have been idle. Flows from [`CountersRegistry::stream_flow`] never expire.
+struct FlowExpiry(Duration);
+
+impl Expiry<TunnelCacheKey, FlowEntry> for FlowExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &TunnelCacheKey,
+        value: &FlowEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        value.close.is_none().then_some(self.0)
+    }
+
+    fn expire_after_read(
+        &self,
+        _key: &TunnelCacheKey,
+        value: &FlowEntry,
+        _read_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+        _last_modified_at: Instant,
+    ) -> Option<Duration> {
+        value.close.is_none().then_some(self.0)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &TunnelCacheKey,
+        value: &FlowEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.close.is_none().then_some(self.0)
+    }
+}
+
+/// Counters for the whole process, each connection, and each UDP flow.
+///
+/// Connections are removed when they close, and their flows with them. Client flows are also removed after they have been idle for as long as the UDP [`crate::TunnelCache`].
+///
+/// This also keeps a handle to every open connection so that the admin socket can inspect them.
+pub struct CountersRegistry {
+    process: Arc<TunnelCounters>,
+    connections: Cache<usize, (quinn::Connection, Arc<TunnelCounters>)>,
+    flows: Cache<TunnelCacheKey, FlowEntry>,
+}
+
+impl CountersRegistry {
+    pub fn new(flow_idle: Duration) -> Arc<Self> {
+        let process = TunnelCounters::new();
+
+        // connections are removed by hand when they close
+        let connections = CacheBuilder::new(10_000).build();
+
+        let flows = CacheBuilder::new(10_000)
+            .expire_after(FlowExpiry(flow_idle))
+            .eviction_listener(|key, _, cause| {
+                // flows that were removed on purpose didn't go idle
+                if cause == RemovalCause::Expired {
+                    events::udp_flow_evicted(&key, cause);
+                }
+            })
+            .build();
+
+        let x = Self {
+            process,
+            connections,
+            flows,
+        };
+
+        Arc::new(x)
+    }
+
+    pub fn process(&self) -> &Arc<TunnelCounters> {
+        &self.process
+    }
+
+    /// get or create the counters for a QUIC connection
+    pub async fn connection(&self, conn: &quinn::Connection) -> Arc<TunnelCounters> {
+        let init = async move { (conn.clone(), self.process.child()) };
+
+        self.connections.get_with(conn.stable_id(), init).await.1
+    }
+
+    /// all the open connections and their counters
+    pub fn connections(&self) -> Vec<(quinn::Connection, Arc<TunnelCounters>)> {
+        self.connections.iter().map(|(_, x)| x).collect()
+    }
+
+    pub async fn get_connection(&self, id: usize) -> Option<quinn::Connection> {
+        self.connections.get(&id).await.map(|(conn, _)| conn)
+    }
+
+    /// Forget a closed connection and its flows. A reconnect gets new flows even if their keys are the same.
+    pub async fn remove_connection(&self, conn: &quinn::Connection) {
+        if let Some((_, counts)) = self.connections.remove(&conn.stable_id()).await {
+            self.remove_flows_of(&counts).await;
+        }
+    }
+
+    async fn remove_flows_of(&self, connection: &Arc<TunnelCounters>) {
+        let keys: Vec<_> = self
+            .flows
+            .iter()
+            .filter(|(_, x)| x.counts.is_child_of(connection))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in keys.iter() {
+            self.flows.invalidate(key).await;
+        }
+    }
+
+    /// Forget a UDP flow's counters. Flows from [`CountersRegistry::stream_flow`] need this when their stream closes.
+    pub async fn remove_flow(&self, key: &TunnelCacheKey) {
+        self.flows.invalidate(key).await;
+    }
+
+    /// get or create the counters for a UDP flow.
+    ///
+    /// Call this every time the flow is used so that it doesn't expire.
+    pub async fn flow(
+        &self,
+        key: TunnelCacheKey,
+        connection: &Arc<TunnelCounters>,
+    ) -> Arc<TunnelCounters> {
+        let init = async move {
+            events::udp_flow_created(&key);
+
+            FlowEntry {
+                counts: connection.child(),
+                close: None,
+            }
+        };
+
+        self.flows.get_with(key, init).await.counts
+    }
+
+    /// Create the counters for a UDP flow that lasts as long as its stream. It never goes idle.
+    ///
+    /// Servers use this. The client decides when a flow is idle and finishes its stream.
+    /// `close` is notified when the flow is flushed. The stream should close then.
+    pub async fn stream_flow(
+        &self,
+        key: TunnelCacheKey,
+        connection: &Arc<TunnelCounters>,
+        close: Arc<Notify>,
+    ) -> Arc<TunnelCounters> {
+        events::udp_flow_created(&key);
+
+        let counts = connection.child();
+
+        let x = FlowEntry {
+            counts: counts.clone(),
+            close: Some(close),
+        };
+
+        self.flows.insert(key, x).await;
+
+        counts
+    }
+
+    /// the flows from [`CountersRegistry::stream_flow`]
+    pub fn stream_flows(&self) -> Vec<TunnelCacheKey> {
+        self.flows
+            .iter()
+            .filter(|(_, x)| x.close.is_some())
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Close the streams of flows from [`CountersRegistry::stream_flow`]. None closes every one. Returns the flows that were closed.
+    ///
+    /// They are removed once their streams have closed.
+    pub fn close_stream_flows(&self, from: Option<SocketAddr>) -> Vec<TunnelCacheKey> {
+        self.flows
+            .iter()
+            .filter(|(key, _)| from.is_none_or(|from| key.from == from))
+            .filter_map(|(key, x)| {
+                x.close.as_ref()?.notify_one();
+
+                Some(*key)
+            })
+            .collect()
+    }
+
+    /// the connections that have transferred the most bytes
+    pub fn top_connections(&self, n: usize) -> Vec<TopTalker> {
+        let talkers = self
+            .connections
+            .iter()
+            .map(|(id, (conn, counts))| TopTalker {
+                scope: CountersScope::Connection {
+                    id: *id,
+                    remote: conn.remote_address(),
+                },
+                counts: counts.snapshot(),
+            })
+            .collect();
+
+        top_n(talkers, n)
+    }
+
+    /// the UDP flows that have transferred the most bytes
+    pub fn top_flows(&self, n: usize) -> Vec<TopTalker> {
+        let talkers = self
+            .flows
+            .iter()
+            .map(|(key, x)| TopTalker {
+                scope: CountersScope::Flow(*key),
+                counts: x.counts.snapshot(),
+            })
+            .collect();
+
+        top_n(talkers, n)
+    }
+
+    /// the connections and UDP flows that have transferred the most bytes
+    pub fn top_talkers(&self, n: usize) -> Vec<TopTalker> {
+        let mut talkers = self.top_connections(n);
+
+        talkers.extend(self.top_flows(n));
+
+        top_n(talkers, n)
+    }
+
+    pub fn spawn_stats_loop(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let mut watch = self.process.watch.subscribe();
+        watch.borrow_and_update();
+
+        let f = async move {
+            let mut i = interval(Duration::from_secs(10));
+            i.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                i.tick().await;
+
+                if let Err(err) = watch.changed().await {
+                    warn!("watch channel closed: {}", err);
+                    break;
+                };
+
+                watch.borrow_and_update();
+
+                info!(counts=?self.process, top=?self.top_talkers(5), "stats");
+            }
+        };
+
+        tokio::spawn(f)
+    }
+}
+
+fn top_n(mut talkers: Vec<TopTalker>, n: usize) -> Vec<TopTalker> {
+    talkers.sort_unstable_by_key(|x| std::cmp::Reverse(x.counts.total_bytes()));
+    talkers.truncate(n);
+    talkers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow_key(port: u16) -> TunnelCacheKey {
+        TunnelCacheKey {
+            addr_a: "127.0.0.1:8053".parse().unwrap(),
+            from: SocketAddr::from(([127, 0, 0, 1], port)),
+            addr_b: "127.0.0.1:8443".parse().unwrap(),
+        }
+    }
+
+    fn talker(port: u16, bytes_sent: usize, bytes_recv: usize) -> TopTalker {
+        TopTalker {
+            scope: CountersScope::Flow(flow_key(port)),
+            counts: CountersSnapshot {
+                bytes_sent,
+                bytes_recv,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn children_count_towards_their_parents() {
+        let process = TunnelCounters::new();
+        let connection = process.child();
+        let flow = connection.child();
+        let other = connection.child();
+
+        flow.sent(100, 40);
+        flow.recv(10, 0);
+        flow.flow_stream_opened();
+        other.stream_opened();
+        other.stream_closed();
+
+        let x = process.snapshot();
+
+        assert_eq!(x.packets_sent, 1);
+        assert_eq!(x.bytes_sent, 100);
+        assert_eq!(x.compressed_bytes_sent, 40);
+        assert_eq!(x.bytes_recv, 10);
+        assert_eq!(x.open_streams(), 1);
+        assert_eq!(x.open_flow_streams(), 1);
+        assert_eq!(connection.snapshot(), x);
+
+        // siblings don't see each other
+        assert_eq!(other.snapshot().total_bytes(), 0);
+        assert_eq!(flow.snapshot().streams_closed, 0);
+    }
+
+    #[test]
+    fn top_n_sorts_by_total_bytes() {
+        let talkers = vec![
+            talker(1, 10, 0),
+            talker(2, 0, 300),
+            talker(3, 100, 100),
+            talker(4, 0, 0),
+        ];
+
+        let x = top_n(talkers, 2);
+
+        let ports: Vec<_> = x
+            .iter()
+            .map(|x| match x.scope {
+                CountersScope::Flow(key) => key.from.port(),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(ports, [2, 3]);
+
+        assert!(top_n(vec![], 5).is_empty());
+        assert_eq!(top_n(vec![talker(1, 1, 1)], 5).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn removing_a_connection_forgets_its_flows() {
+        let registry = CountersRegistry::new(Duration::from_secs(60));
+
+        let old = registry.process().child();
+        let other = registry.process().child();
+
+        registry.flow(flow_key(1), &old).await.sent(100, 0);
+        registry.flow(flow_key(2), &other).await.sent(10, 0);
+
+        registry.remove_flows_of(&old).await;
+        registry.flows.run_pending_tasks().await;
+
+        let top = registry.top_flows(10);
+
+        assert_eq!(top.len(), 1);
+        assert!(matches!(top[0].scope, CountersScope::Flow(x) if x == flow_key(2)));
+
+        // the same key after a reconnect counts against the new connection
+        let new = registry.process().child();
+
+        let flow = registry.flow(flow_key(1), &new).await;
+
+        assert_eq!(flow.snapshot().total_bytes(), 0);
+
+        flow.sent(5, 0);
+
+        assert_eq!(new.snapshot().bytes_sent, 5);
+        assert_eq!(old.snapshot().bytes_sent, 100);
+    }
+
+    #[tokio::test]
+    async fn closing_stream_flows() {
+        let registry = CountersRegistry::new(Duration::from_secs(60));
+
+        let connection = registry.process().child();
+
+        let close_a = Arc::new(Notify::new());
+        let close_b = Arc::new(Notify::new());
+
+        registry
+            .stream_flow(flow_key(1), &connection, close_a.clone())
+            .await;
+        registry
+            .stream_flow(flow_key(2), &connection, close_b.clone())
+            .await;
+        registry.flow(flow_key(3), &connection).await;
+        registry.flows.run_pending_tasks().await;
+
+        let mut keys = registry.stream_flows();
+        keys.sort_by_key(|x| x.from);
+
+        assert_eq!(keys, [flow_key(1), flow_key(2)]);
+
+        let closed = registry.close_stream_flows(Some(flow_key(2).from));
+
+        assert_eq!(closed, [flow_key(2)]);
+
+        // the permit is kept until the stream's task waits for it
+        close_b.notified().await;
+
+        assert_eq!(registry.close_stream_flows(None).len(), 2);
+
+        close_a.notified().await;
+    }
+}
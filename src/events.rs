@@ -0,0 +1,123 @@
+//! Connection lifecycle events.
+//!
+//! Everything here logs at INFO with the [`TARGET`] target and an `event` field so that collectors can filter on them.
+//! Use `--log-format json` to get one machine-parseable record per line.
+
+use std::time::Duration;
+
+use moka::notification::RemovalCause;
+use quinn::{Connection, ConnectionError, StreamId};
+use tracing::info;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::certs::uri_names;
+use crate::counters::CountersSnapshot;
+use crate::TunnelCacheKey;
+
+pub const TARGET: &str = "quic_tunnel::events";
+
+/// the subject of the first certificate the peer presented
+pub fn peer_subject(conn: &Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+
+    let cert = certs.first()?;
+
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+
+    Some(cert.subject().to_string())
+}
+
+/// The first URI on the peer's certificate. A stable identity to authorize clients by. `quick_certs` gives clients `quic-tunnel://{name}/client`
+pub fn peer_uri(conn: &Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+
+    uri_names(certs.first()?).ok()?.into_iter().next()
+}
+
+pub fn connection_opened(conn: &Connection, zero_rtt: bool) {
+    info!(
+        target: TARGET,
+        event = "connection_opened",
+        connection_id = conn.stable_id(),
+        remote = %conn.remote_address(),
+        peer = peer_subject(conn).as_deref().unwrap_or(""),
+        peer_uri = peer_uri(conn).as_deref().unwrap_or(""),
+        zero_rtt,
+    );
+}
+
+pub fn connection_closed(conn: &Connection, reason: &ConnectionError) {
+    info!(
+        target: TARGET,
+        event = "connection_closed",
+        connection_id = conn.stable_id(),
+        remote = %conn.remote_address(),
+        peer = peer_subject(conn).as_deref().unwrap_or(""),
+        peer_uri = peer_uri(conn).as_deref().unwrap_or(""),
+        reason = %reason,
+    );
+}
+
+pub fn stream_opened(conn: &Connection, stream_id: StreamId) {
+    info!(
+        target: TARGET,
+        event = "stream_opened",
+        connection_id = conn.stable_id(),
+        stream_id = %stream_id,
+    );
+}
+
+pub fn stream_closed(
+    conn: &Connection,
+    stream_id: StreamId,
+    counts: CountersSnapshot,
+    duration: Duration,
+) {
+    info!(
+        target: TARGET,
+        event = "stream_closed",
+        connection_id = conn.stable_id(),
+        stream_id = %stream_id,
+        bytes_sent = counts.bytes_sent,
+        bytes_recv = counts.bytes_recv,
+        compression_ratio_sent = compression_ratio(counts.bytes_sent, counts.compressed_bytes_sent),
+        compression_ratio_recv = compression_ratio(counts.bytes_recv, counts.compressed_bytes_recv),
+        duration_ms = duration.as_millis() as u64,
+    );
+}
+
+pub fn udp_flow_created(key: &TunnelCacheKey) {
+    info!(
+        target: TARGET,
+        event = "udp_flow_created",
+        addr_a = %key.addr_a,
+        from = %key.from,
+        addr_b = %key.addr_b,
+    );
+}
+
+pub fn udp_flow_evicted(key: &TunnelCacheKey, cause: RemovalCause) {
+    info!(
+        target: TARGET,
+        event = "udp_flow_evicted",
+        addr_a = %key.addr_a,
+        from = %key.from,
+        addr_b = %key.addr_b,
+        cause = ?cause,
+    );
+}
+
+/// compressed bytes / uncompressed bytes. 1.0 if nothing was compressed
+fn compression_ratio(bytes: usize, compressed: usize) -> f64 {
+    if bytes == 0 || compressed == 0 {
+        1.0
+    } else {
+        compressed as f64 / bytes as f64
+    }
+}
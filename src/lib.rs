@@ -1,17 +1,28 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc};
 
 use moka::future::Cache;
+use serde::Serialize;
 use tokio::sync::Mutex;
 
+pub mod admin;
 pub mod certs;
 pub mod compress;
+pub mod config;
 pub mod counters;
+pub mod events;
 pub mod log;
+pub mod net;
 pub mod quic;
+pub mod reload;
+pub mod renew;
+pub mod resolve;
+pub mod shutdown;
 pub mod stream;
+pub mod timeouts;
 pub mod tls;
+pub mod tunnels;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
 pub struct TunnelCacheKey {
     pub addr_a: SocketAddr,
     pub from: SocketAddr,
@@ -26,9 +37,3 @@ pub type TunnelCache = Cache<
         Arc<Mutex<Option<quinn::RecvStream>>>,
     ),
 >;
-
-/// how long to wait for a tunnel to be idle before closing it.
-/// TODO: make sure this matches quinn's config.
-pub fn get_tunnel_timeout() -> Duration {
-    Duration::from_secs(300)
-}
@@ -1,20 +1,146 @@
-use tracing::{info, Level};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use std::path::PathBuf;
 
-/// TODO: filtered fmt layer, plus a different filter for tokio-console
-/// TODO: verbosity options from command
+use strum::EnumString;
+use tracing::{error, info, level_filters::LevelFilter};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[derive(Copy, Clone, Debug, Default, EnumString, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum LogFormat {
+    /// human readable
+    #[default]
+    Pretty,
+    /// one JSON object per line. good for log collectors
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, Default, EnumString, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    /// positive is more verbose. negative is more quiet. 0 is INFO
+    pub verbosity: i8,
+    /// also write logs to files with this path as the prefix
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+}
+
+impl LogOptions {
+    /// `RUST_LOG` takes priority over this
+    pub fn default_level(&self) -> LevelFilter {
+        match self.verbosity {
+            i8::MIN..=-3 => LevelFilter::OFF,
+            -2 => LevelFilter::ERROR,
+            -1 => LevelFilter::WARN,
+            0 => LevelFilter::INFO,
+            1 => LevelFilter::DEBUG,
+            2..=i8::MAX => LevelFilter::TRACE,
+        }
+    }
+
+    fn env_filter(&self) -> EnvFilter {
+        EnvFilter::builder()
+            .with_default_directive(self.default_level().into())
+            .from_env_lossy()
+    }
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty if ansi => fmt::layer().pretty().with_writer(writer).boxed(),
+        // span fields are formatted once per field formatter type. the pretty formatter's would have ansi codes in them
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .fmt_fields(fmt::format::DefaultFields::new())
+            .with_ansi(false)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// The fmt layers get their filter from `RUST_LOG` and the verbosity. tokio-console has its own filter.
+///
 /// TODO: better way of setting defaults
 /// TODO: sentry
-/// TODO: panic handler
-pub fn configure_logging() {
-    tracing_subscriber::registry()
-        .with(fmt::layer().pretty())
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(Level::INFO.into())
-                .from_env_lossy(),
-        )
-        .init();
+pub fn configure_logging(options: LogOptions) -> anyhow::Result<()> {
+    let stdout_layer = fmt_layer(options.format, std::io::stdout, true);
+
+    // the file appender blocks. that way nothing is lost if we panic
+    let file_layer = match &options.file {
+        Some(path) => {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+
+            let prefix = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("log file must have a name"))?;
+
+            let appender = RollingFileAppender::new(options.rotation.into(), dir, prefix);
+
+            Some(fmt_layer(options.format, appender, false))
+        }
+        None => None,
+    };
+
+    let fmt_layers = stdout_layer.and_then(file_layer);
+
+    let registry =
+        tracing_subscriber::registry().with(fmt_layers.with_filter(options.env_filter()));
+
+    #[cfg(feature = "tokio-console")]
+    let registry = registry.with(console_subscriber::spawn());
+
+    registry.init();
+
+    set_panic_hook();
 
     info!("hello, world!");
+
+    Ok(())
+}
+
+/// log panics through tracing and then abort.
+///
+/// Aborting instead of unwinding means a panicked task can't leave the tunnel half working.
+pub fn set_panic_hook() {
+    std::panic::set_hook(Box::new(|panic_info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+
+        error!(%panic_info, %backtrace, "panic! aborting");
+
+        std::process::abort();
+    }));
 }
@@ -1,15 +1,36 @@
 mod subcommands;
 
 use argh::FromArgs;
-use quic_tunnel::log::configure_logging;
+use quic_tunnel::log::{configure_logging, LogFormat, LogOptions, LogRotation};
+use std::path::PathBuf;
 use subcommands::{
-    QuickCertsSubCommand, ReverseProxyClientSubCommand, ReverseProxyServerSubCommand,
-    UdpClientSubCommand, UdpServerSubCommand,
+    CtlSubCommand, QuickCertsSubCommand, ReverseProxyClientSubCommand,
+    ReverseProxyServerSubCommand, RunSubCommand, UdpClientSubCommand, UdpServerSubCommand,
 };
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Top-level command.
 struct TopLevel {
+    /// log format. "pretty" or "json"
+    #[argh(option, default = "Default::default()")]
+    log_format: LogFormat,
+
+    /// also write logs to files starting with this path
+    #[argh(option)]
+    log_file: Option<PathBuf>,
+
+    /// how often to start a new log file. "minutely", "hourly", "daily", or "never"
+    #[argh(option, default = "Default::default()")]
+    log_rotation: LogRotation,
+
+    /// more logs. use twice for even more
+    #[argh(switch, short = 'v')]
+    verbose: u8,
+
+    /// less logs. use multiple times for even less
+    #[argh(switch, short = 'q')]
+    quiet: u8,
+
     #[argh(subcommand)]
     nested: MySubCommandEnum,
 }
@@ -17,9 +38,11 @@ struct TopLevel {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum MySubCommandEnum {
+    Ctl(CtlSubCommand),
     QuickCerts(QuickCertsSubCommand),
     ReverseProxyClient(ReverseProxyClientSubCommand),
     ReverseProxyServer(ReverseProxyServerSubCommand),
+    Run(RunSubCommand),
     UdpClient(UdpClientSubCommand),
     UdpServer(UdpServerSubCommand),
 }
@@ -28,12 +51,20 @@ enum MySubCommandEnum {
 async fn main() -> anyhow::Result<()> {
     let command: TopLevel = argh::from_env();
 
-    configure_logging();
+    configure_logging(LogOptions {
+        format: command.log_format,
+        verbosity: (i16::from(command.verbose) - i16::from(command.quiet))
+            .clamp(i8::MIN.into(), i8::MAX.into()) as i8,
+        file: command.log_file,
+        rotation: command.log_rotation,
+    })?;
 
     match command.nested {
+        MySubCommandEnum::Ctl(subcommand) => subcommand.main().await?,
         MySubCommandEnum::QuickCerts(subcommand) => subcommand.main()?,
         MySubCommandEnum::ReverseProxyClient(subcommand) => subcommand.main().await?,
         MySubCommandEnum::ReverseProxyServer(subcommand) => subcommand.main().await?,
+        MySubCommandEnum::Run(subcommand) => subcommand.main().await?,
         MySubCommandEnum::UdpClient(subcommand) => subcommand.main().await?,
         MySubCommandEnum::UdpServer(subcommand) => subcommand.main().await?,
     }
@@ -0,0 +1,148 @@
+//! Binding sockets for IPv4, IPv6, or both.
+//!
+//! An unspecified IPv6 address like `[::]:8443` is bound dual-stack with V6ONLY off so that IPv4 peers work too. Some systems don't allow that. Those get an IPv6-only socket and a warning.
+
+use std::io;
+use std::net::{AddrParseError, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tracing::{trace, warn};
+
+/// Any local address with the same family as `x`.
+pub fn matching_bind_address(x: SocketAddr) -> Result<SocketAddr, AddrParseError> {
+    let bind = if x.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+
+    bind.parse()
+}
+
+/// The address to bind when none was given. Dual-stack if this host has IPv6. Otherwise IPv4.
+pub fn default_bind_address() -> SocketAddr {
+    let dual_stack = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+
+    match new_socket(dual_stack, Type::DGRAM, Protocol::UDP) {
+        Ok(_) => dual_stack,
+        Err(err) => {
+            trace!(?err, "no IPv6. binding IPv4 only");
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        }
+    }
+}
+
+fn new_socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+
+    if let SocketAddr::V6(x) = addr {
+        if x.ip().is_unspecified() {
+            if let Err(err) = socket.set_only_v6(false) {
+                warn!(?err, %addr, "dual-stack is not supported. only IPv6 will work");
+            }
+        }
+    }
+
+    Ok(socket)
+}
+
+/// False for remote addresses that a socket bound to `local` can't send to. `dual_stack` is from [`is_dual_stack`].
+pub fn can_reach(local: SocketAddr, dual_stack: bool, remote: SocketAddr) -> bool {
+    match (local, remote) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => false,
+        // only dual-stack sockets can reach IPv4
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => dual_stack,
+        _ => true,
+    }
+}
+
+/// True if an IPv6 socket can reach IPv4 peers. Read from the socket because turning V6ONLY off can fail.
+pub fn is_dual_stack(socket: &std::net::UdpSocket) -> bool {
+    match socket.local_addr() {
+        Ok(SocketAddr::V6(x)) if x.ip().is_unspecified() => {
+            SockRef::from(socket).only_v6().is_ok_and(|x| !x)
+        }
+        _ => false,
+    }
+}
+
+/// Bind a UDP socket. Used for QUIC endpoints and for UDP listeners.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP)?;
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// Bind a UDP socket for tokio.
+pub fn bind_tokio_udp(addr: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = bind_udp(addr)?;
+
+    socket.set_nonblocking(true)?;
+
+    tokio::net::UdpSocket::from_std(socket)
+}
+
+/// Bind and listen on a TCP socket for tokio.
+pub fn bind_tokio_tcp_listener(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP)?;
+
+    // same as tokio's TcpListener::bind
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// A TCP socket with the same family as the address it will connect to.
+pub fn tcp_socket_for(addr: SocketAddr) -> io::Result<tokio::net::TcpSocket> {
+    if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()
+    } else {
+        tokio::net::TcpSocket::new_v6()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_reach_by_family() {
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        let any_v4: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        let any_v6: SocketAddr = "[::]:0".parse().unwrap();
+
+        assert!(can_reach(any_v4, false, v4));
+        assert!(!can_reach(any_v4, false, v6));
+        assert!(can_reach(any_v6, true, v4));
+        assert!(can_reach(any_v6, true, v6));
+        assert!(!can_reach(any_v6, false, v4));
+        assert!(can_reach(any_v6, false, v6));
+    }
+
+    #[test]
+    fn dual_stack_is_read_from_the_socket() {
+        let v4 = bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        assert!(!is_dual_stack(&v4));
+
+        // like a system that doesn't let V6ONLY be turned off
+        let any_v6: SocketAddr = "[::]:0".parse().unwrap();
+
+        let Ok(socket) = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) else {
+            // no IPv6 here
+            return;
+        };
+
+        socket.set_only_v6(true).unwrap();
+        socket.bind(&any_v6.into()).unwrap();
+
+        assert!(!is_dual_stack(&socket.into()));
+
+        if let Ok(x) = bind_udp(any_v6) {
+            assert!(is_dual_stack(&x));
+        }
+    }
+}
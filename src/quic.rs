@@ -1,22 +1,38 @@
-use crate::get_tunnel_timeout;
+use crate::certs::{
+    cert_from_pem, cert_from_pem_async, cert_from_pem_bytes, certs_from_pem, certs_from_pem_async,
+    certs_from_pem_bytes, check_key_permissions, check_key_permissions_async, crls_from_pem,
+    crls_from_pem_async, dns_names, key_from_pem, key_from_pem_async, key_from_pem_bytes,
+    server_name_from_ca_pem, KeyAlgo, SpkiPin, TunnelEnd, DEFAULT_RENEW_WITHIN_DAYS,
+};
+use crate::net::{bind_udp, can_reach, default_bind_address, is_dual_stack};
+use crate::reload::{reload_client_config, reload_server_config, FileWatch};
+use crate::renew::Renewal;
+use crate::resolve::{
+    happy_eyeballs, RemoteAddr, Resolver, SystemResolver, CONNECTION_ATTEMPT_DELAY,
+};
+use crate::timeouts::Timeouts;
 
 use super::tls;
-use quinn::{congestion, ClientConfig, Endpoint, ServerConfig, TransportConfig};
+use anyhow::Context;
+use quinn::{
+    congestion, ClientConfig, Connecting, Connection, Endpoint, EndpointConfig, MtuDiscoveryConfig,
+    ServerConfig, TokioRuntime, TransportConfig, VarInt,
+};
+use rustls::{Certificate, PrivateKey};
+use serde::Deserialize;
 use std::{
-    net::{AddrParseError, SocketAddr},
+    net::SocketAddr,
+    ops::Deref,
     path::PathBuf,
-    sync::Arc,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 use strum::EnumString;
-use tracing::trace;
-
-pub fn matching_bind_address(x: SocketAddr) -> Result<SocketAddr, AddrParseError> {
-    let bind = if x.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
-
-    bind.parse()
-}
+use tokio::time::timeout;
+use tracing::{debug, trace};
 
-#[derive(Debug, Default, EnumString, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq)]
+#[serde(rename_all = "lowercase")]
 #[strum(ascii_case_insensitive)]
 pub enum CongestionMode {
     /// good for high bandwidth networks
@@ -28,9 +44,174 @@ pub enum CongestionMode {
     NewReno,
 }
 
+/// Starting points for [`TransportTuning`]. Anything set in the tuning overrides the preset.
+#[derive(Clone, Copy, Debug, Deserialize, EnumString, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(ascii_case_insensitive)]
+pub enum TransportPreset {
+    /// high loss and high RTT. The wifi on a plane or a train
+    Airplane,
+    /// low RTT and a known MTU
+    Lan,
+    /// very high RTT and lots of bandwidth. Geostationary satellite links
+    Satellite,
+}
+
+impl TransportPreset {
+    pub fn tuning(&self) -> TransportTuning {
+        match self {
+            Self::Airplane => TransportTuning {
+                initial_rtt_ms: Some(600),
+                // jitter is bad. don't call a packet lost just because it's a little late
+                time_threshold: Some(1.5),
+                // losses are usually not congestion. don't collapse the window so quickly
+                persistent_congestion_threshold: Some(6),
+                // captive portals and tunnels break MTU discovery in weird ways
+                mtu_discovery: Some(false),
+                ..Default::default()
+            },
+            Self::Lan => TransportTuning {
+                initial_rtt_ms: Some(10),
+                initial_mtu: Some(1452),
+                ..Default::default()
+            },
+            Self::Satellite => TransportTuning {
+                initial_rtt_ms: Some(650),
+                // big windows to fill a pipe with a big bandwidth-delay product
+                stream_receive_window: Some(16 * 1024 * 1024),
+                receive_window: Some(64 * 1024 * 1024),
+                send_window: Some(64 * 1024 * 1024),
+                datagram_receive_buffer: Some(16 * 1024 * 1024),
+                datagram_send_buffer: Some(16 * 1024 * 1024),
+                persistent_congestion_threshold: Some(4),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Everything else on quinn's TransportConfig. None keeps quinn's default.
+///
+/// TODO: ACK frequency. quinn 0.10 doesn't support the extension
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TransportTuning {
+    /// start from one of the presets
+    pub preset: Option<TransportPreset>,
+    /// RTT to assume before the first measurement
+    pub initial_rtt_ms: Option<u64>,
+    /// bytes we can send before the peer acknowledges them
+    pub send_window: Option<u64>,
+    /// bytes the peer can send on the whole connection before we acknowledge them
+    pub receive_window: Option<u64>,
+    /// bytes the peer can send on one stream before we acknowledge them
+    pub stream_receive_window: Option<u64>,
+    pub datagram_receive_buffer: Option<usize>,
+    pub datagram_send_buffer: Option<usize>,
+    /// packets sent after a packet before it is called lost
+    pub packet_threshold: Option<u32>,
+    /// multiple of the RTT to wait before a packet is called lost
+    pub time_threshold: Option<f32>,
+    /// probe timeouts in a row before the network is called congested
+    pub persistent_congestion_threshold: Option<u32>,
+    /// UDP payload size to start with before MTU discovery
+    pub initial_mtu: Option<u16>,
+    /// UDP payload size that the network is known to support
+    pub min_mtu: Option<u16>,
+    /// quinn turns this on by default
+    pub mtu_discovery: Option<bool>,
+    /// the largest UDP payload size that MTU discovery will try
+    pub max_mtu: Option<u16>,
+}
+
+impl TransportTuning {
+    /// the preset with anything set here on top
+    pub fn resolve(&self) -> Self {
+        let preset = self.preset.map(|x| x.tuning()).unwrap_or_default();
+
+        Self {
+            preset: self.preset,
+            initial_rtt_ms: self.initial_rtt_ms.or(preset.initial_rtt_ms),
+            send_window: self.send_window.or(preset.send_window),
+            receive_window: self.receive_window.or(preset.receive_window),
+            stream_receive_window: self.stream_receive_window.or(preset.stream_receive_window),
+            datagram_receive_buffer: self
+                .datagram_receive_buffer
+                .or(preset.datagram_receive_buffer),
+            datagram_send_buffer: self.datagram_send_buffer.or(preset.datagram_send_buffer),
+            packet_threshold: self.packet_threshold.or(preset.packet_threshold),
+            time_threshold: self.time_threshold.or(preset.time_threshold),
+            persistent_congestion_threshold: self
+                .persistent_congestion_threshold
+                .or(preset.persistent_congestion_threshold),
+            initial_mtu: self.initial_mtu.or(preset.initial_mtu),
+            min_mtu: self.min_mtu.or(preset.min_mtu),
+            mtu_discovery: self.mtu_discovery.or(preset.mtu_discovery),
+            max_mtu: self.max_mtu.or(preset.max_mtu),
+        }
+    }
+
+    fn apply(&self, transport_config: &mut TransportConfig) {
+        let x = self.resolve();
+
+        // windows larger than a VarInt are the same as no limit
+        let var_int = |x: u64| VarInt::from_u64(x).unwrap_or(VarInt::MAX);
+
+        if let Some(initial_rtt_ms) = x.initial_rtt_ms {
+            transport_config.initial_rtt(Duration::from_millis(initial_rtt_ms));
+        }
+        if let Some(send_window) = x.send_window {
+            transport_config.send_window(send_window);
+        }
+        if let Some(receive_window) = x.receive_window {
+            transport_config.receive_window(var_int(receive_window));
+        }
+        if let Some(stream_receive_window) = x.stream_receive_window {
+            transport_config.stream_receive_window(var_int(stream_receive_window));
+        }
+        if let Some(datagram_receive_buffer) = x.datagram_receive_buffer {
+            transport_config.datagram_receive_buffer_size(Some(datagram_receive_buffer));
+        }
+        if let Some(datagram_send_buffer) = x.datagram_send_buffer {
+            transport_config.datagram_send_buffer_size(datagram_send_buffer);
+        }
+        if let Some(packet_threshold) = x.packet_threshold {
+            transport_config.packet_threshold(packet_threshold);
+        }
+        if let Some(time_threshold) = x.time_threshold {
+            transport_config.time_threshold(time_threshold);
+        }
+        if let Some(persistent_congestion_threshold) = x.persistent_congestion_threshold {
+            transport_config.persistent_congestion_threshold(persistent_congestion_threshold);
+        }
+        if let Some(initial_mtu) = x.initial_mtu {
+            transport_config.initial_mtu(initial_mtu);
+        }
+        if let Some(min_mtu) = x.min_mtu {
+            transport_config.min_mtu(min_mtu);
+        }
+
+        match (x.mtu_discovery, x.max_mtu) {
+            (Some(false), _) => {
+                transport_config.mtu_discovery_config(None);
+            }
+            (_, Some(max_mtu)) => {
+                let mut mtu_discovery_config = MtuDiscoveryConfig::default();
+                mtu_discovery_config.upper_bound(max_mtu);
+
+                transport_config.mtu_discovery_config(Some(mtu_discovery_config));
+            }
+            // quinn's default is on
+            _ => {}
+        }
+    }
+}
+
 pub fn build_transport_config(
     keep_alive: bool,
     congestion_mode: CongestionMode,
+    timeouts: &Timeouts,
+    tuning: &TransportTuning,
 ) -> Arc<TransportConfig> {
     let mut transport_config = TransportConfig::default();
 
@@ -39,8 +220,6 @@ pub fn build_transport_config(
     // we want lots of bi streams
     transport_config.max_concurrent_bidi_streams(u16::MAX.into());
 
-    let timeout = get_tunnel_timeout();
-
     match congestion_mode {
         CongestionMode::Brr => {
             transport_config
@@ -58,72 +237,1228 @@ pub fn build_transport_config(
 
     if keep_alive {
         // only one side needs keep alive
-        // TODO: how many keep alives per idle timeout?
-        transport_config.keep_alive_interval(Some(timeout / 3));
+        transport_config.keep_alive_interval(Some(timeouts.keep_alive));
     }
 
-    transport_config.max_idle_timeout(Some(timeout.try_into().unwrap()));
+    transport_config.max_idle_timeout(timeouts.idle_timeout());
 
-    // TODO: MTU discovery
+    tuning.apply(&mut transport_config);
 
     Arc::new(transport_config)
 }
 
-/// TODO: builder pattern
-pub fn build_client_endpoint(
-    ca: PathBuf,
-    cert: PathBuf,
-    key: PathBuf,
-    congestion_mode: CongestionMode,
-    keep_alive: bool,
-) -> anyhow::Result<Endpoint> {
-    let tls_config = tls::build_client_config(ca, cert, key)?;
+/// `alpn_protocols` can be empty. The `run` subcommand uses them to pick a tunnel on a shared endpoint.
+///
+/// If `pins` is not empty, the server is trusted by its public key instead of by the CA.
+pub fn build_client_config(
+    ca: &[Certificate],
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+    pins: &[SpkiPin],
+    transport_config: Arc<TransportConfig>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> anyhow::Result<ClientConfig> {
+    let mut tls_config = tls::build_client_config(ca, cert_chain, key, pins)?;
 
-    let mut client_config = ClientConfig::new(Arc::new(tls_config));
+    tls_config.alpn_protocols = alpn_protocols;
 
-    let transport_config = build_transport_config(keep_alive, congestion_mode);
+    let mut client_config = ClientConfig::new(Arc::new(tls_config));
 
     client_config.transport_config(transport_config);
 
     trace!(?client_config);
 
-    // TODO: do we need to be careful about ipv4 vs ipv6 here?
-    // TODO: io_uring
-    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
-
-    endpoint.set_default_client_config(client_config);
-
-    Ok(endpoint)
+    Ok(client_config)
 }
 
-/// TODO: builder pattern
-pub fn build_server_endpoint(
-    ca: PathBuf,
-    cert: PathBuf,
-    key: PathBuf,
+/// `alpn_protocols` can be empty. The `run` subcommand uses them to pick a tunnel on a shared endpoint.
+///
+/// `crls` are DER certificate revocation lists for client certificates.
+pub fn build_server_config(
+    ca: &[Certificate],
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+    crls: Vec<Vec<u8>>,
     stateless_retry: bool,
-    listen: SocketAddr,
-    congestion_mode: CongestionMode,
-    keep_alive: bool,
-) -> anyhow::Result<Endpoint> {
-    let (tls_config, _root_ca) = tls::build_server_config(ca, cert, key)?;
+    transport_config: Arc<TransportConfig>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> anyhow::Result<ServerConfig> {
+    let (mut tls_config, _root_ca) = tls::build_server_config(ca, cert_chain, key, crls)?;
 
-    let mut server_config = ServerConfig::with_crypto(Arc::new(tls_config));
+    tls_config.alpn_protocols = alpn_protocols;
 
-    let transport_config = build_transport_config(keep_alive, congestion_mode);
+    let mut server_config = ServerConfig::with_crypto(Arc::new(tls_config));
 
     server_config.transport_config(transport_config);
 
     // Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
     server_config.use_retry(stateless_retry);
 
-    // TODO: no uni streams
-    // TODO: lots more bi streams
-
     trace!(?server_config);
 
+    Ok(server_config)
+}
+
+/// Connect to a server. 0-RTT is used if we have talked to this server before.
+///
+/// If `client_config` is None, the endpoint's default client config is used.
+/// Returns the connection and whether 0-RTT was used.
+pub async fn connect(
+    endpoint: &Endpoint,
+    client_config: Option<ClientConfig>,
+    addr: SocketAddr,
+    server_name: &str,
+    handshake_timeout: Duration,
+) -> anyhow::Result<(Connection, bool)> {
+    let connecting = start_connecting(endpoint, client_config, addr, server_name)?;
+
+    let x = match connecting.into_0rtt() {
+        Ok((conn, _)) => {
+            trace!("0-rtt accepted");
+            (conn, true)
+        }
+        Err(connecting) => (timeout(handshake_timeout, connecting).await??, false),
+    };
+
+    Ok(x)
+}
+
+fn start_connecting(
+    endpoint: &Endpoint,
+    client_config: Option<ClientConfig>,
+    addr: SocketAddr,
+    server_name: &str,
+) -> anyhow::Result<Connecting> {
+    let x = match client_config {
+        Some(client_config) => endpoint.connect_with(client_config, addr, server_name)?,
+        None => endpoint.connect(addr, server_name)?,
+    };
+
+    Ok(x)
+}
+
+/// Same as [`connect`] but a 0-RTT connection is only returned once its handshake finishes.
+///
+/// For racing addresses. A 0-RTT connection is usable right away, but that doesn't mean the address works.
+async fn connect_handshaked(
+    endpoint: &Endpoint,
+    client_config: Option<ClientConfig>,
+    addr: SocketAddr,
+    server_name: &str,
+    handshake_timeout: Duration,
+) -> anyhow::Result<(Connection, bool)> {
+    let connecting = start_connecting(endpoint, client_config, addr, server_name)?;
+
+    let x = match connecting.into_0rtt() {
+        Ok((conn, accepted)) => {
+            // false if the server refused the early data or the handshake failed
+            let zero_rtt = timeout(handshake_timeout, accepted).await?;
+
+            if let Some(err) = conn.close_reason() {
+                return Err(err.into());
+            }
+
+            (conn, zero_rtt)
+        }
+        Err(connecting) => (timeout(handshake_timeout, connecting).await??, false),
+    };
+
+    Ok(x)
+}
+
+/// Resolve `remote` and connect to its addresses Happy Eyeballs style. Names are resolved every time so that reconnects follow DNS changes.
+///
+/// With more than one address, an attempt wins when its handshake finishes, even with 0-RTT. Otherwise the first 0-RTT attempt would always win.
+///
+/// `server_name` defaults to the hostname, or to the IP address if `remote` is one. Addresses this endpoint can't reach, like IPv6 from an IPv4 socket, are skipped.
+/// `dual_stack` is from [`bind_endpoint`].
+pub async fn connect_remote(
+    endpoint: &Endpoint,
+    dual_stack: bool,
+    client_config: Option<ClientConfig>,
+    remote: &RemoteAddr,
+    server_name: Option<&str>,
+    resolver: &dyn Resolver,
+    handshake_timeout: Duration,
+) -> anyhow::Result<(Connection, bool)> {
+    let server_name = match server_name {
+        Some(x) => x.to_string(),
+        None => remote.server_name(),
+    };
+
+    let local = endpoint.local_addr()?;
+
+    let addrs: Vec<_> = remote
+        .resolve(resolver)
+        .await?
+        .into_iter()
+        .filter(|x| can_reach(local, dual_stack, *x))
+        .collect();
+
+    if addrs.is_empty() {
+        anyhow::bail!("{} has no addresses that {} can reach", remote, local);
+    }
+
+    // nothing to race. 0-RTT can start right away
+    if let [addr] = addrs[..] {
+        return connect(
+            endpoint,
+            client_config,
+            addr,
+            &server_name,
+            handshake_timeout,
+        )
+        .await;
+    }
+
+    happy_eyeballs(addrs, CONNECTION_ATTEMPT_DELAY, |addr| {
+        connect_handshaked(
+            endpoint,
+            client_config.clone(),
+            addr,
+            &server_name,
+            handshake_timeout,
+        )
+    })
+    .await
+}
+
+/// Finish the handshake for an incoming connection. The server side can always send 0.5-RTT data.
+///
+/// Returns the connection and whether 0-RTT was used.
+pub async fn accept(
+    connecting: Connecting,
+    handshake_timeout: Duration,
+) -> anyhow::Result<(Connection, bool)> {
+    // TODO: are there other things I need to do to set up 0-rtt?
+    let x = match connecting.into_0rtt() {
+        Ok((conn, _)) => {
+            trace!("0-rtt accepted");
+            (conn, true)
+        }
+        Err(connecting) => (timeout(handshake_timeout, connecting).await??, false),
+    };
+
+    Ok(x)
+}
+
+/// Bind a QUIC endpoint. `[::]` is dual-stack so IPv4 peers work too, if the system allows it.
+///
+/// If `server_config` is None, the endpoint can only make connections.
+/// Also returns whether the endpoint can reach IPv4 peers from an IPv6 address, for [`connect_remote`].
+pub fn bind_endpoint(
+    bind: SocketAddr,
+    server_config: Option<ServerConfig>,
+) -> anyhow::Result<(Endpoint, bool)> {
+    let socket = bind_udp(bind).with_context(|| format!("failed binding {}", bind))?;
+
+    let dual_stack = is_dual_stack(&socket);
+
     // TODO: io_uring
-    let endpoint = Endpoint::server(server_config, listen)?;
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(TokioRuntime),
+    )?;
+
+    Ok((endpoint, dual_stack))
+}
+
+/// Where to get a certificate.
+#[derive(Clone, Debug)]
+pub enum CertSource {
+    /// a PEM file. read again every time a config is built so that renewed certificates are used
+    Path(PathBuf),
+    /// PEM that is already in memory
+    Pem(Vec<u8>),
+    Rustls(Certificate),
+}
+
+impl CertSource {
+    pub fn load(&self) -> anyhow::Result<Certificate> {
+        match self {
+            Self::Path(x) => cert_from_pem(x.clone()),
+            Self::Pem(x) => cert_from_pem_bytes(x),
+            Self::Rustls(x) => Ok(x.clone()),
+        }
+    }
+
+    /// every cert instead of only the first. For CA bundles
+    pub fn load_all(&self) -> anyhow::Result<Vec<Certificate>> {
+        match self {
+            Self::Path(x) => certs_from_pem(x.clone()),
+            Self::Pem(x) => certs_from_pem_bytes(x),
+            Self::Rustls(x) => Ok(vec![x.clone()]),
+        }
+    }
+
+    /// Same as `load_all` with tokio's file IO.
+    pub async fn load_all_async(&self) -> anyhow::Result<Vec<Certificate>> {
+        match self {
+            Self::Path(x) => certs_from_pem_async(x.clone()).await,
+            Self::Pem(x) => certs_from_pem_bytes(x),
+            Self::Rustls(x) => Ok(vec![x.clone()]),
+        }
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        match self {
+            Self::Path(x) => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    /// None if this was never PEM
+    fn pem(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Path(x) => std::fs::read(x).ok(),
+            Self::Pem(x) => Some(x.clone()),
+            Self::Rustls(_) => None,
+        }
+    }
+
+    /// Same as `pem` with tokio's file IO.
+    async fn pem_async(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Path(x) => tokio::fs::read(x).await.ok(),
+            Self::Pem(x) => Some(x.clone()),
+            Self::Rustls(_) => None,
+        }
+    }
+}
+
+impl From<PathBuf> for CertSource {
+    fn from(x: PathBuf) -> Self {
+        Self::Path(x)
+    }
+}
+
+impl From<Certificate> for CertSource {
+    fn from(x: Certificate) -> Self {
+        Self::Rustls(x)
+    }
+}
+
+/// Where to get a private key.
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    /// a PEM file. read again every time a config is built so that renewed keys are used
+    Path(PathBuf),
+    /// PEM that is already in memory
+    Pem(Vec<u8>),
+    Rustls(PrivateKey),
+}
+
+impl KeySource {
+    pub fn load(&self) -> anyhow::Result<PrivateKey> {
+        match self {
+            Self::Path(x) => key_from_pem(x.clone()),
+            Self::Pem(x) => key_from_pem_bytes(x),
+            Self::Rustls(x) => Ok(x.clone()),
+        }
+    }
+
+    /// Same as `load` with tokio's file IO.
+    pub async fn load_async(&self) -> anyhow::Result<PrivateKey> {
+        match self {
+            Self::Path(x) => key_from_pem_async(x.clone()).await,
+            Self::Pem(x) => key_from_pem_bytes(x),
+            Self::Rustls(x) => Ok(x.clone()),
+        }
+    }
+}
+
+impl From<PathBuf> for KeySource {
+    fn from(x: PathBuf) -> Self {
+        Self::Path(x)
+    }
+}
+
+impl From<PrivateKey> for KeySource {
+    fn from(x: PrivateKey) -> Self {
+        Self::Rustls(x)
+    }
+}
+
+/// Clients with pinned keys that don't have a name to use send this one. The verifier ignores it.
+const PINNED_SERVER_NAME: &str = "pinned.quic-tunnel.invalid";
+
+/// Build a QUIC endpoint for one end of a tunnel.
+///
+/// ```no_run
+/// # async fn f() -> anyhow::Result<()> {
+/// use quic_tunnel::quic::EndpointBuilder;
+///
+/// let server = EndpointBuilder::new()
+///     .cert_name("data/first")
+///     .bind("0.0.0.0:8443".parse()?)
+///     .build_server()?;
+///
+/// let client = EndpointBuilder::new().cert_name("data/first").build_client()?;
+///
+/// let (conn, _zero_rtt) = client.connect("127.0.0.1:8443".parse()?, "first_server").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct EndpointBuilder {
+    cert_name: Option<String>,
+    ca: Vec<CertSource>,
+    client_ca: Vec<CertSource>,
+    server_ca: Vec<CertSource>,
+    crls: Vec<PathBuf>,
+    cert: Option<CertSource>,
+    key: Option<KeySource>,
+    transport_config: Option<Arc<TransportConfig>>,
+    congestion_mode: CongestionMode,
+    /// None means only the client sends keep alives
+    keep_alive: Option<bool>,
+    timeouts: Timeouts,
+    tuning: TransportTuning,
+    bind: Option<SocketAddr>,
+    stateless_retry: bool,
+    alpn_protocols: Vec<Vec<u8>>,
+    resolver: Arc<dyn Resolver>,
+    server_name: Option<String>,
+    pins: Vec<SpkiPin>,
+    ca_key: Option<PathBuf>,
+    ca_passphrase_file: Option<PathBuf>,
+    renew_within_days: u32,
+    insecure_key_permissions: bool,
+    key_algo: Option<KeyAlgo>,
+}
+
+impl Default for EndpointBuilder {
+    fn default() -> Self {
+        Self {
+            cert_name: None,
+            ca: vec![],
+            client_ca: vec![],
+            server_ca: vec![],
+            crls: vec![],
+            cert: None,
+            key: None,
+            transport_config: None,
+            congestion_mode: Default::default(),
+            keep_alive: None,
+            timeouts: Default::default(),
+            tuning: Default::default(),
+            bind: None,
+            stateless_retry: true,
+            alpn_protocols: vec![],
+            resolver: Arc::new(SystemResolver),
+            server_name: None,
+            pins: vec![],
+            ca_key: None,
+            ca_passphrase_file: None,
+            renew_within_days: DEFAULT_RENEW_WITHIN_DAYS,
+            insecure_key_permissions: false,
+            key_algo: None,
+        }
+    }
+}
+
+impl EndpointBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load `{cert_name}_ca.pem`, `{cert_name}_{client,server}.pem`, and `{cert_name}_{client,server}.key.pem`.
+    ///
+    /// Only used for the certificates that aren't set with `ca`, `cert`, or `key`.
+    pub fn cert_name(mut self, cert_name: impl Into<String>) -> Self {
+        self.cert_name = Some(cert_name.into());
+        self
+    }
+
+    /// Same as `cert_name` but None keeps the default.
+    pub fn cert_name_opt(mut self, cert_name: Option<impl Into<String>>) -> Self {
+        self.cert_name = cert_name.map(Into::into).or(self.cert_name);
+        self
+    }
+
+    /// A CA that signed the other end's certificate. Can be called more than once. Every certificate in a PEM bundle is trusted.
+    ///
+    /// Used in both directions unless `client_ca` or `server_ca` is set.
+    pub fn ca(mut self, ca: impl Into<CertSource>) -> Self {
+        self.ca.push(ca.into());
+        self
+    }
+
+    /// Same as calling `ca` for each one.
+    pub fn cas<T: Into<CertSource>>(mut self, cas: impl IntoIterator<Item = T>) -> Self {
+        self.ca.extend(cas.into_iter().map(Into::into));
+        self
+    }
+
+    /// A CA that servers trust for client certificates. Replaces `ca` for that direction. Can be called more than once.
+    pub fn client_ca(mut self, ca: impl Into<CertSource>) -> Self {
+        self.client_ca.push(ca.into());
+        self
+    }
+
+    /// A CA that clients trust for server certificates. Replaces `ca` for that direction. Can be called more than once.
+    pub fn server_ca(mut self, ca: impl Into<CertSource>) -> Self {
+        self.server_ca.push(ca.into());
+        self
+    }
+
+    /// Same as calling `client_ca` for each one.
+    pub fn client_cas<T: Into<CertSource>>(mut self, cas: impl IntoIterator<Item = T>) -> Self {
+        self.client_ca.extend(cas.into_iter().map(Into::into));
+        self
+    }
+
+    /// Same as calling `server_ca` for each one.
+    pub fn server_cas<T: Into<CertSource>>(mut self, cas: impl IntoIterator<Item = T>) -> Self {
+        self.server_ca.extend(cas.into_iter().map(Into::into));
+        self
+    }
+
+    /// A PEM file of revoked client certificates. Can be called more than once. Servers only.
+    ///
+    /// Defaults to `{cert_name}_crl.pem` if it exists. `quick_certs revoke` writes that file.
+    pub fn crl(mut self, crl: impl Into<PathBuf>) -> Self {
+        self.crls.push(crl.into());
+        self
+    }
+
+    /// Same as calling `crl` for each one.
+    pub fn crls<T: Into<PathBuf>>(mut self, crls: impl IntoIterator<Item = T>) -> Self {
+        self.crls.extend(crls.into_iter().map(Into::into));
+        self
+    }
+
+    /// This end's certificate. Any intermediate CAs after it in the PEM are sent too.
+    pub fn cert(mut self, cert: impl Into<CertSource>) -> Self {
+        self.cert = Some(cert.into());
+        self
+    }
+
+    /// Same as `cert` but None keeps the cert_name fallback.
+    pub fn cert_opt<T: Into<CertSource>>(mut self, cert: Option<T>) -> Self {
+        if let Some(x) = cert {
+            self.cert = Some(x.into());
+        }
+        self
+    }
+
+    /// the private key for this end's certificate
+    pub fn key(mut self, key: impl Into<KeySource>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Same as `key` but None keeps the cert_name fallback.
+    pub fn key_opt<T: Into<KeySource>>(mut self, key: Option<T>) -> Self {
+        if let Some(x) = key {
+            self.key = Some(x.into());
+        }
+        self
+    }
+
+    /// The CA's private key, for renewing this end's certificate. Defaults to `{cert_name}_ca.key.pem`. Renewal is skipped if it doesn't exist.
+    pub fn ca_key(mut self, ca_key: impl Into<PathBuf>) -> Self {
+        self.ca_key = Some(ca_key.into());
+        self
+    }
+
+    /// Same as `ca_key` but None keeps the cert_name fallback.
+    pub fn ca_key_opt<T: Into<PathBuf>>(mut self, ca_key: Option<T>) -> Self {
+        if let Some(x) = ca_key {
+            self.ca_key = Some(x.into());
+        }
+        self
+    }
+
+    /// A file with the passphrase for an encrypted CA key. See `quick_certs new --ca-passphrase-file`.
+    pub fn ca_passphrase_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_passphrase_file = Some(path.into());
+        self
+    }
+
+    /// Same as `ca_passphrase_file` but None keeps the default.
+    pub fn ca_passphrase_file_opt<T: Into<PathBuf>>(mut self, path: Option<T>) -> Self {
+        if let Some(x) = path {
+            self.ca_passphrase_file = Some(x.into());
+        }
+        self
+    }
+
+    /// Load key files that other users can read. Off by default, so a key with the wrong mode is an error.
+    pub fn insecure_key_permissions(mut self, insecure_key_permissions: bool) -> Self {
+        self.insecure_key_permissions = insecure_key_permissions;
+        self
+    }
+
+    /// Refuse to build a config unless this end's key is this kind. See [`tls::check_key`].
+    pub fn key_algo(mut self, key_algo: KeyAlgo) -> Self {
+        self.key_algo = Some(key_algo);
+        self
+    }
+
+    /// Same as `key_algo` but None allows any kind of key.
+    pub fn key_algo_opt(mut self, key_algo: Option<KeyAlgo>) -> Self {
+        self.key_algo = key_algo.or(self.key_algo);
+        self
+    }
+
+    /// Renew this end's certificate when it has less than this many days left. See [`crate::renew`].
+    pub fn renew_within_days(mut self, days: u32) -> Self {
+        self.renew_within_days = days;
+        self
+    }
+
+    /// Use this instead of building one from the congestion mode, keep alive, timeouts, and tuning.
+    pub fn transport_config(mut self, transport_config: Arc<TransportConfig>) -> Self {
+        self.transport_config = Some(transport_config);
+        self
+    }
+
+    pub fn congestion_mode(mut self, congestion_mode: CongestionMode) -> Self {
+        self.congestion_mode = congestion_mode;
+        self
+    }
+
+    /// Only one side needs keep alive. Defaults to on for clients and off for servers.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
 
-    Ok(endpoint)
+    /// The handshake timeout is used even if a transport config is set.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn tuning(mut self, tuning: TransportTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Required for servers. Clients default to `[::]:0`, which is dual-stack, or `0.0.0.0:0` if this host has no IPv6.
+    pub fn bind(mut self, bind: SocketAddr) -> Self {
+        self.bind = Some(bind);
+        self
+    }
+
+    /// Same as `bind` but None keeps the default.
+    pub fn bind_opt(mut self, bind: Option<SocketAddr>) -> Self {
+        self.bind = bind.or(self.bind);
+        self
+    }
+
+    /// Introduces an additional round-trip to the handshake to make denial of service attacks more difficult. On by default.
+    pub fn stateless_retry(mut self, stateless_retry: bool) -> Self {
+        self.stateless_retry = stateless_retry;
+        self
+    }
+
+    /// Can be empty. The `run` subcommand uses them to pick a tunnel on a shared endpoint.
+    pub fn alpn_protocols(mut self, alpn_protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = alpn_protocols;
+        self
+    }
+
+    /// Clients use this for hostnames. Defaults to the system resolver.
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// The name on the server's certificate for clients that weren't given one. See [`EndpointBuilder::expected_server_name`].
+    pub fn server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    /// Trust servers with these public keys instead of checking the CA and the name. See [`SpkiPin`].
+    pub fn pin_sha256(mut self, pins: Vec<SpkiPin>) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// The name that clients expect on the server's certificate when a tunnel doesn't set one. The first of:
+    ///
+    ///  - the name given to [`EndpointBuilder::server_name`]
+    ///  - a `server_name:` line in the CA file
+    ///  - the first DNS name on `{cert_name}_server.pem`, if this machine has it
+    ///  - a placeholder if keys are pinned. the name isn't checked then
+    ///
+    /// After that, clients use the remote's hostname.
+    pub fn expected_server_name(&self) -> Option<String> {
+        if let Some(x) = self.server_name.as_ref() {
+            return Some(x.clone());
+        }
+
+        let trusted = self.trusted(TunnelEnd::Client);
+
+        let ca_pems: Vec<_> = if trusted.is_empty() {
+            self.cert_name
+                .iter()
+                .filter_map(|x| std::fs::read(format!("{}_ca.pem", x)).ok())
+                .collect()
+        } else {
+            trusted.iter().filter_map(CertSource::pem).collect()
+        };
+
+        if let Some(x) = ca_pems.iter().find_map(|x| server_name_from_ca_pem(x)) {
+            debug!(server_name = x, "server name from the CA file");
+            return Some(x);
+        }
+
+        if let Some(x) = self.server_name_from_server_cert() {
+            debug!(server_name = x, "server name from the server certificate");
+            return Some(x);
+        }
+
+        (!self.pins.is_empty()).then(|| PINNED_SERVER_NAME.to_string())
+    }
+
+    /// Same as `expected_server_name` with tokio's file IO. For building configs while tunnels are running.
+    pub async fn expected_server_name_async(&self) -> Option<String> {
+        if let Some(x) = self.server_name.as_ref() {
+            return Some(x.clone());
+        }
+
+        let trusted = self.trusted(TunnelEnd::Client);
+
+        let mut ca_pems = vec![];
+
+        if trusted.is_empty() {
+            for x in self.cert_name.iter() {
+                ca_pems.extend(tokio::fs::read(format!("{}_ca.pem", x)).await.ok());
+            }
+        } else {
+            for x in trusted {
+                ca_pems.extend(x.pem_async().await);
+            }
+        }
+
+        if let Some(x) = ca_pems.iter().find_map(|x| server_name_from_ca_pem(x)) {
+            debug!(server_name = x, "server name from the CA file");
+            return Some(x);
+        }
+
+        if let Some(x) = self.server_name_from_server_cert_async().await {
+            debug!(server_name = x, "server name from the server certificate");
+            return Some(x);
+        }
+
+        (!self.pins.is_empty()).then(|| PINNED_SERVER_NAME.to_string())
+    }
+
+    fn server_name_from_server_cert(&self) -> Option<String> {
+        let server_cert = PathBuf::from(format!("{}_server.pem", self.cert_name.as_ref()?));
+
+        if !server_cert.exists() {
+            return None;
+        }
+
+        match cert_from_pem(server_cert).and_then(|x| dns_names(&x)) {
+            Ok(x) => x.into_iter().next(),
+            Err(err) => {
+                debug!(?err, "no server name from the server certificate");
+                None
+            }
+        }
+    }
+
+    async fn server_name_from_server_cert_async(&self) -> Option<String> {
+        let server_cert = PathBuf::from(format!("{}_server.pem", self.cert_name.as_ref()?));
+
+        if !tokio::fs::try_exists(&server_cert).await.unwrap_or(false) {
+            return None;
+        }
+
+        match cert_from_pem_async(server_cert)
+            .await
+            .and_then(|x| dns_names(&x))
+        {
+            Ok(x) => x.into_iter().next(),
+            Err(err) => {
+                debug!(?err, "no server name from the server certificate");
+                None
+            }
+        }
+    }
+
+    fn build_transport_config(&self, end: TunnelEnd) -> Arc<TransportConfig> {
+        if let Some(x) = self.transport_config.as_ref() {
+            return x.clone();
+        }
+
+        let keep_alive = self.keep_alive.unwrap_or(matches!(end, TunnelEnd::Client));
+
+        build_transport_config(
+            keep_alive,
+            self.congestion_mode,
+            &self.timeouts,
+            &self.tuning,
+        )
+    }
+
+    /// the CAs that `end` trusts for the other end's certificate
+    fn trusted(&self, end: TunnelEnd) -> &[CertSource] {
+        let x = match end {
+            TunnelEnd::Client => &self.server_ca,
+            TunnelEnd::Server => &self.client_ca,
+        };
+
+        if x.is_empty() {
+            &self.ca
+        } else {
+            x
+        }
+    }
+
+    /// where `end`'s trusted CAs, certificate chain, and key come from. Falls back to the cert_name files
+    fn cert_sources(
+        &self,
+        end: TunnelEnd,
+    ) -> anyhow::Result<(Vec<CertSource>, CertSource, KeySource)> {
+        let end_name = match end {
+            TunnelEnd::Client => "client",
+            TunnelEnd::Server => "server",
+        };
+
+        let fallback = |suffix: &str| -> anyhow::Result<PathBuf> {
+            let cert_name = self.cert_name.as_ref().with_context(|| {
+                format!(
+                    "no {} {} and no cert_name to find one with",
+                    end_name, suffix
+                )
+            })?;
+
+            Ok(PathBuf::from(format!("{}_{}", cert_name, suffix)))
+        };
+
+        let trusted = self.trusted(end);
+
+        let ca = if trusted.is_empty() {
+            vec![CertSource::Path(fallback("ca.pem")?)]
+        } else {
+            trusted.to_vec()
+        };
+
+        let cert = match self.cert.as_ref() {
+            Some(x) => x.clone(),
+            None => CertSource::Path(fallback(&format!("{}.pem", end_name))?),
+        };
+
+        let key = match self.key.as_ref() {
+            Some(x) => x.clone(),
+            None => KeySource::Path(fallback(&format!("{}.key.pem", end_name))?),
+        };
+
+        Ok((ca, cert, key))
+    }
+
+    /// load the trusted CAs, this end's certificate chain, and its key
+    fn load_certs(
+        &self,
+        end: TunnelEnd,
+    ) -> anyhow::Result<(Vec<Certificate>, Vec<Certificate>, PrivateKey)> {
+        let (trusted, cert, key) = self.cert_sources(end)?;
+
+        let mut ca = vec![];
+        for x in trusted {
+            ca.extend(x.load_all()?);
+        }
+
+        let cert_chain = cert.load_all()?;
+
+        if let (KeySource::Path(x), false) = (&key, self.insecure_key_permissions) {
+            check_key_permissions(x)?;
+        }
+
+        let key = key.load()?;
+
+        tls::check_key(&key, self.key_algo)?;
+
+        Ok((ca, cert_chain, key))
+    }
+
+    /// Same as `load_certs` with tokio's file IO.
+    async fn load_certs_async(
+        &self,
+        end: TunnelEnd,
+    ) -> anyhow::Result<(Vec<Certificate>, Vec<Certificate>, PrivateKey)> {
+        let (trusted, cert, key) = self.cert_sources(end)?;
+
+        let mut ca = vec![];
+        for x in trusted {
+            ca.extend(x.load_all_async().await?);
+        }
+
+        let cert_chain = cert.load_all_async().await?;
+
+        if let (KeySource::Path(x), false) = (&key, self.insecure_key_permissions) {
+            check_key_permissions_async(x).await?;
+        }
+
+        let key = key.load_async().await?;
+
+        tls::check_key(&key, self.key_algo)?;
+
+        Ok((ca, cert_chain, key))
+    }
+
+    /// the CRL files, or `{cert_name}_crl.pem`. The fallback may not exist
+    fn crl_paths(&self) -> Vec<PathBuf> {
+        if !self.crls.is_empty() {
+            return self.crls.clone();
+        }
+
+        self.cert_name
+            .iter()
+            .map(|x| PathBuf::from(format!("{}_crl.pem", x)))
+            .collect()
+    }
+
+    fn load_crls(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut crls = vec![];
+
+        for x in self.crl_paths() {
+            // only the fallback is allowed to be missing
+            if self.crls.is_empty() && !x.exists() {
+                continue;
+            }
+
+            crls.extend(crls_from_pem(&x)?);
+        }
+
+        Ok(crls)
+    }
+
+    /// Same as `load_crls` with tokio's file IO.
+    async fn load_crls_async(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut crls = vec![];
+
+        for x in self.crl_paths() {
+            if self.crls.is_empty() && !tokio::fs::try_exists(&x).await? {
+                continue;
+            }
+
+            crls.extend(crls_from_pem_async(&x).await?);
+        }
+
+        Ok(crls)
+    }
+
+    /// Every file that `end` reads its certificates, keys, and CRLs from. For noticing when they change.
+    pub fn watched_paths(&self, end: TunnelEnd) -> Vec<PathBuf> {
+        let end_name = match end {
+            TunnelEnd::Client => "client",
+            TunnelEnd::Server => "server",
+        };
+
+        let fallback = |suffix: &str| {
+            self.cert_name
+                .as_ref()
+                .map(|x| PathBuf::from(format!("{}_{}", x, suffix)))
+        };
+
+        let mut paths = vec![];
+
+        let trusted = self.trusted(end);
+
+        if trusted.is_empty() {
+            paths.extend(fallback("ca.pem"));
+        } else {
+            paths.extend(trusted.iter().filter_map(CertSource::path));
+        }
+
+        match self.cert.as_ref() {
+            Some(x) => paths.extend(x.path()),
+            None => paths.extend(fallback(&format!("{}.pem", end_name))),
+        }
+
+        match self.key.as_ref() {
+            Some(KeySource::Path(x)) => paths.push(x.clone()),
+            Some(_) => {}
+            None => paths.extend(fallback(&format!("{}.key.pem", end_name))),
+        }
+
+        if matches!(end, TunnelEnd::Server) {
+            paths.extend(self.crl_paths());
+        }
+
+        paths
+    }
+
+    /// Renews `end`'s certificate from the CA that signed it. Disabled if the certificate or key aren't files.
+    ///
+    /// The CA is the first file the other end trusts, or `{cert_name}_ca.pem`.
+    pub fn renewal(&self, end: TunnelEnd) -> Renewal {
+        let end_name = match end {
+            TunnelEnd::Client => "client",
+            TunnelEnd::Server => "server",
+        };
+
+        let fallback = |suffix: &str| {
+            self.cert_name
+                .as_ref()
+                .map(|x| PathBuf::from(format!("{}_{}", x, suffix)))
+        };
+
+        let cert = match self.cert.as_ref() {
+            Some(x) => x.path(),
+            None => fallback(&format!("{}.pem", end_name)),
+        };
+
+        let key = match self.key.as_ref() {
+            Some(KeySource::Path(x)) => Some(x.clone()),
+            Some(_) => None,
+            None => fallback(&format!("{}.key.pem", end_name)),
+        };
+
+        // the CA that signed this end's cert is the one the other end trusts
+        let issuer = match end {
+            TunnelEnd::Client => self.trusted(TunnelEnd::Server),
+            TunnelEnd::Server => self.trusted(TunnelEnd::Client),
+        };
+
+        let ca_cert = match issuer.first() {
+            Some(x) => x.path(),
+            None => fallback("ca.pem"),
+        };
+
+        let ca_key = self.ca_key.clone().or_else(|| fallback("ca.key.pem"));
+
+        match (cert, key, ca_cert, ca_key) {
+            (Some(cert), Some(key), Some(ca_cert), Some(ca_key)) => {
+                Renewal::new(cert, key, ca_cert, ca_key)
+                    .with_within_days(self.renew_within_days)
+                    .with_ca_passphrase_file(self.ca_passphrase_file.clone())
+                    .with_insecure_key_permissions(self.insecure_key_permissions)
+            }
+            _ => Renewal::disabled(),
+        }
+    }
+
+    /// Build just the client config. Useful for `connect_with` on an endpoint that is shared.
+    pub fn client_config(&self) -> anyhow::Result<ClientConfig> {
+        let (ca, cert_chain, key) = self.load_certs(TunnelEnd::Client)?;
+
+        build_client_config(
+            &ca,
+            cert_chain,
+            key,
+            &self.pins,
+            self.build_transport_config(TunnelEnd::Client),
+            self.alpn_protocols.clone(),
+        )
+    }
+
+    /// Same as `client_config` with tokio's file IO. For building configs while tunnels are running.
+    pub async fn client_config_async(&self) -> anyhow::Result<ClientConfig> {
+        let (ca, cert_chain, key) = self.load_certs_async(TunnelEnd::Client).await?;
+
+        build_client_config(
+            &ca,
+            cert_chain,
+            key,
+            &self.pins,
+            self.build_transport_config(TunnelEnd::Client),
+            self.alpn_protocols.clone(),
+        )
+    }
+
+    /// Build just the server config. Path sources are read again every time.
+    pub fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let (ca, cert_chain, key) = self.load_certs(TunnelEnd::Server)?;
+
+        build_server_config(
+            &ca,
+            cert_chain,
+            key,
+            self.load_crls()?,
+            self.stateless_retry,
+            self.build_transport_config(TunnelEnd::Server),
+            self.alpn_protocols.clone(),
+        )
+    }
+
+    /// Same as `server_config` with tokio's file IO. For reloading while tunnels are running.
+    pub async fn server_config_async(&self) -> anyhow::Result<ServerConfig> {
+        let (ca, cert_chain, key) = self.load_certs_async(TunnelEnd::Server).await?;
+
+        build_server_config(
+            &ca,
+            cert_chain,
+            key,
+            self.load_crls_async().await?,
+            self.stateless_retry,
+            self.build_transport_config(TunnelEnd::Server),
+            self.alpn_protocols.clone(),
+        )
+    }
+
+    pub fn build_client(self) -> anyhow::Result<ClientEndpoint> {
+        let client_config = self.client_config()?;
+
+        let bind = self.bind.unwrap_or_else(default_bind_address);
+
+        let (mut endpoint, dual_stack) = bind_endpoint(bind, None)?;
+
+        endpoint.set_default_client_config(client_config.clone());
+
+        Ok(ClientEndpoint {
+            endpoint,
+            dual_stack,
+            client_config: Arc::new(RwLock::new(client_config)),
+            server_name: self.expected_server_name(),
+            builder: self,
+        })
+    }
+
+    pub fn build_server(self) -> anyhow::Result<ServerEndpoint> {
+        let bind = self.bind.context("servers need a bind address")?;
+
+        let (endpoint, _) = bind_endpoint(bind, Some(self.server_config()?))?;
+
+        Ok(ServerEndpoint {
+            endpoint,
+            builder: self,
+        })
+    }
+}
+
+/// An endpoint that connects to servers. Derefs to the quinn [`Endpoint`].
+#[derive(Clone, Debug)]
+pub struct ClientEndpoint {
+    endpoint: Endpoint,
+    /// from [`bind_endpoint`]
+    dual_stack: bool,
+    /// shared by clones so that a reload reaches tunnels that are already running
+    client_config: Arc<RwLock<ClientConfig>>,
+    /// from [`EndpointBuilder::expected_server_name`]
+    server_name: Option<String>,
+    /// kept so that the client config can be built again on reload
+    builder: EndpointBuilder,
+}
+
+impl ClientEndpoint {
+    /// Connect with the current client config. Returns the connection and whether 0-RTT was used.
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+    ) -> anyhow::Result<(Connection, bool)> {
+        connect(
+            &self.endpoint,
+            Some(self.client_config()),
+            addr,
+            server_name,
+            self.builder.timeouts.handshake,
+        )
+        .await
+    }
+
+    /// Connect to a hostname or an address. See [`connect_remote`].
+    ///
+    /// `server_name` defaults to [`EndpointBuilder::expected_server_name`] and then to the hostname or IP address.
+    pub async fn connect_remote(
+        &self,
+        remote: &RemoteAddr,
+        server_name: Option<&str>,
+    ) -> anyhow::Result<(Connection, bool)> {
+        connect_remote(
+            &self.endpoint,
+            self.dual_stack,
+            Some(self.client_config()),
+            remote,
+            server_name.or(self.server_name.as_deref()),
+            self.builder.resolver.as_ref(),
+            self.builder.timeouts.handshake,
+        )
+        .await
+    }
+
+    /// the config that new connections use
+    pub fn client_config(&self) -> ClientConfig {
+        self.client_config.read().unwrap().clone()
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.builder.timeouts
+    }
+
+    /// Renews the client certificate. Call [`ClientEndpoint::reload`] when it is renewed.
+    pub fn renewal(&self) -> Renewal {
+        self.builder.renewal(TunnelEnd::Client)
+    }
+
+    /// Read the certificates again and use them for new connections. Open connections are not dropped.
+    ///
+    /// If anything fails, the old config is kept.
+    pub async fn reload(&self) {
+        reload_client_config(
+            &self.client_config,
+            self.builder.client_config_async().await,
+        );
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Deref for ClientEndpoint {
+    type Target = Endpoint;
+
+    fn deref(&self) -> &Self::Target {
+        &self.endpoint
+    }
+}
+
+/// An endpoint that accepts connections from clients. Derefs to the quinn [`Endpoint`].
+#[derive(Clone, Debug)]
+pub struct ServerEndpoint {
+    endpoint: Endpoint,
+    /// kept so that the server config can be built again on reload
+    builder: EndpointBuilder,
+}
+
+impl ServerEndpoint {
+    /// for [`accept`]. The handshake should be finished in a new task so that one slow client does not block the others
+    pub fn handshake_timeout(&self) -> Duration {
+        self.builder.timeouts.handshake
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.builder.timeouts
+    }
+
+    /// Watches the certificate, key, and CRL files. Call [`ServerEndpoint::reload`] when they change.
+    pub fn file_watch(&self) -> FileWatch {
+        FileWatch::new(self.builder.watched_paths(TunnelEnd::Server))
+    }
+
+    /// Renews the server certificate. Call [`ServerEndpoint::reload`] when it is renewed.
+    pub fn renewal(&self) -> Renewal {
+        self.builder.renewal(TunnelEnd::Server)
+    }
+
+    /// Read the certificates again and use them for new handshakes. Open connections are not dropped.
+    ///
+    /// If anything fails, the old config is kept.
+    pub async fn reload(&self) {
+        reload_server_config(&self.endpoint, self.builder.server_config_async().await);
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Deref for ServerEndpoint {
+    type Target = Endpoint;
+
+    fn deref(&self) -> &Self::Target {
+        &self.endpoint
+    }
 }
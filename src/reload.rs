@@ -0,0 +1,114 @@
+//! Reloading certificates and config without dropping connections.
+//!
+//! Send the process SIGHUP, or change the certificate, key, or CRL files. New handshakes use the new certificates. Existing connections keep the ones they were made with.
+
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use quinn::{ClientConfig, Endpoint, ServerConfig};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::sleep;
+use tracing::{error, info, trace};
+
+/// How often [`FileWatch`] checks the files.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Yields each time the process gets SIGHUP.
+pub fn reload_signal() -> anyhow::Result<Signal> {
+    let x = signal(SignalKind::hangup())?;
+
+    Ok(x)
+}
+
+/// Use a freshly built server config for new handshakes.
+///
+/// If building the config failed, the old config is kept. A typo in a renewed cert shouldn't take down the tunnel.
+pub fn reload_server_config(endpoint: &Endpoint, server_config: anyhow::Result<ServerConfig>) {
+    match server_config {
+        Ok(server_config) => {
+            endpoint.set_server_config(Some(server_config));
+
+            info!("reloaded server config");
+        }
+        Err(err) => {
+            error!(?err, "reload failed. keeping the old server config");
+        }
+    }
+}
+
+/// Use a freshly built client config for new connections.
+///
+/// quinn's default client config isn't shared between clones of an endpoint, so clients keep theirs behind a lock.
+pub fn reload_client_config(
+    shared: &RwLock<ClientConfig>,
+    client_config: anyhow::Result<ClientConfig>,
+) {
+    match client_config {
+        Ok(client_config) => {
+            *shared.write().unwrap() = client_config;
+
+            info!("reloaded client config");
+        }
+        Err(err) => {
+            error!(?err, "reload failed. keeping the old client config");
+        }
+    }
+}
+
+/// Notices when files change by checking their modification times. Simpler than inotify and works on every filesystem.
+///
+/// Files that don't exist yet are watched too. Creating one counts as a change.
+#[derive(Debug)]
+pub struct FileWatch {
+    paths: Vec<PathBuf>,
+    mtimes: Vec<Option<SystemTime>>,
+    interval: Duration,
+}
+
+impl FileWatch {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let mtimes = paths.iter().map(mtime).collect();
+
+        trace!(?paths, "watching");
+
+        Self {
+            paths,
+            mtimes,
+            interval: WATCH_INTERVAL,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Wait until any of the files changes. Never returns if there are none.
+    pub async fn changed(&mut self) {
+        loop {
+            sleep(self.interval).await;
+
+            let mut mtimes = Vec::with_capacity(self.paths.len());
+            for x in self.paths.iter() {
+                mtimes.push(mtime_async(x).await);
+            }
+
+            if mtimes != self.mtimes {
+                self.mtimes = mtimes;
+                return;
+            }
+        }
+    }
+}
+
+fn mtime(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+async fn mtime_async(path: &PathBuf) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|x| x.modified())
+        .ok()
+}
@@ -0,0 +1,173 @@
+//! Renewing this end's certificate before it expires.
+//!
+//! If the CA's key is on this machine (like after `quick_certs new`), a certificate that the CA signed is signed again once it's close to expiring. It keeps its key, names, and lifetime, so `--pin-sha256` pins keep working. Servers use it for new handshakes right away.
+//!
+//! The CA itself is never renewed. It only gets a loud warning.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+use crate::certs::{
+    cert_from_pem_async, check_key_permissions_async, expires_in, needs_renewal,
+    read_passphrase_async, CertificateAuthority, CA_WARN_DAYS, DEFAULT_RENEW_WITHIN_DAYS,
+};
+
+/// How often [`Renewal`] checks the certificates.
+pub const RENEW_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// the files that renewing needs
+#[derive(Clone, Debug)]
+struct RenewalPaths {
+    cert: PathBuf,
+    key: PathBuf,
+    ca_cert: PathBuf,
+    ca_key: PathBuf,
+}
+
+/// Checks a certificate's expiration and renews it from the local CA.
+#[derive(Clone, Debug)]
+pub struct Renewal {
+    paths: Option<RenewalPaths>,
+    ca_passphrase_file: Option<PathBuf>,
+    insecure_key_permissions: bool,
+    within: time::Duration,
+    interval: Duration,
+}
+
+impl Renewal {
+    pub fn new(cert: PathBuf, key: PathBuf, ca_cert: PathBuf, ca_key: PathBuf) -> Self {
+        Self {
+            paths: Some(RenewalPaths {
+                cert,
+                key,
+                ca_cert,
+                ca_key,
+            }),
+            ca_passphrase_file: None,
+            insecure_key_permissions: false,
+            within: time::Duration::days(DEFAULT_RENEW_WITHIN_DAYS.into()),
+            interval: RENEW_INTERVAL,
+        }
+    }
+
+    /// Never renews anything. For certificates that don't come from files.
+    pub fn disabled() -> Self {
+        Self {
+            paths: None,
+            ca_passphrase_file: None,
+            insecure_key_permissions: false,
+            within: time::Duration::days(DEFAULT_RENEW_WITHIN_DAYS.into()),
+            interval: RENEW_INTERVAL,
+        }
+    }
+
+    /// renew certificates with less than this many days left
+    pub fn with_within_days(mut self, days: u32) -> Self {
+        self.within = time::Duration::days(days.into());
+        self
+    }
+
+    /// the file with the passphrase for an encrypted CA key
+    pub fn with_ca_passphrase_file(mut self, path: Option<PathBuf>) -> Self {
+        self.ca_passphrase_file = path;
+        self
+    }
+
+    /// Use a CA key that other users can read.
+    pub fn with_insecure_key_permissions(mut self, insecure_key_permissions: bool) -> Self {
+        self.insecure_key_permissions = insecure_key_permissions;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Check the certificates now. Returns true if this end's certificate was renewed.
+    ///
+    /// Errors are logged. An expiring certificate still works until it expires.
+    pub async fn check(&self) -> bool {
+        let Some(paths) = self.paths.as_ref() else {
+            return false;
+        };
+
+        match self.try_check(paths).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, cert = %paths.cert.display(), "certificate renewal failed");
+                false
+            }
+        }
+    }
+
+    async fn try_check(&self, paths: &RenewalPaths) -> anyhow::Result<bool> {
+        let ca_cert = cert_from_pem_async(paths.ca_cert.clone()).await?;
+
+        let ca_left = expires_in(&ca_cert)?;
+
+        if ca_left.is_negative() {
+            error!(ca = %paths.ca_cert.display(), "THE CA HAS EXPIRED. nothing it signed is trusted. make a new CA and copy it to every client and server");
+        } else if ca_left < time::Duration::days(CA_WARN_DAYS) {
+            warn!(ca = %paths.ca_cert.display(), days = ca_left.whole_days(), "THE CA EXPIRES SOON. make a new CA and copy it to every client and server before then");
+        }
+
+        let cert = cert_from_pem_async(paths.cert.clone()).await?;
+
+        if !needs_renewal(&cert, self.within)? {
+            debug!(cert = %paths.cert.display(), days = expires_in(&cert)?.whole_days(), "certificate doesn't need renewing yet");
+            return Ok(false);
+        }
+
+        if !tokio::fs::try_exists(&paths.ca_key).await? {
+            warn!(
+                cert = %paths.cert.display(),
+                days = expires_in(&cert)?.whole_days(),
+                "certificate expires soon and there's no CA key at \"{}\" to renew it with",
+                paths.ca_key.display()
+            );
+            return Ok(false);
+        }
+
+        if !self.insecure_key_permissions {
+            check_key_permissions_async(&paths.ca_key).await?;
+        }
+
+        let passphrase = match self.ca_passphrase_file.as_deref() {
+            Some(x) => Some(read_passphrase_async(x).await?),
+            None => None,
+        };
+
+        let ca = CertificateAuthority::load_async(
+            paths.ca_cert.clone(),
+            paths.ca_key.clone(),
+            passphrase.as_deref(),
+        )
+        .await?;
+
+        if !ca.issued(&cert)? {
+            warn!(cert = %paths.cert.display(), "certificate expires soon and wasn't signed by the local CA. renew it wherever it came from");
+            return Ok(false);
+        }
+
+        let cert = ca.renew_async(&paths.cert, &paths.key).await?;
+
+        info!(cert = %paths.cert.display(), days = expires_in(&cert)?.whole_days(), "renewed certificate");
+
+        Ok(true)
+    }
+
+    /// Wait until the certificate is renewed by a periodic check. Never returns if renewal is disabled.
+    pub async fn renewed(&mut self) {
+        loop {
+            sleep(self.interval).await;
+
+            if self.check().await {
+                return;
+            }
+        }
+    }
+}
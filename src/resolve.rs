@@ -0,0 +1,419 @@
+//! Remote addresses that can be hostnames, and connecting to them Happy Eyeballs style.
+//!
+//! Names are resolved every time we connect. That way a reconnect follows DNS changes. Client tunnels use [`reconnect`] when their connection closes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use tokio::select;
+use tokio::time::sleep;
+use tracing::{debug, trace, warn};
+
+/// How long to wait for one attempt before racing the next address. From RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long [`reconnect`] waits after the first failure. It doubles after each one.
+pub const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest [`reconnect`] waits between attempts.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// An IP address or a hostname, with a port.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
+pub enum RemoteAddr {
+    Addr(SocketAddr),
+    Name(String, u16),
+}
+
+impl RemoteAddr {
+    /// None if this is already an IP address
+    pub fn hostname(&self) -> Option<&str> {
+        match self {
+            Self::Addr(_) => None,
+            Self::Name(x, _) => Some(x),
+        }
+    }
+
+    /// The hostname, or the IP address. An IP address is checked against the IP addresses on the server's certificate.
+    pub fn server_name(&self) -> String {
+        match self {
+            Self::Addr(x) => x.ip().to_string(),
+            Self::Name(x, _) => x.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Addr(x) => x.port(),
+            Self::Name(_, x) => *x,
+        }
+    }
+
+    pub async fn resolve(&self, resolver: &dyn Resolver) -> anyhow::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Addr(x) => Ok(vec![*x]),
+            Self::Name(host, port) => {
+                let addrs = resolver
+                    .lookup(host, *port)
+                    .await
+                    .with_context(|| format!("failed resolving {}", host))?;
+
+                if addrs.is_empty() {
+                    anyhow::bail!("{} has no addresses", host);
+                }
+
+                trace!(host, ?addrs, "resolved");
+
+                Ok(addrs)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(x) => x.fmt(f),
+            Self::Name(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for RemoteAddr {
+    fn from(x: SocketAddr) -> Self {
+        Self::Addr(x)
+    }
+}
+
+impl FromStr for RemoteAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(x) = s.parse::<SocketAddr>() {
+            return Ok(Self::Addr(x));
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .with_context(|| format!("{} needs a port", s))?;
+
+        if host.is_empty() {
+            anyhow::bail!("{} needs a host", s);
+        }
+
+        if host.contains(':') {
+            anyhow::bail!("IPv6 addresses need brackets: [{}]:{}", host, port);
+        }
+
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in {}", s))?;
+
+        Ok(Self::Name(host.to_string(), port))
+    }
+}
+
+impl TryFrom<String> for RemoteAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Turns hostnames into addresses.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str, port: u16)
+        -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// The system resolver. Respects /etc/hosts and /etc/resolv.conf.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Fixed answers instead of DNS. Good for tests and for hosts that DNS doesn't know about.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn with_host(mut self, host: impl Into<String>, ips: Vec<IpAddr>) -> Self {
+        self.hosts.insert(host.into(), ips);
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        let x = self
+            .hosts
+            .get(host)
+            .map(|ips| ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("unknown host {}", host))
+            });
+
+        Box::pin(std::future::ready(x))
+    }
+}
+
+/// Alternate address families, starting with whichever the resolver put first. RFC 8305 section 4.
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+
+    let first_is_ipv6 = first.is_ipv6();
+
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|x| x.is_ipv6() == first_is_ipv6);
+
+    let mut x = Vec::with_capacity(preferred.len() + other.len());
+
+    // reverse so that pop takes from the front
+    preferred.reverse();
+    other.reverse();
+
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => x.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    x
+}
+
+/// Try every address, starting a new attempt every `attempt_delay` or as soon as one fails. The first success wins.
+///
+/// The slower attempts are dropped.
+pub async fn happy_eyeballs<T, F, Fut>(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    connect: F,
+) -> anyhow::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut pending = interleave_families(addrs).into_iter().peekable();
+
+    // one closure so that every attempt is the same type
+    let start = |addr: SocketAddr| {
+        let f = connect(addr);
+
+        async move { (addr, f.await) }
+    };
+
+    let mut attempts = FuturesUnordered::new();
+
+    attempts.push(start(pending.next().context("no addresses to connect to")?));
+
+    // attempts is never empty at the top of the loop
+    loop {
+        select! {
+            Some((addr, x)) = attempts.next() => match x {
+                Ok(x) => {
+                    debug!(%addr, "connected");
+                    return Ok(x);
+                }
+                Err(err) => {
+                    debug!(%addr, ?err, "connection attempt failed");
+
+                    // don't wait for the delay. the next address might work
+                    match pending.next() {
+                        Some(addr) => attempts.push(start(addr)),
+                        // every address failed. the last error is as good as any
+                        None if attempts.is_empty() => return Err(err),
+                        None => {}
+                    }
+                }
+            },
+            _ = sleep(attempt_delay), if pending.peek().is_some() => {
+                let addr = pending.next().unwrap();
+
+                trace!(%addr, "starting another attempt");
+
+                attempts.push(start(addr));
+            }
+        }
+    }
+}
+
+/// Call `connect` until it works. Waits longer after each failure, up to [`MAX_RECONNECT_DELAY`].
+///
+/// For after a connection closes. `connect` should resolve the name again, like [`crate::quic::connect_remote`] does.
+pub async fn reconnect<T, F, Fut>(connect: F) -> T
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        match connect().await {
+            Ok(x) => return x,
+            Err(err) => {
+                warn!(?err, ?delay, "reconnecting failed. trying again");
+
+                sleep(delay).await;
+
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use super::*;
+
+    fn v4(last: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(192, 0, 2, last).into(), 8443)
+    }
+
+    fn v6(last: u16) -> SocketAddr {
+        SocketAddr::new(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last).into(),
+            8443,
+        )
+    }
+
+    #[test]
+    fn remote_addr_from_str() {
+        assert_eq!(
+            "192.0.2.1:8443".parse::<RemoteAddr>().unwrap(),
+            RemoteAddr::Addr(v4(1))
+        );
+        assert_eq!(
+            "[2001:db8::1]:8443".parse::<RemoteAddr>().unwrap(),
+            RemoteAddr::Addr(v6(1))
+        );
+        assert_eq!(
+            "vpn.example.com:8443".parse::<RemoteAddr>().unwrap(),
+            RemoteAddr::Name("vpn.example.com".to_string(), 8443)
+        );
+
+        for x in [
+            "vpn.example.com",
+            ":8443",
+            "2001:db8::1:8443",
+            "vpn.example.com:http",
+        ] {
+            assert!(x.parse::<RemoteAddr>().is_err(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn interleave_starts_with_the_first_family() {
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(1)]),
+            vec![v6(1), v4(1), v6(2), v6(3)]
+        );
+        assert_eq!(
+            interleave_families(vec![v4(1), v4(2), v6(1), v6(2)]),
+            vec![v4(1), v6(1), v4(2), v6(2)]
+        );
+        assert_eq!(interleave_families(vec![]), vec![]);
+    }
+
+    #[tokio::test]
+    async fn resolve_with_static_resolver() {
+        let resolver = StaticResolver::default().with_host("vpn", vec![v6(1).ip(), v4(1).ip()]);
+
+        let remote: RemoteAddr = "vpn:8443".parse().unwrap();
+
+        assert_eq!(remote.resolve(&resolver).await.unwrap(), vec![v6(1), v4(1)]);
+
+        let unknown: RemoteAddr = "other:8443".parse().unwrap();
+
+        assert!(unknown.resolve(&resolver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_skips_failures_without_waiting() {
+        let start = Instant::now();
+
+        let x = happy_eyeballs(
+            vec![v6(1), v4(1)],
+            Duration::from_secs(10),
+            |addr| async move {
+                if addr.is_ipv6() {
+                    anyhow::bail!("unreachable");
+                }
+
+                Ok(addr)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(x, v4(1));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_races_slow_attempts() {
+        let x = happy_eyeballs(
+            vec![v6(1), v4(1)],
+            Duration::from_millis(10),
+            |addr| async move {
+                // the first address hangs. the second one starts after the attempt delay and wins
+                if addr.is_ipv6() {
+                    sleep(Duration::from_secs(10)).await;
+                }
+
+                Ok(addr)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(x, v4(1));
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_fails_when_every_address_fails() {
+        let attempts = AtomicUsize::new(0);
+
+        let x = happy_eyeballs(vec![v6(1), v4(1), v4(2)], Duration::from_millis(10), |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+
+            async { anyhow::bail!("unreachable") as anyhow::Result<()> }
+        })
+        .await;
+
+        assert!(x.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
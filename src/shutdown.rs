@@ -0,0 +1,110 @@
+//! Graceful shutdown.
+//!
+//! QUIC doesn't have a GOAWAY frame like HTTP/2. Instead, we stop accepting connections and new streams and then let the open streams finish.
+//! UDP flows never finish on their own, so they aren't waited for. UDP clients finish their flows before draining.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::Endpoint;
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
+use tracing::{info, warn};
+
+use crate::counters::CountersRegistry;
+
+/// how long to wait for streams to finish after a shutdown signal.
+pub fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Tells a tunnel from [`crate::tunnels`] to stop. Every clone stops the same tunnel.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+
+        Self { tx: Arc::new(tx) }
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop accepting, drain open streams, and then return from `run`.
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once `shutdown` has been called. Resolves right away if it already was.
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+
+        // the sender is in self so this can't fail
+        let _ = rx.wait_for(|x| *x).await;
+    }
+}
+
+/// Resolves when the process gets ctrl-c or SIGTERM.
+pub async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+    let x = tokio::select! {
+        x = tokio::signal::ctrl_c() => {
+            x?;
+            "SIGINT"
+        }
+        _ = sigterm.recv() => "SIGTERM",
+    };
+
+    Ok(x)
+}
+
+/// Stop new connections and streams, wait up to `drain_timeout` for open streams to finish, then close the endpoint.
+///
+/// Stop any listeners that open new streams before calling this. UDP flow streams are not waited for. They are closed with the endpoint.
+pub async fn drain(endpoint: &Endpoint, counts: &CountersRegistry, drain_timeout: Duration) {
+    endpoint.reject_new_connections();
+
+    for (conn, _) in counts.connections() {
+        // only limits streams the peer opens after this. streams that are already open keep going
+        conn.set_max_concurrent_bi_streams(0u32.into());
+    }
+
+    let open_streams = draining_streams(counts);
+
+    if open_streams > 0 {
+        info!(open_streams, ?drain_timeout, "draining");
+
+        let wait_for_streams = async {
+            let mut i = interval(Duration::from_millis(100));
+
+            while draining_streams(counts) > 0 {
+                i.tick().await;
+            }
+        };
+
+        if timeout(drain_timeout, wait_for_streams).await.is_err() {
+            warn!(open_streams = draining_streams(counts), "drain timed out");
+        }
+    }
+
+    endpoint.close(0u32.into(), b"shutting down");
+
+    endpoint.wait_idle().await;
+
+    info!("shutdown complete");
+}
+
+/// open streams that will finish on their own
+fn draining_streams(counts: &CountersRegistry) -> usize {
+    let x = counts.process().snapshot();
+
+    x.open_streams().saturating_sub(x.open_flow_streams())
+}
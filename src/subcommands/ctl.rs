@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use argh::FromArgs;
+use quic_tunnel::admin::send_admin_command;
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "ctl")]
+/// Talk to a running tunnel's admin socket.
+///
+/// Commands are "connections", "streams", "cache", "counters", "drop <connection id>", and "flush [from address]".
+pub struct CtlSubCommand {
+    /// path of the admin socket
+    #[argh(positional)]
+    admin_socket: PathBuf,
+
+    /// the command to send
+    #[argh(positional, greedy)]
+    command: Vec<String>,
+}
+
+impl CtlSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        let command = self.command.join(" ");
+
+        let response = send_admin_command(self.admin_socket, &command).await?;
+
+        println!("{}", serde_json::to_string_pretty(&response)?);
+
+        Ok(())
+    }
+}
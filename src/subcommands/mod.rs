@@ -1,11 +1,15 @@
+mod ctl;
 mod quick_certs;
 mod reverse_proxy_client;
 mod reverse_proxy_server;
+mod run;
 mod udp_client;
 mod udp_server;
 
+pub use ctl::CtlSubCommand;
 pub use quick_certs::QuickCertsSubCommand;
 pub use reverse_proxy_client::ReverseProxyClientSubCommand;
 pub use reverse_proxy_server::ReverseProxyServerSubCommand;
+pub use run::RunSubCommand;
 pub use udp_client::UdpClientSubCommand;
 pub use udp_server::UdpServerSubCommand;
@@ -0,0 +1,73 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use argh::FromArgs;
+use quic_tunnel::certs::{KeyAlgo, SubjectNames, TunnelCertificate, TunnelEnd};
+use tracing::info;
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "csr")]
+/// Create a key and a certificate request on the machine that will use them.
+///
+/// Copy the request (not the key) to the CA's machine and run "quick_certs sign" there.
+pub struct CsrSubCommand {
+    #[argh(positional)]
+    /// the directory to write the key and request to
+    dir: PathBuf,
+
+    /// the tunnel name. writes "<name>_client.key.pem" and "<name>_client.csr.pem"
+    #[argh(positional)]
+    name: String,
+
+    /// request a server certificate instead of a client one
+    #[argh(switch)]
+    server: bool,
+
+    /// the kind of key: ecdsa-p256 (default), ed25519, or rsa-3072
+    #[argh(option, default = "Default::default()")]
+    alg: KeyAlgo,
+
+    /// another DNS name for a server certificate, besides "<name>_server". can be repeated. "quick_certs sign" needs it too
+    #[argh(option)]
+    dns: Vec<String>,
+
+    /// an IP address for a server certificate, so clients can connect to it without a name. can be repeated. "quick_certs sign" needs it too
+    #[argh(option)]
+    ip: Vec<IpAddr>,
+}
+
+impl CsrSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let (tunnel_end, end_name) = if self.server {
+            (TunnelEnd::Server, "server")
+        } else {
+            (TunnelEnd::Client, "client")
+        };
+
+        if !self.server && (!self.dns.is_empty() || !self.ip.is_empty()) {
+            anyhow::bail!("--dns and --ip are for server certificates. add --server");
+        }
+
+        let key = self.dir.join(format!("{}_{}.key.pem", self.name, end_name));
+        let csr = self.dir.join(format!("{}_{}.csr.pem", self.name, end_name));
+
+        for x in [&key, &csr] {
+            if x.exists() {
+                anyhow::bail!("\"{}\" already exists", x.display());
+            }
+        }
+
+        let names = SubjectNames::for_tunnel(&self.name, tunnel_end)
+            .with_dns(self.dns)
+            .with_ips(self.ip);
+
+        TunnelCertificate::new_request(key, csr.clone(), &names, self.alg)?;
+
+        info!(
+            "copy \"{}\" to the CA and run \"quick_certs sign\" there. the key stays here",
+            csr.display()
+        );
+
+        Ok(())
+    }
+}
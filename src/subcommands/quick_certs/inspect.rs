@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use argh::FromArgs;
+use quic_tunnel::certs::{inspect_dir, CertReport};
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "inspect")]
+/// List every certificate in a directory with its names, serial, extended key usages, expiration, and the CA that signed it.
+///
+/// Flags expired certificates, missing extended key usages, unknown CAs, and keys that don't match their certificates.
+pub struct InspectSubCommand {
+    #[argh(positional)]
+    /// the directory with the certs
+    dir: PathBuf,
+
+    /// print JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+}
+
+impl InspectSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let reports = inspect_dir(&self.dir)?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&reports)?);
+        } else {
+            print_table(&reports);
+        }
+
+        Ok(())
+    }
+}
+
+fn print_table(reports: &[CertReport]) {
+    let header = [
+        "FILE",
+        "SUBJECT",
+        "NAMES",
+        "SERIAL",
+        "USAGE",
+        "NOT AFTER",
+        "SIGNED BY",
+        "PROBLEMS",
+    ]
+    .map(String::from);
+
+    let rows: Vec<[String; 8]> = reports
+        .iter()
+        .map(|x| {
+            let file = x
+                .file
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+
+            let file = if x.index == 0 {
+                file
+            } else {
+                format!("{}#{}", file, x.index)
+            };
+
+            let usage = if x.is_ca {
+                "CA".to_string()
+            } else {
+                x.extended_key_usages.join(",")
+            };
+
+            // the directory is the same for every file
+            let signed_by = match x.signed_by.as_deref() {
+                Some(x) => PathBuf::from(x)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                None => "?".to_string(),
+            };
+
+            [
+                file,
+                x.subject.clone(),
+                x.names.join(","),
+                x.serial.clone(),
+                usage,
+                x.not_after.clone(),
+                signed_by,
+                x.problems.join("; "),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|x| x.len());
+
+    for row in rows.iter() {
+        for (width, x) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(x.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(x, width)| format!("{:width$}", x, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        println!("{}", line.trim_end());
+    }
+}
@@ -0,0 +1,44 @@
+mod csr;
+mod inspect;
+mod new;
+mod revoke;
+mod sign;
+
+use argh::FromArgs;
+
+pub use csr::CsrSubCommand;
+pub use inspect::InspectSubCommand;
+pub use new::NewCertsSubCommand;
+pub use revoke::RevokeSubCommand;
+pub use sign::SignSubCommand;
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "quick_certs")]
+/// Generage certs easily.
+/// There's a lot more this could do, but I feel like you should just use other existing Certificate Authority management software if need anything more than this
+pub struct QuickCertsSubCommand {
+    #[argh(subcommand)]
+    nested: QuickCertsSubCommandEnum,
+}
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand)]
+enum QuickCertsSubCommandEnum {
+    New(NewCertsSubCommand),
+    Revoke(RevokeSubCommand),
+    Csr(CsrSubCommand),
+    Sign(SignSubCommand),
+    Inspect(InspectSubCommand),
+}
+
+impl QuickCertsSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        match self.nested {
+            QuickCertsSubCommandEnum::New(x) => x.main(),
+            QuickCertsSubCommandEnum::Revoke(x) => x.main(),
+            QuickCertsSubCommandEnum::Csr(x) => x.main(),
+            QuickCertsSubCommandEnum::Sign(x) => x.main(),
+            QuickCertsSubCommandEnum::Inspect(x) => x.main(),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use argh::FromArgs;
+use quic_tunnel::certs::{
+    dns_names, read_passphrase, write_ca_server_name, CertificateAuthority, KeyAlgo, SpkiPin,
+    SubjectNames, TunnelCertificate, TunnelEnd, Validity, DEFAULT_CA_DAYS, DEFAULT_CLIENT_DAYS,
+    DEFAULT_SERVER_DAYS,
+};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::info;
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "new")]
+/// Create a CA, a server cert, and a client cert for each name. Existing files are kept.
+///
+/// This makes every key on this machine. To keep a client's key on the client, use "quick_certs csr" there and "quick_certs sign" here instead.
+pub struct NewCertsSubCommand {
+    #[argh(positional)]
+    /// the directory to write certs to
+    dir: PathBuf,
+
+    /// days the CA is valid for (default 3650)
+    #[argh(option, default = "DEFAULT_CA_DAYS")]
+    ca_days: u32,
+
+    /// days server certs are valid for (default 365)
+    #[argh(option, default = "DEFAULT_SERVER_DAYS")]
+    server_days: u32,
+
+    /// days client certs are valid for (default 30)
+    #[argh(option, default = "DEFAULT_CLIENT_DAYS")]
+    client_days: u32,
+
+    /// when new certs start being valid, like 2024-01-01T00:00:00Z (default an hour ago)
+    #[argh(option, from_str_fn(parse_rfc3339))]
+    not_before: Option<OffsetDateTime>,
+
+    /// the kind of key for new certs: ecdsa-p256 (default), ed25519, or rsa-3072
+    #[argh(option, default = "Default::default()")]
+    alg: KeyAlgo,
+
+    /// encrypt a new CA key with the passphrase on the first line of this file. needed to use an encrypted CA key too
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+
+    /// another DNS name for new server certs, besides "<name>_server". can be repeated
+    #[argh(option)]
+    server_dns: Vec<String>,
+
+    /// an IP address for new server certs, so clients can connect to it without a name. can be repeated
+    #[argh(option)]
+    server_ip: Vec<IpAddr>,
+
+    /// names of the client certs to generate (if they don't already exist)
+    #[argh(positional)]
+    client_names: Vec<String>,
+}
+
+impl NewCertsSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let validity = |days| match self.not_before {
+            Some(x) => Validity::starting(x, days),
+            None => Validity::days(days),
+        };
+
+        let passphrase = self
+            .ca_passphrase_file
+            .as_deref()
+            .map(read_passphrase)
+            .transpose()?;
+
+        // get or create all of the client certificates
+        for tunnel_name in self.client_names {
+            // get or create the certificate authority
+            let ca_cert = self.dir.join(format!("{tunnel_name}_ca.pem"));
+            let ca_key = self.dir.join(format!("{tunnel_name}_ca.key.pem"));
+
+            let ca = CertificateAuthority::load_or_new(
+                ca_cert.clone(),
+                ca_key,
+                validity(self.ca_days),
+                passphrase.as_deref(),
+                self.alg,
+            )?;
+
+            // get or create the server certificate
+            let server_cert = self.dir.join(format!("{tunnel_name}_server.pem"));
+            let server_key = self.dir.join(format!("{tunnel_name}_server.key.pem"));
+
+            let server_names = SubjectNames::for_tunnel(&tunnel_name, TunnelEnd::Server)
+                .with_dns(self.server_dns.clone())
+                .with_ips(self.server_ip.clone());
+
+            let server = TunnelCertificate::load_or_new(
+                &ca.cert_gen,
+                server_cert,
+                server_key,
+                &server_names,
+                TunnelEnd::Server,
+                validity(self.server_days),
+                self.alg,
+            )?;
+
+            // so clients can find the server's name without guessing from file names
+            if let Some(server_name) = dns_names(&server.cert)?.first() {
+                write_ca_server_name(&ca_cert, server_name)?;
+            }
+
+            info!(
+                tunnel_name,
+                "server key pin for --pin-sha256: {}",
+                SpkiPin::from_cert(&server.cert)?
+            );
+
+            let client_cert = self.dir.join(format!("{tunnel_name}_client.pem"));
+            let client_key = self.dir.join(format!("{tunnel_name}_client.key.pem"));
+
+            TunnelCertificate::load_or_new(
+                &ca.cert_gen,
+                client_cert,
+                client_key,
+                &SubjectNames::for_tunnel(&tunnel_name, TunnelEnd::Client),
+                TunnelEnd::Client,
+                validity(self.client_days),
+                self.alg,
+            )?;
+        }
+
+        info!("saved certs to \"{}\"", self.dir.display());
+
+        Ok(())
+    }
+}
+
+fn parse_rfc3339(value: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(value, &Rfc3339).map_err(|err| format!("{}: {}", value, err))
+}
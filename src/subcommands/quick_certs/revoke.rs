@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use argh::FromArgs;
+use quic_tunnel::certs::{
+    cert_from_pem, cert_serial, parse_serial, read_passphrase, CertificateAuthority,
+};
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "revoke")]
+/// Revoke a client certificate and write an updated CRL.
+///
+/// Servers using "<dir>/<name>_crl.pem" (the default) or "--crl" pick it up without a restart.
+pub struct RevokeSubCommand {
+    #[argh(positional)]
+    /// the directory with the certs
+    dir: PathBuf,
+
+    /// the name that was given to "quick_certs new"
+    #[argh(positional)]
+    name: String,
+
+    /// the serial number of the certificate to revoke ("c0:ff:ee"), or the path to the certificate
+    #[argh(positional)]
+    cert: String,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+}
+
+impl RevokeSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let ca_cert = self.dir.join(format!("{}_ca.pem", self.name));
+        let ca_key = self.dir.join(format!("{}_ca.key.pem", self.name));
+        let crl = self.dir.join(format!("{}_crl.pem", self.name));
+
+        let passphrase = self
+            .ca_passphrase_file
+            .as_deref()
+            .map(read_passphrase)
+            .transpose()?;
+
+        let ca = CertificateAuthority::load(ca_cert, ca_key, passphrase.as_deref())?;
+
+        let cert = PathBuf::from(&self.cert);
+
+        let serial = if cert.exists() {
+            cert_serial(&cert_from_pem(cert)?)?
+        } else {
+            parse_serial(&self.cert)?
+        };
+
+        ca.revoke(&crl, &serial)
+    }
+}
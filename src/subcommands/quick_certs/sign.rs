@@ -0,0 +1,136 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use argh::FromArgs;
+use quic_tunnel::certs::{
+    cert_from_pem, dns_names, read_passphrase, write_atomic, write_ca_server_name,
+    CertificateAuthority, SpkiPin, SubjectNames, TunnelEnd, Validity, DEFAULT_CLIENT_DAYS,
+    DEFAULT_SERVER_DAYS,
+};
+use tracing::info;
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "sign")]
+/// Sign a certificate request from "quick_certs csr" with the CA.
+///
+/// The certificate can only be used for client authentication (or server authentication with --server), whatever the request asked for.
+/// Its names come from <name>, --dns, and --ip. The names in the request are ignored.
+pub struct SignSubCommand {
+    #[argh(positional)]
+    /// the directory with the CA
+    dir: PathBuf,
+
+    /// the name that was given to "quick_certs new"
+    #[argh(positional)]
+    name: String,
+
+    /// the certificate request to sign
+    #[argh(positional)]
+    csr: PathBuf,
+
+    /// sign a server certificate instead of a client one
+    #[argh(switch)]
+    server: bool,
+
+    /// another DNS name for a server certificate, besides "<name>_server". can be repeated
+    #[argh(option)]
+    dns: Vec<String>,
+
+    /// an IP address for a server certificate. can be repeated
+    #[argh(option)]
+    ip: Vec<IpAddr>,
+
+    /// days the certificate is valid for (default 30 for clients and 365 for servers)
+    #[argh(option)]
+    days: Option<u32>,
+
+    /// where to write the certificate. defaults to the request's path without ".csr"
+    #[argh(option)]
+    out: Option<PathBuf>,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+}
+
+impl SignSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let (tunnel_end, default_days) = if self.server {
+            (TunnelEnd::Server, DEFAULT_SERVER_DAYS)
+        } else {
+            (TunnelEnd::Client, DEFAULT_CLIENT_DAYS)
+        };
+
+        if !self.server && (!self.dns.is_empty() || !self.ip.is_empty()) {
+            anyhow::bail!("--dns and --ip are for server certificates. add --server");
+        }
+
+        let out = match self.out {
+            Some(x) => x,
+            None => {
+                let csr = self
+                    .csr
+                    .to_str()
+                    .context("request path is not valid utf8")?;
+
+                let x = csr
+                    .strip_suffix(".csr.pem")
+                    .context("the request doesn't end with .csr.pem. set --out")?;
+
+                PathBuf::from(format!("{}.pem", x))
+            }
+        };
+
+        if out.exists() {
+            anyhow::bail!("\"{}\" already exists", out.display());
+        }
+
+        let ca_cert = self.dir.join(format!("{}_ca.pem", self.name));
+        let ca_key = self.dir.join(format!("{}_ca.key.pem", self.name));
+
+        let passphrase = self
+            .ca_passphrase_file
+            .as_deref()
+            .map(read_passphrase)
+            .transpose()?;
+
+        let ca = CertificateAuthority::load(ca_cert.clone(), ca_key, passphrase.as_deref())?;
+
+        let csr = std::fs::read_to_string(&self.csr)
+            .with_context(|| format!("failed reading {}", self.csr.display()))?;
+
+        let names = SubjectNames::for_tunnel(&self.name, tunnel_end)
+            .with_dns(self.dns)
+            .with_ips(self.ip);
+
+        info!("signing {}", names);
+
+        let pem = ca.sign_request(
+            &csr,
+            &names,
+            tunnel_end,
+            Validity::days(self.days.unwrap_or(default_days)),
+        )?;
+
+        write_atomic(&out, pem)?;
+
+        info!("saved certificate to \"{}\"", out.display());
+
+        if self.server {
+            let server = cert_from_pem(out)?;
+
+            // so clients can find the server's name without guessing from file names
+            if let Some(server_name) = dns_names(&server)?.first() {
+                write_ca_server_name(&ca_cert, server_name)?;
+            }
+
+            info!(
+                "server key pin for --pin-sha256: {}",
+                SpkiPin::from_cert(&server)?
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,28 +1,58 @@
 use argh::FromArgs;
-use futures::TryFutureExt;
 use quic_tunnel::{
-    compress::{copy_bidirectional_with_compression, CompressAlgo},
-    quic::{build_client_endpoint, CongestionMode},
-    stream::Stream,
+    admin::{spawn_admin_socket, AdminState},
+    certs::{SpkiPin, TunnelEnd, DEFAULT_RENEW_WITHIN_DAYS},
+    compress::CompressAlgo,
+    config::tunnel_alpn,
+    quic::{CongestionMode, EndpointBuilder, TransportPreset, TransportTuning},
+    resolve::RemoteAddr,
+    shutdown::{default_drain_timeout, shutdown_signal},
+    timeouts::{TimeoutOverrides, Timeouts},
+    tunnels::{ReverseProxyClient, ReverseProxyTarget},
 };
 use std::{net::SocketAddr, path::PathBuf, time::Duration};
-use tokio::{
-    net::{TcpSocket, UnixStream},
-    time::timeout,
-};
-use tracing::{debug, info, trace};
+use tokio::select;
+use tracing::info;
 
 #[derive(Debug, FromArgs, PartialEq)]
 /// Run the QUIC Tunnel Client for forwarding a TCP port.
 #[argh(subcommand, name = "reverse_proxy_client")]
 pub struct ReverseProxyClientSubCommand {
-    /// prefix for all the certificates to load
+    /// prefix for the certificates that aren't set with --ca, --cert, or --key
     #[argh(positional)]
     cert_name: String,
 
-    /// the address of the remote QUIC server
+    /// a CA that signed the other end's certificate. can be repeated. every cert in a PEM bundle is trusted. defaults to "<cert_name>_ca.pem"
+    #[argh(option)]
+    ca: Vec<PathBuf>,
+
+    /// this end's certificate, then any intermediate CAs. defaults to "<cert_name>_client.pem"
+    #[argh(option)]
+    cert: Option<PathBuf>,
+
+    /// the key for this end's certificate. defaults to "<cert_name>_client.key.pem"
+    #[argh(option)]
+    key: Option<PathBuf>,
+
+    /// the CA's key, for renewing this end's certificate before it expires. defaults to "<cert_name>_ca.key.pem" if it exists
+    #[argh(option)]
+    ca_key: Option<PathBuf>,
+
+    /// renew this end's certificate when it has less than this many days left (default 10)
+    #[argh(option, default = "DEFAULT_RENEW_WITHIN_DAYS")]
+    renew_within_days: u32,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+
+    /// load keys that other users can read instead of refusing to start
+    #[argh(switch)]
+    insecure_key_permissions: bool,
+
+    /// the address of the remote QUIC server. host:port or ip:port
     #[argh(positional)]
-    remote_quic_addr: SocketAddr,
+    remote_quic_addr: RemoteAddr,
 
     /// the address of the nearby service to forward
     #[argh(option)]
@@ -34,88 +64,150 @@ pub struct ReverseProxyClientSubCommand {
 
     /// the name on the remote server's certificate.
     ///
-    /// If not specified, will be calculated based on `cert`.
+    /// defaults to a "server_name:" line in the CA file, then the name on the "_server.pem" cert for cert_name if it is here, then the host or IP address in remote_quic_addr.
     #[argh(option)]
     remote_name: Option<String>,
 
+    /// trust the server by the SHA-256 of its public key (sha256/<base64>) instead of by the CA and its name. can be repeated
+    #[argh(option)]
+    pin_sha256: Vec<SpkiPin>,
+
+    /// the local address for the QUIC endpoint. defaults to [::]:0, which also reaches IPv4 servers
+    #[argh(option)]
+    bind: Option<SocketAddr>,
+
     /// congestion mode for QUIC
     #[argh(option, default = "Default::default()")]
     congestion_mode: CongestionMode,
 
+    /// tune QUIC for a kind of network: airplane, lan, or satellite. the config file can tune everything
+    #[argh(option)]
+    transport_preset: Option<TransportPreset>,
+
     /// compression mode for the QUIC tunnel.
     ///
     /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
     #[argh(option, default = "CompressAlgo::None")]
     compress: CompressAlgo,
-}
-
-impl ReverseProxyClientSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        if self.tcp_connect.is_none() ^ self.unix_connect.is_none() {
-            anyhow::bail!("specify either tcp_connect or socket_connect. not none. not both");
-        }
-
-        let ca = PathBuf::new().join(format!("{}_ca.pem", self.cert_name));
-        let cert = PathBuf::new().join(format!("{}_client.pem", self.cert_name));
-        let key = PathBuf::new().join(format!("{}_client.key.pem", self.cert_name));
 
-        // connect to the QUIC endpoint on the server
-        // since the client initiates the connections, the client needs keep alive
-        let endpoint = build_client_endpoint(ca, cert.clone(), key, self.congestion_mode, true)?;
-
-        let remote_name = self.remote_name.unwrap_or_else(|| {
-            // TODO: read the cert and use the name on it rather than the filename. filename works for our dev certs though so its fine for now
-            let client_name = cert.file_stem().unwrap().to_string_lossy().to_string();
-
-            client_name.replace("client", "server")
-        });
-
-        // TODO: how should we handle reconnecting?
-        let remote = endpoint.connect(self.remote_quic_addr, &remote_name)?;
+    /// the name of a tunnel in a "run" config on the server. leave it out for the udp_server and reverse_proxy_server subcommands unless they have the same --tunnel-name
+    #[argh(option)]
+    tunnel_name: Option<String>,
 
-        let remote = match remote.into_0rtt() {
-            Ok((remote, _)) => {
-                trace!("0-rtt accepted");
-                remote
-            }
-            Err(remote) => timeout(Duration::from_secs(30), remote).await??,
-        };
+    /// listen on this Unix socket for admin commands. see the "ctl" subcommand
+    #[argh(option)]
+    admin_socket: Option<PathBuf>,
 
-        info!("connected to QUIC server at {}", remote.remote_address());
+    /// seconds to wait for open streams to finish after ctrl-c or SIGTERM
+    #[argh(option, default = "default_drain_timeout().as_secs()")]
+    drain_timeout: u64,
 
-        loop {
-            // TODO: connection pool for re-using these streams
-            let stream = if let Some(tcp_connect) = self.tcp_connect {
-                let tcp_socket = TcpSocket::new_v4()?;
+    /// seconds without any packets before the QUIC connection is closed. default 300
+    #[argh(option)]
+    idle_timeout: Option<u64>,
 
-                trace!(?tcp_socket, "new socket for {}", tcp_connect);
+    /// seconds between keep-alive packets. default a third of the idle timeout
+    #[argh(option)]
+    keep_alive_interval: Option<u64>,
 
-                let nearby_tcp_stream = tcp_socket.connect(tcp_connect).await?;
+    /// seconds to wait for the QUIC handshake. default 30
+    #[argh(option)]
+    handshake_timeout: Option<u64>,
+}
 
-                debug!(
-                    "connected to nearby tcp server at {}",
-                    nearby_tcp_stream.peer_addr().unwrap()
-                );
+impl ReverseProxyClientSubCommand {
+    fn tuning(&self) -> TransportTuning {
+        TransportTuning {
+            preset: self.transport_preset,
+            ..Default::default()
+        }
+    }
 
-                Stream::Tcp(nearby_tcp_stream)
-            } else if let Some(unix_connect) = &self.unix_connect {
-                debug!("connecting to unix socket at {}", unix_connect.display());
+    fn timeouts(&self) -> anyhow::Result<Timeouts> {
+        TimeoutOverrides {
+            idle: self.idle_timeout,
+            keep_alive: self.keep_alive_interval,
+            handshake: self.handshake_timeout,
+            ..Default::default()
+        }
+        .build()
+    }
 
-                let unix_stream = UnixStream::connect(unix_connect).await?;
+    pub async fn main(self) -> anyhow::Result<()> {
+        let target = ReverseProxyTarget::new(self.tcp_connect, self.unix_connect.clone())?;
 
-                Stream::Unix(unix_stream)
-            } else {
-                unimplemented!();
-            };
+        // connect to the QUIC endpoint on the server
+        // since the client initiates the connections, the client needs keep alive
+        let timeouts = self.timeouts()?;
+
+        let builder = EndpointBuilder::new()
+            .cert_name(&self.cert_name)
+            .cas(self.ca.clone())
+            .cert_opt(self.cert.clone())
+            .key_opt(self.key.clone())
+            .ca_key_opt(self.ca_key.clone())
+            .renew_within_days(self.renew_within_days)
+            .ca_passphrase_file_opt(self.ca_passphrase_file.clone())
+            .insecure_key_permissions(self.insecure_key_permissions)
+            .alpn_protocols(self.tunnel_name.iter().map(|x| tunnel_alpn(x)).collect())
+            .congestion_mode(self.congestion_mode)
+            .keep_alive(true)
+            .timeouts(timeouts)
+            .tuning(self.tuning())
+            .bind_opt(self.bind)
+            .pin_sha256(self.pin_sha256.clone());
+
+        // renew an expiring certificate before it's loaded
+        let mut renewal = builder.renewal(TunnelEnd::Client);
+        renewal.check().await;
+
+        let endpoint = builder.build_client()?;
+
+        let tunnel = ReverseProxyClient::new(
+            endpoint,
+            self.remote_quic_addr.clone(),
+            self.remote_name.clone(),
+            target,
+        )
+        .with_compress(self.compress)
+        .with_drain_timeout(Duration::from_secs(self.drain_timeout));
+
+        let shutdown = tunnel.shutdown_handle();
+        let endpoint = tunnel.endpoint().clone();
+        let counts = tunnel.counts().clone();
+
+        let mut admin_handle =
+            spawn_admin_socket(self.admin_socket.clone(), AdminState::new(counts.clone()));
+        let mut stats_handle = counts.spawn_stats_loop();
+
+        let mut tunnel_handle = tokio::spawn(tunnel.run());
+
+        let x = loop {
+            select! {
+                x = &mut tunnel_handle => break x,
+                x = &mut stats_handle => {
+                    info!(?x, "stats task finished");
+                }
+                x = &mut admin_handle => {
+                    info!(?x, "admin task finished");
+                }
+                _ = renewal.renewed() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                x = shutdown_signal() => {
+                    info!(?x, "shutting down");
+                }
+            }
 
-            let (remote_tx, remote_rx) = remote.accept_bi().await?;
+            shutdown.shutdown();
 
-            debug!("reverse proxy server connected to us");
+            break tunnel_handle.await;
+        };
 
-            let f =
-                copy_bidirectional_with_compression(self.compress, remote_rx, remote_tx, stream);
+        stats_handle.abort();
+        admin_handle.abort();
 
-            tokio::spawn(f.inspect_err(|err| debug!(?err, "reverse proxy client error")));
-        }
+        x?
     }
 }
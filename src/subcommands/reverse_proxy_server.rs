@@ -1,27 +1,59 @@
 use argh::FromArgs;
-use flume::Receiver;
-use futures::TryFutureExt;
-use quic_tunnel::compress::{copy_bidirectional_with_compression, CompressAlgo};
-use quic_tunnel::counters::TunnelCounters;
-use quic_tunnel::quic::{build_server_endpoint, CongestionMode};
-use quic_tunnel::stream::Stream;
-use quinn::Connecting;
+use quic_tunnel::admin::{spawn_admin_socket, AdminState};
+use quic_tunnel::certs::{TunnelEnd, DEFAULT_RENEW_WITHIN_DAYS};
+use quic_tunnel::compress::CompressAlgo;
+use quic_tunnel::config::tunnel_alpn;
+use quic_tunnel::quic::{CongestionMode, EndpointBuilder, TransportPreset, TransportTuning};
+use quic_tunnel::reload::reload_signal;
+use quic_tunnel::shutdown::{default_drain_timeout, shutdown_signal};
+use quic_tunnel::timeouts::{TimeoutOverrides, Timeouts};
+use quic_tunnel::tunnels::ReverseProxyServer;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
-use tokio::net::{TcpListener, UdpSocket, UnixListener};
 use tokio::select;
-use tokio::time::timeout;
-use tracing::{debug, error, info, trace};
+use tracing::info;
 
 /// Run the QUIC Tunnel Server.
 #[derive(Debug, FromArgs, PartialEq)]
 #[argh(subcommand, name = "reverse_proxy_server")]
 pub struct ReverseProxyServerSubCommand {
-    /// prefix for all the certificates to load
+    /// prefix for the certificates that aren't set with --ca, --cert, or --key
     #[argh(positional)]
     cert_name: String,
 
+    /// a CA that signed the other end's certificate. can be repeated. every cert in a PEM bundle is trusted. defaults to "<cert_name>_ca.pem"
+    #[argh(option)]
+    ca: Vec<PathBuf>,
+
+    /// this end's certificate, then any intermediate CAs. defaults to "<cert_name>_server.pem"
+    #[argh(option)]
+    cert: Option<PathBuf>,
+
+    /// the key for this end's certificate. defaults to "<cert_name>_server.key.pem"
+    #[argh(option)]
+    key: Option<PathBuf>,
+
+    /// a PEM file of revoked client certificates. can be repeated. defaults to "<cert_name>_crl.pem" if it exists. reloaded when it changes
+    #[argh(option)]
+    crl: Vec<PathBuf>,
+
+    /// the CA's key, for renewing this end's certificate before it expires. defaults to "<cert_name>_ca.key.pem" if it exists
+    #[argh(option)]
+    ca_key: Option<PathBuf>,
+
+    /// renew this end's certificate when it has less than this many days left (default 10)
+    #[argh(option, default = "DEFAULT_RENEW_WITHIN_DAYS")]
+    renew_within_days: u32,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+
+    /// load keys that other users can read instead of refusing to start
+    #[argh(switch)]
+    insecure_key_permissions: bool,
+
     /// the local address to listen on with QUIC. Clients connect here
     ///
     /// TODO: descriptive name
@@ -44,203 +76,139 @@ pub struct ReverseProxyServerSubCommand {
     #[argh(option, default = "CongestionMode::NewReno")]
     congestion_mode: CongestionMode,
 
+    /// tune QUIC for a kind of network: airplane, lan, or satellite. the config file can tune everything
+    #[argh(option)]
+    transport_preset: Option<TransportPreset>,
+
     /// compression mode for the QUIC tunnel.
     ///
     /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
     #[argh(option, default = "CompressAlgo::None")]
     compress: CompressAlgo,
-}
-
-impl ReverseProxyServerSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        if self.tcp_listen.is_none() && self.unix_listen.is_none() {
-            anyhow::bail!("specify tcp_listen or socket_listen or both");
-        }
-
-        let (stream_sender, stream_receiver) = flume::unbounded::<Stream>();
-
-        let ca = PathBuf::new().join(format!("{}_ca.pem", self.cert_name));
-        let cert = PathBuf::new().join(format!("{}_server.pem", self.cert_name));
-        let key = PathBuf::new().join(format!("{}_server.key.pem", self.cert_name));
 
-        let endpoint = build_server_endpoint(
-            ca,
-            cert,
-            key,
-            true,
-            self.quic_addr,
-            self.congestion_mode,
-            false,
-        )?;
-
-        info!("QUIC listening on {}", endpoint.local_addr()?);
-
-        let counts = TunnelCounters::new();
+    /// accept clients from a "run" config that have a tunnel with this name. standalone clients need the same --tunnel-name then
+    #[argh(option)]
+    tunnel_name: Option<String>,
 
-        // the tunnel handle listens on quic and forwards messages from a channel for tcp
-        // TODO: better name
-        let mut quic_endpoint_handle = {
-            let endpoint = endpoint.clone();
-            let stream_receiver = stream_receiver.clone();
-            let compression_mode = self.compress;
+    /// listen on this Unix socket for admin commands. see the "ctl" subcommand
+    #[argh(option)]
+    admin_socket: Option<PathBuf>,
 
-            let f = async move {
-                while let Some(conn) = endpoint.accept().await {
-                    let f = handle_quic_connection(conn, stream_receiver.clone(), compression_mode);
+    /// seconds to wait for open streams to finish after ctrl-c or SIGTERM
+    #[argh(option, default = "default_drain_timeout().as_secs()")]
+    drain_timeout: u64,
 
-                    // spawn to handle multiple connections at once? we only have one listener right now
-                    tokio::spawn(f.inspect_err(|err| trace!(?err, "reverse proxy tunnel closed")));
-                }
-            };
+    /// seconds without any packets before the QUIC connection is closed. default 300
+    #[argh(option)]
+    idle_timeout: Option<u64>,
 
-            // this handle isn't needed. errors are logged elsewhere
-            tokio::spawn(f)
-        };
+    /// seconds to wait for the QUIC handshake. default 30
+    #[argh(option)]
+    handshake_timeout: Option<u64>,
+}
 
-        // listens on tcp and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut tcp_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(listen_addr) = self.tcp_listen {
-                let stream_sender = stream_sender.clone();
-
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    let tcp_listener = TcpListener::bind(listen_addr).await?;
-                    info!("TCP listening on {}", tcp_listener.local_addr()?);
-
-                    loop {
-                        match tcp_listener.accept().await {
-                            Ok((stream, _)) => {
-                                // send the stream to a channel. one of multiple connections might handle it
-                                stream_sender.send_async(Stream::Tcp(stream)).await?
-                            }
-                            Err(err) => error!(?err, "tcp accept failed"),
-                        }
-                    }
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
-
-        // listens on udp and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut udp_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(listen_addr) = self.udp_listen {
-                // let stream_sender = stream_sender.clone();
-
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    let udp_socket = UdpSocket::bind(listen_addr).await?;
-                    info!("UDP listening on {}", udp_socket.local_addr()?);
-
-                    todo!("do we actually care about tunneling udp?");
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
-
-        // listens on unix socket and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut unix_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(unix_listen_path) = self.unix_listen {
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    info!("UNIX listening at {}", unix_listen_path.display());
-                    let listener = UnixListener::bind(unix_listen_path)?;
-
-                    loop {
-                        match listener.accept().await {
-                            Ok((stream, _)) => {
-                                // send the stream to a channel. one of multiple connections might handle it
-                                stream_sender.send_async(Stream::Unix(stream)).await?
-                            }
-                            Err(err) => error!(?err, "tcp accept failed"),
-                        }
-                    }
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
+impl ReverseProxyServerSubCommand {
+    fn tuning(&self) -> TransportTuning {
+        TransportTuning {
+            preset: self.transport_preset,
+            ..Default::default()
+        }
+    }
 
-        let mut stats_handle = counts.spawn_stats_loop();
+    fn timeouts(&self) -> anyhow::Result<Timeouts> {
+        TimeoutOverrides {
+            idle: self.idle_timeout,
+            handshake: self.handshake_timeout,
+            ..Default::default()
+        }
+        .build()
+    }
 
-        select! {
-            x = &mut quic_endpoint_handle => {
-                info!(?x, "tunnel task finished");
-            }
-            x = &mut tcp_listener_handle => {
-                info!(?x, "tcp task finished");
-            }
-            x = &mut udp_listener_handle => {
-                info!(?x, "udp task finished");
-            }
-            x = &mut unix_listener_handle => {
-                info!(?x, "unix task finished");
-            }
-            x = &mut stats_handle => {
-                info!(?x, "stats task finished");
-            }
+    pub async fn main(self) -> anyhow::Result<()> {
+        // TODO: do we actually care about tunneling udp?
+        if self.udp_listen.is_some() {
+            anyhow::bail!("udp_listen is not supported yet");
         }
 
-        endpoint.close(0u32.into(), b"server done");
+        let timeouts = self.timeouts()?;
+
+        // the builder reads the certificates again on SIGHUP so that renewed certificates are used for new handshakes
+        let builder = EndpointBuilder::new()
+            .cert_name(&self.cert_name)
+            .cas(self.ca.clone())
+            .cert_opt(self.cert.clone())
+            .key_opt(self.key.clone())
+            .crls(self.crl.clone())
+            .ca_key_opt(self.ca_key.clone())
+            .renew_within_days(self.renew_within_days)
+            .ca_passphrase_file_opt(self.ca_passphrase_file.clone())
+            .insecure_key_permissions(self.insecure_key_permissions)
+            .alpn_protocols(self.tunnel_name.iter().map(|x| tunnel_alpn(x)).collect())
+            .congestion_mode(self.congestion_mode)
+            .timeouts(timeouts)
+            .tuning(self.tuning())
+            .bind(self.quic_addr);
+
+        // renew an expiring certificate before it's loaded
+        let mut renewal = builder.renewal(TunnelEnd::Server);
+        renewal.check().await;
+
+        let endpoint = builder.build_server()?;
+
+        let tunnel = ReverseProxyServer::new(endpoint, self.tcp_listen, self.unix_listen)?
+            .with_compress(self.compress)
+            .with_drain_timeout(Duration::from_secs(self.drain_timeout));
+
+        let shutdown = tunnel.shutdown_handle();
+        let endpoint = tunnel.endpoint().clone();
+        let counts = tunnel.counts().clone();
+
+        let mut admin_handle =
+            spawn_admin_socket(self.admin_socket, AdminState::new(counts.clone()));
 
-        quic_endpoint_handle.abort();
-        tcp_listener_handle.abort();
-        udp_listener_handle.abort();
-        unix_listener_handle.abort();
-        stats_handle.abort();
+        let mut stats_handle = counts.spawn_stats_loop();
 
-        Ok(())
-    }
-}
+        let mut tunnel_handle = tokio::spawn(tunnel.run());
 
-async fn handle_quic_connection(
-    conn_a: Connecting,
-    rx_b: Receiver<Stream>,
-    compress_algo: CompressAlgo,
-) -> anyhow::Result<()> {
-    // TODO: are there other things I need to do to set up 0-rtt? this is copypasta
-    let conn_a = match conn_a.into_0rtt() {
-        Ok((conn_a, _)) => {
-            trace!("0-rtt accepted");
-            conn_a
-        }
-        Err(conn_a) => timeout(Duration::from_secs(30), conn_a).await??,
-    };
+        let mut reload = reload_signal()?;
 
-    // TODO: look at the handshake data to figure out what client connected? that way we know what TcpListener to connect it to?
+        let mut file_watch = endpoint.file_watch();
 
-    loop {
-        while let Ok(stream_b) = rx_b.recv_async().await {
-            debug!(?stream_b, "user connected");
+        let x = loop {
+            select! {
+                x = &mut tunnel_handle => break x,
+                x = &mut stats_handle => {
+                    info!(?x, "stats task finished");
+                }
+                x = &mut admin_handle => {
+                    info!(?x, "admin task finished");
+                }
+                _ = reload.recv() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                _ = file_watch.changed() => {
+                    info!("certificate files changed");
+                    endpoint.reload().await;
+                    continue;
+                }
+                _ = renewal.renewed() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                x = shutdown_signal() => {
+                    info!(?x, "shutting down");
+                }
+            }
 
-            // each new TCP stream gets a new QUIC stream
-            let (tx_a, rx_a) = conn_a.open_bi().await?;
+            shutdown.shutdown();
 
-            trace!("reverse proxy stream opened");
+            break tunnel_handle.await;
+        };
 
-            // TODO: counters while the stream happens
-            let f = copy_bidirectional_with_compression(compress_algo, rx_a, tx_a, stream_b);
+        stats_handle.abort();
+        admin_handle.abort();
 
-            // spawn to handle multiple requests at once
-            tokio::spawn(
-                f.inspect_err(|e| {
-                    error!("failed: {}", e);
-                })
-                .inspect_ok(|(a_to_b, b_to_a)| trace!(%a_to_b, %b_to_a, "success")),
-            );
-        }
+        x?
     }
 }
@@ -0,0 +1,706 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use argh::FromArgs;
+use flume::Receiver;
+use futures::TryFutureExt;
+use moka::future::CacheBuilder;
+use quic_tunnel::admin::{spawn_admin_socket, AdminState};
+use quic_tunnel::certs::TunnelEnd;
+use quic_tunnel::compress::CompressAlgo;
+use quic_tunnel::config::{tunnel_alpn, TunnelConfig, TunnelsConfig};
+use quic_tunnel::counters::CountersRegistry;
+use quic_tunnel::net::{bind_tokio_udp, default_bind_address};
+use quic_tunnel::quic::{accept, bind_endpoint, connect_remote, EndpointBuilder};
+use quic_tunnel::reload::{reload_client_config, reload_server_config, reload_signal, FileWatch};
+use quic_tunnel::renew::Renewal;
+use quic_tunnel::resolve::{reconnect, SystemResolver};
+use quic_tunnel::shutdown::{default_drain_timeout, drain, shutdown_signal};
+use quic_tunnel::stream::Stream;
+use quic_tunnel::timeouts::Timeouts;
+use quic_tunnel::tunnels::{
+    evict_idle_flows, finish_flows, forward_streams, handle_connection, handle_quic_connection,
+    spawn_tcp_listener, spawn_unix_listener, tunnel_udp_to_endpoint, ReverseProxyTarget,
+};
+use quic_tunnel::{events, TunnelCache};
+use quinn::{ClientConfig, Connecting, Connection, Endpoint, ServerConfig};
+use tokio::select;
+use tokio::task::JoinHandle;
+use tracing::{error, info, trace, warn};
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "run")]
+/// Run every tunnel in a config file from one process and one QUIC endpoint.
+///
+/// Send SIGHUP to reload the config file and certificates. Tunnels that didn't change keep their connections.
+pub struct RunSubCommand {
+    /// the TOML file describing the endpoint and tunnels
+    #[argh(option)]
+    config: PathBuf,
+}
+
+/// the connections that belong to one tunnel. closed when the tunnel is removed
+type TunnelConnections = Arc<Mutex<HashMap<usize, Connection>>>;
+
+/// where to send connections that arrive on the shared endpoint
+enum ServerTunnel {
+    Udp(SocketAddr),
+    ReverseProxy(Receiver<Stream>, CompressAlgo),
+}
+
+struct ServerRoute {
+    tunnel: ServerTunnel,
+    connections: TunnelConnections,
+}
+
+/// ALPN protocol -> server tunnel
+type ServerRoutes = Arc<RwLock<HashMap<Vec<u8>, Arc<ServerRoute>>>>;
+
+/// A tunnel from the config file and everything needed to stop it.
+struct RunningTunnel {
+    config: TunnelConfig,
+    /// tasks that accept new users or connections
+    handles: Vec<JoinHandle<anyhow::Result<()>>>,
+    cache: Option<TunnelCache>,
+    connections: TunnelConnections,
+    /// for client tunnels. shared with the task so that renewed certificates are used when it reconnects
+    client_config: Option<Arc<RwLock<ClientConfig>>>,
+}
+
+/// Everything the tunnels on the shared endpoint need.
+struct Tunnels {
+    config: TunnelsConfig,
+    endpoint: Endpoint,
+    /// from [`bind_endpoint`]
+    dual_stack: bool,
+    counts: Arc<CountersRegistry>,
+    admin_state: AdminState,
+    routes: ServerRoutes,
+    /// the top level timeouts. used for handshakes before we know which tunnel a connection is for
+    server_timeouts: Arc<RwLock<Timeouts>>,
+    running: BTreeMap<String, RunningTunnel>,
+    /// false until the first config is applied
+    started: bool,
+}
+
+impl RunSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        let config = TunnelsConfig::load(&self.config)?;
+
+        // renew expiring certificates before they're loaded
+        client_renewal(&config).check().await;
+        server_renewal(&config).check().await;
+
+        let (endpoint, dual_stack) = if let Some(listen) = config.endpoint.listen {
+            let x = bind_endpoint(listen, Some(server_config(&config).await?))?;
+
+            info!("QUIC listening on {}", x.0.local_addr()?);
+
+            x
+        } else {
+            let bind = config.endpoint.bind.unwrap_or_else(default_bind_address);
+
+            bind_endpoint(bind, None)?
+        };
+
+        let server_timeouts = Arc::new(RwLock::new(config.timeouts()));
+
+        let counts = CountersRegistry::new(config.timeouts().udp_flow_idle);
+
+        let admin_state = AdminState::new(counts.clone());
+
+        let mut tunnels = Tunnels {
+            config: Default::default(),
+            endpoint: endpoint.clone(),
+            dual_stack,
+            counts: counts.clone(),
+            admin_state: admin_state.clone(),
+            routes: Default::default(),
+            server_timeouts: server_timeouts.clone(),
+            running: Default::default(),
+            started: false,
+        };
+
+        tunnels.apply(config).await?;
+
+        let accept_handle = {
+            let endpoint = endpoint.clone();
+            let routes = tunnels.routes.clone();
+            let counts = counts.clone();
+
+            tokio::spawn(async move {
+                while let Some(conn) = endpoint.accept().await {
+                    let handshake_timeout = server_timeouts.read().unwrap().handshake;
+
+                    let f = dispatch_connection(
+                        conn,
+                        handshake_timeout,
+                        routes.clone(),
+                        counts.clone(),
+                    );
+
+                    // spawn to handle multiple connections at once
+                    tokio::spawn(f.inspect_err(|err| trace!(?err, "connection closed")));
+                }
+            })
+        };
+
+        let mut admin_handle = spawn_admin_socket(tunnels.config.admin_socket.clone(), admin_state);
+
+        let mut stats_handle = counts.clone().spawn_stats_loop();
+
+        let mut reload = reload_signal()?;
+
+        let mut file_watch = server_file_watch(&tunnels.config);
+
+        let mut renewal = server_renewal(&tunnels.config);
+
+        let mut client_cert_renewal = client_renewal(&tunnels.config);
+
+        // one broken tunnel shouldn't stop the others. they log their own errors
+        loop {
+            select! {
+                x = &mut stats_handle => {
+                    info!(?x, "stats task finished");
+                }
+                x = &mut admin_handle => {
+                    info!(?x, "admin task finished");
+                }
+                _ = reload.recv() => {
+                    info!(config = %self.config.display(), "reloading");
+
+                    match TunnelsConfig::load(&self.config) {
+                        Ok(config) => {
+                            if let Err(err) = tunnels.apply(config).await {
+                                error!(?err, "reload failed. keeping the old config");
+                            }
+                        }
+                        Err(err) => error!(?err, "reload failed. keeping the old config"),
+                    }
+
+                    file_watch = server_file_watch(&tunnels.config);
+                    renewal = server_renewal(&tunnels.config);
+                    client_cert_renewal = client_renewal(&tunnels.config);
+
+                    continue;
+                }
+                _ = file_watch.changed() => {
+                    info!("certificate files changed");
+
+                    reload_server_config(&endpoint, server_config(&tunnels.config).await);
+
+                    continue;
+                }
+                // the client and server can share a certificate file. reload both
+                _ = renewal.renewed() => {
+                    tunnels.reload_certificates().await;
+
+                    continue;
+                }
+                _ = client_cert_renewal.renewed() => {
+                    tunnels.reload_certificates().await;
+
+                    continue;
+                }
+                x = shutdown_signal() => {
+                    info!(?x, "shutting down");
+                }
+            }
+
+            break;
+        }
+
+        let drain_timeout = tunnels
+            .config
+            .drain_timeout
+            .map(Duration::from_secs)
+            .unwrap_or_else(default_drain_timeout);
+
+        // stop accepting users. streams that are already open get to finish
+        for x in tunnels.running.values() {
+            for handle in x.handles.iter() {
+                handle.abort();
+            }
+
+            if let Some(cache) = x.cache.as_ref() {
+                finish_flows(cache).await;
+            }
+        }
+
+        drain(&endpoint, &counts, drain_timeout).await;
+
+        accept_handle.abort();
+        stats_handle.abort();
+        admin_handle.abort();
+
+        Ok(())
+    }
+}
+
+impl Tunnels {
+    /// Start, stop, and restart tunnels so that they match `config`.
+    ///
+    /// Tunnels that didn't change are left alone. Client tunnels are restarted if `[endpoint]`, `[transport]`, or `[timeouts]` changed.
+    /// Server tunnels get those with the new server config. If the certificates can't be loaded, nothing is changed.
+    async fn apply(&mut self, config: TunnelsConfig) -> anyhow::Result<()> {
+        // build this first so that broken certificates don't leave us half reloaded
+        // the endpoint was built with the first config, so only reloads need this
+        let server_config = match config.endpoint.listen.filter(|_| self.started) {
+            Some(_) => Some(server_config(&config).await?),
+            None => None,
+        };
+
+        if self.started && config.endpoint.listen != self.config.endpoint.listen {
+            warn!(
+                old = ?self.config.endpoint.listen,
+                new = ?config.endpoint.listen,
+                "endpoint.listen can't change without a restart"
+            );
+        }
+
+        if self.started && config.admin_socket != self.config.admin_socket {
+            warn!("admin_socket can't change without a restart");
+        }
+
+        let new_tunnels = config.tunnels();
+
+        // client tunnels only read these when they connect
+        let clients_changed = self.started
+            && (config.endpoint != self.config.endpoint
+                || config.transport != self.config.transport
+                || config.timeouts != self.config.timeouts);
+
+        if clients_changed {
+            info!("endpoint, transport, or timeouts changed. restarting client tunnels");
+        }
+
+        let old_names: Vec<_> = self.running.keys().cloned().collect();
+
+        // stop tunnels that were removed or changed
+        for name in old_names {
+            let old = self.running.get(&name).map(|x| &x.config);
+
+            let restart = clients_changed && old.is_some_and(|x| x.is_client());
+
+            if new_tunnels.get(&name) != old || restart {
+                self.stop(&name).await;
+            }
+        }
+
+        *self.server_timeouts.write().unwrap() = config.timeouts();
+
+        self.config = config;
+
+        // start tunnels that were added or changed
+        for (name, tunnel_config) in new_tunnels {
+            if self.running.contains_key(&name) {
+                trace!(name, "unchanged");
+                continue;
+            }
+
+            // one bad tunnel shouldn't stop the others from starting
+            match self.start(tunnel_config).await {
+                Ok(x) => {
+                    self.running.insert(name, x);
+                }
+                Err(err) => error!(name, ?err, "failed starting tunnel"),
+            }
+        }
+
+        // new handshakes get the new certificates and ALPN protocols
+        if let Some(server_config) = server_config {
+            reload_server_config(&self.endpoint, Ok(server_config));
+        }
+
+        self.started = true;
+
+        Ok(())
+    }
+
+    async fn start(&self, config: TunnelConfig) -> anyhow::Result<RunningTunnel> {
+        let connections = TunnelConnections::default();
+
+        let mut handles = vec![];
+
+        let mut cache = None;
+
+        let mut shared_client_config = None;
+
+        match &config {
+            TunnelConfig::UdpServer(x) => {
+                info!(name = x.name, "forwarding UDP to {}", x.forward);
+
+                self.add_route(&x.name, ServerTunnel::Udp(x.forward), &connections);
+            }
+            TunnelConfig::ReverseProxyServer(x) => {
+                let (stream_sender, stream_receiver) = flume::unbounded::<Stream>();
+
+                handles.push(spawn_tcp_listener(x.tcp_listen, stream_sender.clone()));
+                handles.push(spawn_unix_listener(x.unix_listen.clone(), stream_sender));
+
+                self.add_route(
+                    &x.name,
+                    ServerTunnel::ReverseProxy(stream_receiver, x.compress),
+                    &connections,
+                );
+            }
+            TunnelConfig::UdpClient(x) => {
+                let timeouts = self.config.tunnel_timeouts(&x.timeouts);
+
+                let (client_config, server_name) =
+                    client_config(&self.config, &x.name, &timeouts).await?;
+
+                let server_name = x.server_name.clone().or(server_name);
+
+                let client_config = Arc::new(RwLock::new(client_config));
+
+                shared_client_config = Some(client_config.clone());
+
+                let tunnel_cache: TunnelCache = CacheBuilder::new(10_000)
+                    .time_to_idle(timeouts.udp_flow_idle)
+                    .build();
+
+                self.admin_state
+                    .add_tunnel_cache(&x.name, tunnel_cache.clone());
+
+                cache = Some(tunnel_cache.clone());
+
+                handles.push(tokio::spawn(evict_idle_flows(tunnel_cache.clone())));
+
+                let endpoint = self.endpoint.clone();
+                let dual_stack = self.dual_stack;
+                let counts = self.counts.clone();
+                let connections = connections.clone();
+                let x = x.clone();
+
+                let f = async move {
+                    let connect = || {
+                        connect_remote(
+                            &endpoint,
+                            dual_stack,
+                            Some(client_config.read().unwrap().clone()),
+                            &x.remote,
+                            server_name.as_deref(),
+                            &SystemResolver,
+                            timeouts.handshake,
+                        )
+                    };
+
+                    let mut connected = connect().await?;
+
+                    let local_socket = Arc::new(bind_tokio_udp(x.listen)?);
+
+                    loop {
+                        let (remote, zero_rtt) = connected;
+
+                        events::connection_opened(&remote, zero_rtt);
+
+                        connections
+                            .lock()
+                            .unwrap()
+                            .insert(remote.stable_id(), remote.clone());
+
+                        info!(
+                            name = x.name,
+                            "Forwarding {} through QUIC tunnel at {}",
+                            local_socket.local_addr()?,
+                            remote.remote_address()
+                        );
+
+                        let forward = tunnel_udp_to_endpoint(
+                            local_socket.clone(),
+                            remote.clone(),
+                            tunnel_cache.clone(),
+                            counts.clone(),
+                        );
+
+                        select! {
+                            x = forward => return x,
+                            reason = remote.closed() => events::connection_closed(&remote, &reason),
+                        }
+
+                        connections.lock().unwrap().remove(&remote.stable_id());
+                        counts.remove_connection(&remote).await;
+
+                        // their streams were on the old connection
+                        tunnel_cache.invalidate_all();
+
+                        info!(name = x.name, "reconnecting to {}", x.remote);
+
+                        connected = reconnect(&connect).await;
+                    }
+                };
+
+                handles.push(tokio::spawn(
+                    f.inspect_err(|err| error!(?err, "udp client failed")),
+                ));
+            }
+            TunnelConfig::ReverseProxyClient(x) => {
+                let timeouts = self.config.tunnel_timeouts(&x.timeouts);
+
+                let (client_config, server_name) =
+                    client_config(&self.config, &x.name, &timeouts).await?;
+
+                let server_name = x.server_name.clone().or(server_name);
+
+                let client_config = Arc::new(RwLock::new(client_config));
+
+                shared_client_config = Some(client_config.clone());
+
+                let target = ReverseProxyTarget::new(x.tcp_connect, x.unix_connect.clone())?;
+
+                let endpoint = self.endpoint.clone();
+                let dual_stack = self.dual_stack;
+                let counts = self.counts.clone();
+                let connections = connections.clone();
+                let x = x.clone();
+
+                let f = async move {
+                    let connect = || {
+                        connect_remote(
+                            &endpoint,
+                            dual_stack,
+                            Some(client_config.read().unwrap().clone()),
+                            &x.remote,
+                            server_name.as_deref(),
+                            &SystemResolver,
+                            timeouts.handshake,
+                        )
+                    };
+
+                    let mut connected = connect().await?;
+
+                    loop {
+                        let (remote, zero_rtt) = connected;
+
+                        info!(
+                            name = x.name,
+                            "connected to QUIC server at {}",
+                            remote.remote_address()
+                        );
+
+                        events::connection_opened(&remote, zero_rtt);
+
+                        connections
+                            .lock()
+                            .unwrap()
+                            .insert(remote.stable_id(), remote.clone());
+
+                        let connection_counts = counts.connection(&remote).await;
+
+                        let result =
+                            forward_streams(&remote, &connection_counts, &target, x.compress).await;
+
+                        // other errors, like the nearby service being down, still stop the tunnel
+                        if remote.close_reason().is_none() {
+                            return result;
+                        }
+
+                        connections.lock().unwrap().remove(&remote.stable_id());
+                        counts.remove_connection(&remote).await;
+
+                        info!(name = x.name, "reconnecting to {}", x.remote);
+
+                        connected = reconnect(&connect).await;
+                    }
+                };
+
+                handles.push(tokio::spawn(
+                    f.inspect_err(|err| error!(?err, "reverse proxy client failed")),
+                ));
+            }
+        }
+
+        Ok(RunningTunnel {
+            config,
+            handles,
+            cache,
+            connections,
+            client_config: shared_client_config,
+        })
+    }
+
+    /// Read the certificates again. New handshakes and client reconnects use them. Open connections are not dropped.
+    async fn reload_certificates(&self) {
+        if self.config.endpoint.listen.is_some() {
+            reload_server_config(&self.endpoint, server_config(&self.config).await);
+        }
+
+        for (name, x) in self.running.iter() {
+            let (Some(shared), Some(overrides)) = (x.client_config.as_ref(), x.config.timeouts())
+            else {
+                continue;
+            };
+
+            let timeouts = self.config.tunnel_timeouts(overrides);
+
+            let client_config = client_builder(&self.config, name, &timeouts)
+                .client_config_async()
+                .await;
+
+            reload_client_config(shared, client_config);
+        }
+    }
+
+    fn add_route(&self, name: &str, tunnel: ServerTunnel, connections: &TunnelConnections) {
+        let route = ServerRoute {
+            tunnel,
+            connections: connections.clone(),
+        };
+
+        self.routes
+            .write()
+            .unwrap()
+            .insert(tunnel_alpn(name), Arc::new(route));
+    }
+
+    /// Stop a tunnel and close its connections. Other tunnels are not touched.
+    async fn stop(&mut self, name: &str) {
+        let Some(x) = self.running.remove(name) else {
+            return;
+        };
+
+        info!(name, "stopping tunnel");
+
+        for handle in x.handles {
+            handle.abort();
+        }
+
+        self.routes.write().unwrap().remove(&tunnel_alpn(name));
+
+        let connections: Vec<_> = x.connections.lock().unwrap().drain().collect();
+
+        // the aborted tasks can't forget their connections themselves
+        for (_, conn) in connections {
+            conn.close(0u32.into(), b"tunnel removed");
+
+            self.counts.remove_connection(&conn).await;
+        }
+
+        if let Some(cache) = x.cache {
+            self.admin_state.remove_tunnel_cache(name);
+
+            // the connection is closed. there's nothing to finish
+            cache.invalidate_all();
+        }
+    }
+}
+
+/// certificates and transport settings shared by every tunnel
+fn endpoint_builder(config: &TunnelsConfig, timeouts: &Timeouts) -> EndpointBuilder {
+    EndpointBuilder::new()
+        .cert_name_opt(config.endpoint.cert_name.as_ref())
+        .cas(config.endpoint.ca.clone())
+        .client_cas(config.endpoint.client_ca.clone())
+        .server_cas(config.endpoint.server_ca.clone())
+        .crls(config.endpoint.crl.clone())
+        .cert_opt(config.endpoint.cert.clone())
+        .key_opt(config.endpoint.key.clone())
+        .transport_config(config.transport.build(timeouts))
+        .timeouts(*timeouts)
+        .stateless_retry(config.endpoint.stateless_retry)
+        .pin_sha256(config.endpoint.pin_sha256.clone())
+        .ca_key_opt(config.endpoint.ca_key.clone())
+        .renew_within_days(config.endpoint.renew_within_days)
+        .ca_passphrase_file_opt(config.endpoint.ca_passphrase_file.clone())
+        .insecure_key_permissions(config.endpoint.insecure_key_permissions)
+        .key_algo_opt(config.endpoint.key_alg)
+}
+
+/// the server's certificate, key, and CRL files. Nothing if there are no server tunnels
+fn server_file_watch(config: &TunnelsConfig) -> FileWatch {
+    let paths = match config.endpoint.listen {
+        Some(_) => endpoint_builder(config, &config.timeouts()).watched_paths(TunnelEnd::Server),
+        None => vec![],
+    };
+
+    FileWatch::new(paths)
+}
+
+/// renews the client's certificate. Disabled if there are no client tunnels
+fn client_renewal(config: &TunnelsConfig) -> Renewal {
+    match config.has_clients() {
+        true => endpoint_builder(config, &config.timeouts()).renewal(TunnelEnd::Client),
+        false => Renewal::disabled(),
+    }
+}
+
+/// renews the server's certificate. Disabled if there are no server tunnels
+fn server_renewal(config: &TunnelsConfig) -> Renewal {
+    match config.endpoint.listen {
+        Some(_) => endpoint_builder(config, &config.timeouts()).renewal(TunnelEnd::Server),
+        None => Renewal::disabled(),
+    }
+}
+
+async fn server_config(config: &TunnelsConfig) -> anyhow::Result<ServerConfig> {
+    endpoint_builder(config, &config.timeouts())
+        .alpn_protocols(config.server_alpn_protocols())
+        .server_config_async()
+        .await
+}
+
+/// the endpoint builder for one client tunnel
+fn client_builder(config: &TunnelsConfig, name: &str, timeouts: &Timeouts) -> EndpointBuilder {
+    endpoint_builder(config, timeouts).alpn_protocols(vec![tunnel_alpn(name)])
+}
+
+/// also returns the server name to use if the tunnel didn't set one
+async fn client_config(
+    config: &TunnelsConfig,
+    name: &str,
+    timeouts: &Timeouts,
+) -> anyhow::Result<(ClientConfig, Option<String>)> {
+    let builder = client_builder(config, name, timeouts);
+
+    Ok((
+        builder.client_config_async().await?,
+        builder.expected_server_name_async().await,
+    ))
+}
+
+/// send a connection to the tunnel that matches its ALPN protocol
+async fn dispatch_connection(
+    conn: Connecting,
+    handshake_timeout: Duration,
+    routes: ServerRoutes,
+    counts: Arc<CountersRegistry>,
+) -> anyhow::Result<()> {
+    let (conn, zero_rtt) = accept(conn, handshake_timeout).await?;
+
+    let protocol = conn
+        .handshake_data()
+        .context("no handshake data")?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .map_err(|_| anyhow::anyhow!("unexpected handshake data"))?
+        .protocol
+        .context("no ALPN protocol. standalone clients need --tunnel-name")?;
+
+    let route = routes.read().unwrap().get(&protocol).cloned();
+
+    let Some(route) = route else {
+        // the tunnel was removed by a reload while this connection was in its handshake
+        warn!(protocol = %String::from_utf8_lossy(&protocol), "unknown tunnel");
+        conn.close(0u32.into(), b"unknown tunnel");
+        return Ok(());
+    };
+
+    let id = conn.stable_id();
+
+    route.connections.lock().unwrap().insert(id, conn.clone());
+
+    let x = match &route.tunnel {
+        ServerTunnel::Udp(addr_b) => handle_connection(conn, zero_rtt, *addr_b, counts).await,
+        ServerTunnel::ReverseProxy(rx_b, compress_algo) => {
+            handle_quic_connection(conn, zero_rtt, rx_b.clone(), *compress_algo, counts).await
+        }
+    };
+
+    route.connections.lock().unwrap().remove(&id);
+
+    x
+}
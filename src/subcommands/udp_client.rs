@@ -1,19 +1,17 @@
-//! TODO: helper for setting routes so that the WireGuard VPN doesn't try to take over the udp tunnel.
-//! TODO: refactor this so that the udp and related cache is inside a single StatefulUdpSomething struct.
-
-use anyhow::Context;
 use argh::FromArgs;
-use moka::future::CacheBuilder;
 use quic_tunnel::{
-    counters::TunnelCounters,
-    get_tunnel_timeout,
-    quic::{build_client_endpoint, CongestionMode},
-    TunnelCache, TunnelCacheKey,
+    admin::{spawn_admin_socket, AdminState},
+    certs::{SpkiPin, TunnelEnd, DEFAULT_RENEW_WITHIN_DAYS},
+    config::tunnel_alpn,
+    quic::{CongestionMode, EndpointBuilder, TransportPreset, TransportTuning},
+    resolve::RemoteAddr,
+    shutdown::{default_drain_timeout, shutdown_signal},
+    timeouts::{TimeoutOverrides, Timeouts},
+    tunnels::UdpTunnelClient,
 };
-use quinn::Connection;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
-use tokio::{net::UdpSocket, select, sync::Mutex, time::timeout};
-use tracing::{debug, error, info, trace};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tokio::select;
+use tracing::info;
 
 #[derive(Debug, FromArgs, PartialEq)]
 #[argh(subcommand, name = "udp_client")]
@@ -21,207 +19,187 @@ use tracing::{debug, error, info, trace};
 ///
 /// For improving connections with packet loss, this is the process that tunnels the WireGuard connection to the server.
 pub struct UdpClientSubCommand {
-    /// prefix for all the certificates to load
+    /// prefix for the certificates that aren't set with --ca, --cert, or --key
     #[argh(positional)]
     cert_name: String,
 
+    /// a CA that signed the other end's certificate. can be repeated. every cert in a PEM bundle is trusted. defaults to "<cert_name>_ca.pem"
+    #[argh(option)]
+    ca: Vec<PathBuf>,
+
+    /// this end's certificate, then any intermediate CAs. defaults to "<cert_name>_client.pem"
+    #[argh(option)]
+    cert: Option<PathBuf>,
+
+    /// the key for this end's certificate. defaults to "<cert_name>_client.key.pem"
+    #[argh(option)]
+    key: Option<PathBuf>,
+
+    /// the CA's key, for renewing this end's certificate before it expires. defaults to "<cert_name>_ca.key.pem" if it exists
+    #[argh(option)]
+    ca_key: Option<PathBuf>,
+
+    /// renew this end's certificate when it has less than this many days left (default 10)
+    #[argh(option, default = "DEFAULT_RENEW_WITHIN_DAYS")]
+    renew_within_days: u32,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+
+    /// load keys that other users can read instead of refusing to start
+    #[argh(switch)]
+    insecure_key_permissions: bool,
+
     /// the local address to listen on
     #[argh(positional)]
     local_addr: SocketAddr,
 
-    /// the remote server to connect to
+    /// the remote server to connect to. host:port or ip:port
     #[argh(positional)]
-    remote_addr: SocketAddr,
+    remote_addr: RemoteAddr,
 
     /// the name on the remote server's certificate.
     ///
-    /// this is not read from the cert because thats a blob of hex and we want you to be able to specify it.
+    /// defaults to a "server_name:" line in the CA file, then the name on the "_server.pem" cert for cert_name if it is here, then the host or IP address in remote_addr.
     #[argh(positional)]
-    remote_name: String,
+    remote_name: Option<String>,
+
+    /// trust the server by the SHA-256 of its public key (sha256/<base64>) instead of by the CA and its name. can be repeated
+    #[argh(option)]
+    pin_sha256: Vec<SpkiPin>,
+
+    /// the local address for the QUIC endpoint. defaults to [::]:0, which also reaches IPv4 servers
+    #[argh(option)]
+    bind: Option<SocketAddr>,
 
     /// congestion mode for QUIC
     #[argh(option, default = "Default::default()")]
     congestion_mode: CongestionMode,
-}
 
-impl UdpClientSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
-        let cert = PathBuf::from(format!("{}_client.pem", self.cert_name));
-        let key = PathBuf::from(format!("{}_client.key.pem", self.cert_name));
-
-        // connect to the remote server
-        let endpoint = build_client_endpoint(ca, cert, key, self.congestion_mode, true)?;
+    /// tune QUIC for a kind of network: airplane, lan, or satellite. the config file can tune everything
+    #[argh(option)]
+    transport_preset: Option<TransportPreset>,
 
-        let connecting = endpoint.connect(self.remote_addr, &self.remote_name)?;
+    /// the name of a tunnel in a "run" config on the server. leave it out for the udp_server and reverse_proxy_server subcommands unless they have the same --tunnel-name
+    #[argh(option)]
+    tunnel_name: Option<String>,
 
-        let remote = timeout(Duration::from_secs(30), connecting).await??;
+    /// listen on this Unix socket for admin commands. see the "ctl" subcommand
+    #[argh(option)]
+    admin_socket: Option<PathBuf>,
 
-        // TODO: this connection doesn't seem to have keep alive even though I turned it on in the server endpoint.
-        // TODO: if this connection isn't used soon, the
-
-        info!(
-            "Forwarding {} through QUIC tunnel at {}",
-            self.local_addr,
-            remote.remote_address()
-        );
+    /// seconds to wait for open streams to finish after ctrl-c or SIGTERM
+    #[argh(option, default = "default_drain_timeout().as_secs()")]
+    drain_timeout: u64,
 
-        let counts = TunnelCounters::new();
+    /// seconds without any packets before the QUIC connection is closed. default 300
+    #[argh(option)]
+    idle_timeout: Option<u64>,
 
-        let timeout = get_tunnel_timeout();
+    /// seconds between keep-alive packets. default a third of the idle timeout
+    #[argh(option)]
+    keep_alive_interval: Option<u64>,
 
-        let cache: TunnelCache = CacheBuilder::new(10_000).time_to_idle(timeout).build();
+    /// seconds without any packets before a UDP flow is forgotten. defaults to the idle timeout. DNS only needs a few
+    #[argh(option)]
+    udp_flow_idle: Option<u64>,
 
-        // listen on UDP
-        let local_socket = UdpSocket::bind(self.local_addr).await?;
+    /// seconds to wait for the QUIC handshake. default 30
+    #[argh(option)]
+    handshake_timeout: Option<u64>,
+}
 
-        trace!(?local_socket);
+impl UdpClientSubCommand {
+    fn tuning(&self) -> TransportTuning {
+        TransportTuning {
+            preset: self.transport_preset,
+            ..Default::default()
+        }
+    }
 
-        let local_socket = Arc::new(local_socket);
+    fn timeouts(&self) -> anyhow::Result<Timeouts> {
+        TimeoutOverrides {
+            idle: self.idle_timeout,
+            keep_alive: self.keep_alive_interval,
+            udp_flow_idle: self.udp_flow_idle,
+            handshake: self.handshake_timeout,
+        }
+        .build()
+    }
 
-        let mut tunnel_handle = tokio::spawn(tunnel_udp_to_endpoint(
-            local_socket,
-            remote,
-            cache,
-            counts.clone(),
-        ));
+    pub async fn main(self) -> anyhow::Result<()> {
+        let timeouts = self.timeouts()?;
+
+        let builder = EndpointBuilder::new()
+            .cert_name(&self.cert_name)
+            .cas(self.ca.clone())
+            .cert_opt(self.cert.clone())
+            .key_opt(self.key.clone())
+            .ca_key_opt(self.ca_key.clone())
+            .renew_within_days(self.renew_within_days)
+            .ca_passphrase_file_opt(self.ca_passphrase_file.clone())
+            .insecure_key_permissions(self.insecure_key_permissions)
+            .alpn_protocols(self.tunnel_name.iter().map(|x| tunnel_alpn(x)).collect())
+            .congestion_mode(self.congestion_mode)
+            .timeouts(timeouts)
+            .tuning(self.tuning())
+            .bind_opt(self.bind)
+            .pin_sha256(self.pin_sha256.clone());
+
+        // renew an expiring certificate before it's loaded
+        let mut renewal = builder.renewal(TunnelEnd::Client);
+        renewal.check().await;
+
+        let endpoint = builder.build_client()?;
+
+        let tunnel = UdpTunnelClient::new(
+            endpoint,
+            self.local_addr,
+            self.remote_addr,
+            self.remote_name,
+        )
+        .with_drain_timeout(Duration::from_secs(self.drain_timeout));
+
+        let shutdown = tunnel.shutdown_handle();
+        let endpoint = tunnel.endpoint().clone();
+        let counts = tunnel.counts().clone();
+
+        let mut admin_handle = spawn_admin_socket(
+            self.admin_socket,
+            AdminState::new(counts.clone()).with_tunnel_cache("udp_client", tunnel.cache().clone()),
+        );
 
         let mut stats_handle = counts.spawn_stats_loop();
 
-        // TODO: if our network changes, rebind the endpoint to a new udp socket
+        let mut tunnel_handle = tokio::spawn(tunnel.run());
 
-        select! {
-            x = &mut tunnel_handle => {
-                info!(?x, "local task finished");
-            }
-            x = &mut stats_handle => {
-                info!(?x, "stats task finished");
+        let x = loop {
+            select! {
+                x = &mut tunnel_handle => break x,
+                x = &mut stats_handle => {
+                    info!(?x, "stats task finished");
+                }
+                x = &mut admin_handle => {
+                    info!(?x, "admin task finished");
+                }
+                _ = renewal.renewed() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                x = shutdown_signal() => {
+                    info!(?x, "shutting down");
+                }
             }
-        }
 
-        tunnel_handle.abort();
-        stats_handle.abort();
+            shutdown.shutdown();
 
-        endpoint.close(0u32.into(), b"client done");
+            break tunnel_handle.await;
+        };
 
-        Ok(())
-    }
-}
+        stats_handle.abort();
+        admin_handle.abort();
 
-/// copy things on socket to endpoint and save the from address.
-/// then spawn a task that reads from the endpoint and sends everything to socket_a and the saved from address.
-async fn tunnel_udp_to_endpoint(
-    socket_a: Arc<UdpSocket>,
-    connection_b: Connection,
-    cache: TunnelCache,
-    counts: Arc<TunnelCounters>,
-) -> anyhow::Result<()> {
-    loop {
-        socket_a.readable().await?;
-
-        let max_size = connection_b.max_datagram_size().unwrap_or(8096);
-
-        // The buffer is **not** included in the async task and will only exist on the stack.
-        // TODO: what size should this buffer be?
-        // TODO: do this without allocating
-        let mut data = Vec::with_capacity(max_size);
-
-        match socket_a.try_recv_buf_from(&mut data) {
-            Ok((n, from)) => {
-                let addr_a = socket_a.local_addr().unwrap();
-                let addr_b = connection_b.remote_address();
-
-                debug!("sending {n} bytes from {from} @ {addr_a:?} over QUIC tunnel to {addr_b}");
-
-                // don't bind every time, re-use existing sockets if they are for the local and remote addresses
-                let cache_key = TunnelCacheKey {
-                    addr_a,
-                    from,
-                    addr_b,
-                };
-
-                let connection_b = connection_b.clone();
-
-                let (tx_b, rx_b) = cache
-                    .try_get_with(cache_key, async move {
-                        let (tx_b, rx_b) = connection_b.open_bi().await?;
-
-                        let tx_b = Arc::new(Mutex::new(tx_b));
-                        let rx_b = Arc::new(Mutex::new(Some(rx_b)));
-
-                        Ok::<_, anyhow::Error>((tx_b, rx_b))
-                    })
-                    .await
-                    .map_err(|e| anyhow::anyhow!("cache error: {}", e))?;
-
-                let mut lock_tx_b = tx_b.lock().await;
-
-                // TODO: we don't actually take advantage of quic's multiplexing. this could add the destination address and the server could have a mapping
-                // TODO: we would probably want to be able to listen on multiple ports then too
-                let tx = lock_tx_b.write_all(&data[..n]).await;
-
-                drop(lock_tx_b);
-
-                match tx {
-                    Ok(()) => {
-                        counts.sent(n, 0);
-                        let socket_a = socket_a.clone();
-                        let counts = counts.clone();
-
-                        // we only need to rx once
-                        if let Some(mut rx) = rx_b.lock().await.take() {
-                            // wait for socket_b to receive something or close
-                            tokio::spawn(async move {
-                                // TODO: we need tokio_util::UdpFramed for this
-                                // io::copy(&mut rx, &mut socket_a).await?;
-
-                                let mut buf = [0; 8096];
-
-                                loop {
-                                    // TODO: what should udp timeout be?
-                                    // TODO: what should the max size be?
-                                    match rx.read(&mut buf).await {
-                                        Ok(Some(n)) => {
-                                            debug!("received {n} bytes from {addr_b} for {from} @ {addr_a:?}");
-
-                                            if let Err(e) = socket_a
-                                                .send_to(&buf[..n], from)
-                                                .await
-                                                .context("unable to send")
-                                            {
-                                                error!(
-                                                        "error from {addr_b} for {from} @ {addr_a:?}: {e}"
-                                                    );
-                                                break;
-                                            }
-
-                                            counts.recv(n, 0);
-                                        }
-                                        Ok(None) => {
-                                            trace!("connection closed");
-                                            break;
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "error from {addr_b} for {from} @ {addr_a:?}: {e}"
-                                            );
-                                            break;
-                                        }
-                                    };
-                                }
-                            });
-                        }
-                    }
-                    Err(err) => error!("failed to write to QUIC stream: {}", err),
-                }
-            }
-            Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
-                // False-positive, continue
-            }
-            Err(e) => {
-                // Actual error. Return it
-                return Err(e.into());
-            }
-        }
+        x?
     }
 }
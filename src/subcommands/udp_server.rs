@@ -1,17 +1,17 @@
 use argh::FromArgs;
-use futures::TryFutureExt;
-use quic_tunnel::counters::TunnelCounters;
-use quic_tunnel::quic::{build_server_endpoint, matching_bind_address, CongestionMode};
-use quinn::Connecting;
+use quic_tunnel::admin::{spawn_admin_socket, AdminState};
+use quic_tunnel::certs::{TunnelEnd, DEFAULT_RENEW_WITHIN_DAYS};
+use quic_tunnel::config::tunnel_alpn;
+use quic_tunnel::quic::{CongestionMode, EndpointBuilder, TransportPreset, TransportTuning};
+use quic_tunnel::reload::reload_signal;
+use quic_tunnel::shutdown::{default_drain_timeout, shutdown_signal};
+use quic_tunnel::timeouts::{TimeoutOverrides, Timeouts};
+use quic_tunnel::tunnels::UdpTunnelServer;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::AsyncReadExt;
-use tokio::net::UdpSocket;
 use tokio::select;
-use tokio::time::timeout;
-use tracing::{debug, error, info, trace};
+use tracing::info;
 
 /// Run the QUIC Tunnel Server.
 ///
@@ -19,10 +19,42 @@ use tracing::{debug, error, info, trace};
 #[derive(Debug, FromArgs, PartialEq)]
 #[argh(subcommand, name = "udp_server")]
 pub struct UdpServerSubCommand {
-    /// prefix for all the certificates to load
+    /// prefix for the certificates that aren't set with --ca, --cert, or --key
     #[argh(positional)]
     cert_name: String,
 
+    /// a CA that signed the other end's certificate. can be repeated. every cert in a PEM bundle is trusted. defaults to "<cert_name>_ca.pem"
+    #[argh(option)]
+    ca: Vec<PathBuf>,
+
+    /// this end's certificate, then any intermediate CAs. defaults to "<cert_name>_server.pem"
+    #[argh(option)]
+    cert: Option<PathBuf>,
+
+    /// the key for this end's certificate. defaults to "<cert_name>_server.key.pem"
+    #[argh(option)]
+    key: Option<PathBuf>,
+
+    /// a PEM file of revoked client certificates. can be repeated. defaults to "<cert_name>_crl.pem" if it exists. reloaded when it changes
+    #[argh(option)]
+    crl: Vec<PathBuf>,
+
+    /// the CA's key, for renewing this end's certificate before it expires. defaults to "<cert_name>_ca.key.pem" if it exists
+    #[argh(option)]
+    ca_key: Option<PathBuf>,
+
+    /// renew this end's certificate when it has less than this many days left (default 10)
+    #[argh(option, default = "DEFAULT_RENEW_WITHIN_DAYS")]
+    renew_within_days: u32,
+
+    /// the file with the passphrase for an encrypted CA key
+    #[argh(option)]
+    ca_passphrase_file: Option<PathBuf>,
+
+    /// load keys that other users can read instead of refusing to start
+    #[argh(switch)]
+    insecure_key_permissions: bool,
+
     /// the local address to listen on with QUIC. Clients connect here
     #[argh(positional)]
     local_addr: SocketAddr,
@@ -34,179 +66,128 @@ pub struct UdpServerSubCommand {
     /// congestion mode for QUIC
     #[argh(option, default = "Default::default()")]
     congestion_mode: CongestionMode,
-}
 
-impl UdpServerSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
-        let cert = PathBuf::from(format!("{}_server.pem", self.cert_name));
-        let key = PathBuf::from(format!("{}_server.key.pem", self.cert_name));
-
-        let endpoint = build_server_endpoint(
-            ca,
-            cert,
-            key,
-            true,
-            self.local_addr,
-            self.congestion_mode,
-            false,
-        )?;
-
-        info!(
-            "QUIC listening on {} and forwarding to {}",
-            endpoint.local_addr()?,
-            self.remote_addr,
-        );
-
-        let counts = TunnelCounters::new();
-
-        let mut tunnel_handle = {
-            let endpoint = endpoint.clone();
-            let addr_b = self.remote_addr;
-
-            tokio::spawn(async move {
-                while let Some(conn) = endpoint.accept().await {
-                    let f = handle_connection(conn, addr_b);
-
-                    // spawn to handle multiple connections at once
-                    tokio::spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
-                }
-            })
-        };
+    /// tune QUIC for a kind of network: airplane, lan, or satellite. the config file can tune everything
+    #[argh(option)]
+    transport_preset: Option<TransportPreset>,
 
-        let mut stats_handle = counts.spawn_stats_loop();
+    /// accept clients from a "run" config that have a tunnel with this name. standalone clients need the same --tunnel-name then
+    #[argh(option)]
+    tunnel_name: Option<String>,
 
-        select! {
-            x = &mut tunnel_handle => {
-                info!(?x, "tunnel task finished");
-            }
-            x = &mut stats_handle => {
-                info!(?x, "stats task finished");
-            }
-        }
+    /// listen on this Unix socket for admin commands. see the "ctl" subcommand
+    #[argh(option)]
+    admin_socket: Option<PathBuf>,
 
-        tunnel_handle.abort();
-        stats_handle.abort();
+    /// seconds to wait for open streams to finish after ctrl-c or SIGTERM
+    #[argh(option, default = "default_drain_timeout().as_secs()")]
+    drain_timeout: u64,
 
-        endpoint.close(0u32.into(), b"server done");
+    /// seconds without any packets before the QUIC connection is closed. default 300
+    #[argh(option)]
+    idle_timeout: Option<u64>,
 
-        Ok(())
-    }
+    /// seconds to wait for the QUIC handshake. default 30
+    #[argh(option)]
+    handshake_timeout: Option<u64>,
 }
 
-async fn handle_connection(conn_a: Connecting, addr_b: SocketAddr) -> anyhow::Result<()> {
-    // TODO: are there other things I need to do to set up 0-rtt?
-    let conn_a = match conn_a.into_0rtt() {
-        Ok((conn_a, _)) => {
-            trace!("0-rtt accepted");
-            conn_a
+impl UdpServerSubCommand {
+    fn tuning(&self) -> TransportTuning {
+        TransportTuning {
+            preset: self.transport_preset,
+            ..Default::default()
         }
-        Err(conn_a) => timeout(Duration::from_secs(30), conn_a).await??,
-    };
-
-    // TODO: look at the handshake data to figure out what client connected. that way we know what TcpListener to connect it to
-    // conn.handshake_data()
-
-    loop {
-        // each new QUIC stream gets a new UDP socket
-        let stream_a = conn_a.accept_bi().await;
-
-        let bind_b = matching_bind_address(conn_a.remote_address())?;
-
-        let socket_b = UdpSocket::bind(bind_b).await?;
-        socket_b.connect(addr_b).await?;
-
-        let socket_b = Arc::new(socket_b);
-
-        let (tx_a, rx_a) = match stream_a {
-            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                debug!("connection closed");
-                return Ok(());
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
-            Ok(s) => s,
-        };
-
-        let f = handle_request(tx_a, rx_a, socket_b);
-
-        // spawn to handle multiple requests at once
-        tokio::spawn(async move {
-            if let Err(e) = f.await {
-                error!("failed: {reason}", reason = e.to_string());
-            }
-        });
     }
-}
-
-/// TODO: counters
-/// TODO: i think if we use UdpFramed, we can use tokio::io::copy
-async fn handle_request(
-    mut tx_a: quinn::SendStream,
-    mut rx_a: quinn::RecvStream,
-    socket_b: Arc<UdpSocket>,
-) -> anyhow::Result<()> {
-    // listen on rx. when anything arrives, forward it to socket_b
-    let read_f = {
-        let socket_b = socket_b.clone();
-
-        async move {
-            // let max_size = rx_a.max_datagram_size().unwrap_or(8096);
-            let max_size = 8096;
 
-            let mut buf = Vec::with_capacity(max_size);
-
-            loop {
-                let n = rx_a.read_buf(&mut buf).await?;
+    fn timeouts(&self) -> anyhow::Result<Timeouts> {
+        TimeoutOverrides {
+            idle: self.idle_timeout,
+            handshake: self.handshake_timeout,
+            ..Default::default()
+        }
+        .build()
+    }
 
-                trace!("rx_a -> socket_b = {}", n);
+    pub async fn main(self) -> anyhow::Result<()> {
+        let timeouts = self.timeouts()?;
+
+        // the builder reads the certificates again on SIGHUP so that renewed certificates are used for new handshakes
+        let builder = EndpointBuilder::new()
+            .cert_name(&self.cert_name)
+            .cas(self.ca.clone())
+            .cert_opt(self.cert.clone())
+            .key_opt(self.key.clone())
+            .crls(self.crl.clone())
+            .ca_key_opt(self.ca_key.clone())
+            .renew_within_days(self.renew_within_days)
+            .ca_passphrase_file_opt(self.ca_passphrase_file.clone())
+            .insecure_key_permissions(self.insecure_key_permissions)
+            .alpn_protocols(self.tunnel_name.iter().map(|x| tunnel_alpn(x)).collect())
+            .congestion_mode(self.congestion_mode)
+            .timeouts(timeouts)
+            .tuning(self.tuning())
+            .bind(self.local_addr);
+
+        // renew an expiring certificate before it's loaded
+        let mut renewal = builder.renewal(TunnelEnd::Server);
+        renewal.check().await;
+
+        let endpoint = builder.build_server()?;
+
+        let tunnel = UdpTunnelServer::new(endpoint, self.remote_addr)
+            .with_drain_timeout(Duration::from_secs(self.drain_timeout));
+
+        let shutdown = tunnel.shutdown_handle();
+        let endpoint = tunnel.endpoint().clone();
+        let counts = tunnel.counts().clone();
+
+        let mut admin_handle =
+            spawn_admin_socket(self.admin_socket, AdminState::new(counts.clone()));
 
-                socket_b.send(&buf[..n]).await?;
-            }
-        }
-    };
-    // TODO: log errors and return ()
-    let mut read_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(read_f);
+        let mut stats_handle = counts.spawn_stats_loop();
 
-    let write_f = async move {
-        loop {
-            socket_b.readable().await?;
+        let mut tunnel_handle = tokio::spawn(tunnel.run());
 
-            let mut buf = [0; 8096];
+        let mut reload = reload_signal()?;
 
-            match socket_b.recv(&mut buf).await {
-                Ok(n) => {
-                    trace!("socket_b -> tx_a = {}", n);
+        let mut file_watch = endpoint.file_watch();
 
-                    tx_a.write_all(&buf[..n]).await?;
+        let x = loop {
+            select! {
+                x = &mut tunnel_handle => break x,
+                x = &mut stats_handle => {
+                    info!(?x, "stats task finished");
                 }
-                Err(e) => {
-                    error!("failed to read from socket: {}", e);
-                    break;
+                x = &mut admin_handle => {
+                    info!(?x, "admin task finished");
+                }
+                _ = reload.recv() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                _ = file_watch.changed() => {
+                    info!("certificate files changed");
+                    endpoint.reload().await;
+                    continue;
+                }
+                _ = renewal.renewed() => {
+                    endpoint.reload().await;
+                    continue;
+                }
+                x = shutdown_signal() => {
+                    info!(?x, "shutting down");
                 }
             }
-        }
-
-        Ok(())
-    };
 
-    // TODO: log errors and return ()
-    let mut write_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(write_f);
+            shutdown.shutdown();
 
-    select! {
-        x = &mut read_f => {
-            trace!("read_f finished: {:?}", x);
-        }
-        x = &mut write_f => {
-            trace!("write_f finished: {:?}", x);
-        }
-    }
-
-    read_f.abort();
-    write_f.abort();
+            break tunnel_handle.await;
+        };
 
-    info!("request finished");
+        stats_handle.abort();
+        admin_handle.abort();
 
-    Ok(())
+        x?
+    }
 }
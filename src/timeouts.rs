@@ -0,0 +1,132 @@
+//! How long to wait before giving up on connections and flows.
+//!
+//! These used to be one global 300 seconds. A DNS flow is done after one response, but a WireGuard flow should stay open for minutes.
+
+use std::time::Duration;
+
+use quinn::IdleTimeout;
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// close the QUIC connection after this long without any packets. The peers use the lower of their two values
+    pub idle: Duration,
+    /// how often to send keep-alive packets. Only used if keep alive is on
+    pub keep_alive: Duration,
+    /// forget a UDP flow after this long without any packets
+    pub udp_flow_idle: Duration,
+    /// how long to wait for a QUIC handshake
+    pub handshake: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        let idle = Duration::from_secs(300);
+
+        Self {
+            idle,
+            // TODO: how many keep alives per idle timeout?
+            keep_alive: idle / 3,
+            udp_flow_idle: idle,
+            handshake: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Errors for values that can't work. Warns for values that probably don't do what you want.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.handshake.is_zero() {
+            anyhow::bail!("handshake timeout must be more than 0");
+        }
+
+        if self.udp_flow_idle.is_zero() {
+            anyhow::bail!("udp flow idle timeout must be more than 0");
+        }
+
+        if self.keep_alive.is_zero() {
+            anyhow::bail!("keep alive interval must be more than 0");
+        }
+
+        // quinn treats 0 as "no idle timeout"
+        IdleTimeout::try_from(self.idle)
+            .map_err(|_| anyhow::anyhow!("idle timeout is too large: {:?}", self.idle))?;
+
+        if !self.idle.is_zero() && self.keep_alive >= self.idle {
+            warn!(
+                idle = ?self.idle,
+                keep_alive = ?self.keep_alive,
+                "keep alive is not less than the idle timeout. idle connections will be closed"
+            );
+        }
+
+        if self.udp_flow_idle > self.idle && !self.idle.is_zero() {
+            warn!(
+                idle = ?self.idle,
+                udp_flow_idle = ?self.udp_flow_idle,
+                "udp flows outlive the idle timeout. the connection may close before the flow is forgotten"
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Option<IdleTimeout> {
+        if self.idle.is_zero() {
+            None
+        } else {
+            // validate checks this
+            Some(self.idle.try_into().unwrap())
+        }
+    }
+}
+
+/// Override some of the timeouts. Everything is in seconds.
+///
+/// Used by the config file and by the command line options. Keep alive and UDP flows follow a new `idle` unless they are set too.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutOverrides {
+    pub idle: Option<u64>,
+    pub keep_alive: Option<u64>,
+    pub udp_flow_idle: Option<u64>,
+    pub handshake: Option<u64>,
+}
+
+impl TimeoutOverrides {
+    pub fn apply(&self, mut x: Timeouts) -> Timeouts {
+        if let Some(idle) = self.idle {
+            x.idle = Duration::from_secs(idle);
+
+            // 0 turns the idle timeout off. there's nothing to follow then
+            if !x.idle.is_zero() {
+                x.keep_alive = x.idle / 3;
+                x.udp_flow_idle = x.idle;
+            }
+        }
+
+        if let Some(keep_alive) = self.keep_alive {
+            x.keep_alive = Duration::from_secs(keep_alive);
+        }
+
+        if let Some(udp_flow_idle) = self.udp_flow_idle {
+            x.udp_flow_idle = Duration::from_secs(udp_flow_idle);
+        }
+
+        if let Some(handshake) = self.handshake {
+            x.handshake = Duration::from_secs(handshake);
+        }
+
+        x
+    }
+
+    /// apply these on top of the defaults and validate the result
+    pub fn build(&self) -> anyhow::Result<Timeouts> {
+        let x = self.apply(Default::default());
+
+        x.validate()?;
+
+        Ok(x)
+    }
+}
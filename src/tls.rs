@@ -1,11 +1,79 @@
 // TODO: compare with <https://github.com/quinn-rs/quinn/blob/main/quinn/examples/common/mod.rs>
 
-use crate::certs::{cert_from_pem, key_from_pem};
-use rustls::server::AllowAnyAuthenticatedClient;
-use rustls::{Certificate, ClientConfig, RootCertStore, ServerConfig};
-use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{AllowAnyAuthenticatedClient, UnparsedCertRevocationList};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+    ServerName,
+};
+use tracing::warn;
+
+use crate::certs::{KeyAlgo, SpkiPin};
+
+/// Trust a server because its public key is pinned instead of because the CA signed a certificate with the right name.
+///
+/// The handshake still proves that the server has the private key. The name and the CA are not checked.
+#[derive(Debug)]
+pub struct SpkiPinVerifier {
+    pins: Vec<SpkiPin>,
+}
+
+impl SpkiPinVerifier {
+    pub fn new(pins: Vec<SpkiPin>) -> Self {
+        Self { pins }
+    }
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pins.iter().any(|x| x.matches(end_entity)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        // log the actual pin so that a renewed key is easy to fix
+        let actual = SpkiPin::from_cert(end_entity).map(|x| x.to_string());
+        warn!(?actual, expected = ?self.pins, "server key is not pinned");
+
+        Err(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+}
+
+/// Fail early if rustls can't sign with `key`, or if it isn't the kind of key that was asked for. Otherwise the handshake fails later with a vague error.
+pub fn check_key(key: &PrivateKey, algo: Option<KeyAlgo>) -> anyhow::Result<()> {
+    rustls::sign::any_supported_type(key).map_err(|_| {
+        anyhow::anyhow!("rustls can't sign with this key. use ecdsa-p256, ed25519, or rsa-3072")
+    })?;
+
+    if let Some(algo) = algo {
+        let actual = KeyAlgo::of_key(&key.0);
+
+        if actual != Some(algo) {
+            anyhow::bail!(
+                "the key is {}, not {}. make a new one with \"quick_certs new --alg {}\"",
+                actual.map_or("another kind".to_string(), |x| x.to_string()),
+                algo,
+                algo
+            );
+        }
+    }
 
-pub fn build_root_store(root_certs: &[&Certificate]) -> anyhow::Result<RootCertStore> {
+    Ok(())
+}
+
+pub fn build_root_store(root_certs: &[Certificate]) -> anyhow::Result<RootCertStore> {
     let mut root_store = RootCertStore::empty();
 
     for root_cert in root_certs {
@@ -15,24 +83,26 @@ pub fn build_root_store(root_certs: &[&Certificate]) -> anyhow::Result<RootCertS
     Ok(root_store)
 }
 
+/// `cert_chain` is our certificate followed by any intermediate CAs.
+///
+/// If `pins` is not empty, the server is trusted by its public key instead of by the CA.
 pub fn build_client_config(
-    ca: PathBuf,
-    cert: PathBuf,
-    key: PathBuf,
+    ca: &[Certificate],
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+    pins: &[SpkiPin],
 ) -> anyhow::Result<ClientConfig> {
-    let ca = cert_from_pem(ca)?;
-    let cert = cert_from_pem(cert)?;
-    let key = key_from_pem(key)?;
-
-    let root_store = build_root_store(&[&ca])?;
-
-    let mut config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_client_auth_cert(vec![cert], key)?;
-
-    // // TODO: set alpn protocols?
-    // config.alpn_protocols = vec!["quic-tunnel".into()];
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+
+    let mut config = if pins.is_empty() {
+        builder
+            .with_root_certificates(build_root_store(ca)?)
+            .with_client_auth_cert(cert_chain, key)?
+    } else {
+        builder
+            .with_custom_certificate_verifier(Arc::new(SpkiPinVerifier::new(pins.to_vec())))
+            .with_client_auth_cert(cert_chain, key)?
+    };
 
     // TODO: make early data optional?
     config.enable_early_data = true;
@@ -43,30 +113,29 @@ pub fn build_client_config(
     Ok(config)
 }
 
+/// Clients signed by any of the CAs are accepted unless they are in one of the `crls`. `cert_chain` is our certificate followed by any intermediate CAs.
 pub fn build_server_config(
-    ca: PathBuf,
-    cert: PathBuf,
-    key: PathBuf,
+    ca: &[Certificate],
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+    crls: Vec<Vec<u8>>,
 ) -> anyhow::Result<(ServerConfig, RootCertStore)> {
-    let ca = cert_from_pem(ca)?;
-    let cert = cert_from_pem(cert)?;
-    let key = key_from_pem(key)?;
-
-    let root_store = build_root_store(&[&ca])?;
+    let root_store = build_root_store(ca)?;
 
     // accept any client cert signed by the CA
     // TODO: figure out why certs aren't working
     // server says `DEBUG quinn_proto::connection: closing connection due to transport error: the cryptographic handshake failed: error 116: peer sent no certificates`
     // client says `DEBUG rustls::client::common: Client auth requested but no cert/sigscheme available`
-    let client_cert_verifier = AllowAnyAuthenticatedClient::new(root_store.clone()).boxed();
+    let client_cert_verifier = AllowAnyAuthenticatedClient::new(root_store.clone())
+        .with_crls(crls.into_iter().map(UnparsedCertRevocationList))
+        .map_err(|err| anyhow::anyhow!("invalid CRL: {:?}", err))?
+        .boxed();
 
     let mut config = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_client_cert_verifier(client_cert_verifier)
-        .with_single_cert(vec![cert, ca], key)?;
-
-    // // TODO: set alpn protocols?
-    // config.alpn_protocols = vec!["quic-tunnel".into()];
+        // clients already have the root CA. they only need the intermediates
+        .with_single_cert(cert_chain, key)?;
 
     // TODO: make 0.5-rtt optional
     config.send_half_rtt_data = true;
@@ -0,0 +1,39 @@
+//! Tunnels for embedding in other programs. The subcommands are thin wrappers around these.
+//!
+//! Each tunnel has an async `run` and a [`ShutdownHandle`](crate::shutdown::ShutdownHandle) to stop it.
+//!
+//! ```no_run
+//! # async fn f() -> anyhow::Result<()> {
+//! use quic_tunnel::quic::EndpointBuilder;
+//! use quic_tunnel::tunnels::UdpTunnelServer;
+//!
+//! let endpoint = EndpointBuilder::new()
+//!     .cert_name("data/first")
+//!     .bind("0.0.0.0:8443".parse()?)
+//!     .build_server()?;
+//!
+//! let tunnel = UdpTunnelServer::new(endpoint, "127.0.0.1:51820".parse()?);
+//!
+//! let shutdown = tunnel.shutdown_handle();
+//!
+//! tokio::spawn(async move {
+//!     tokio::signal::ctrl_c().await.ok();
+//!     shutdown.shutdown();
+//! });
+//!
+//! tunnel.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod reverse_proxy_client;
+mod reverse_proxy_server;
+mod udp_client;
+mod udp_server;
+
+pub use reverse_proxy_client::{forward_streams, ReverseProxyClient, ReverseProxyTarget};
+pub use reverse_proxy_server::{
+    handle_quic_connection, spawn_tcp_listener, spawn_unix_listener, ReverseProxyServer,
+};
+pub use udp_client::{evict_idle_flows, finish_flows, tunnel_udp_to_endpoint, UdpTunnelClient};
+pub use udp_server::{handle_connection, UdpTunnelServer};
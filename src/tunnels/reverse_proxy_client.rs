@@ -0,0 +1,224 @@
+//! Forward streams from a reverse proxy server to a nearby service.
+
+use futures::{FutureExt, TryFutureExt};
+use quinn::Connection;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::UnixStream, select};
+use tracing::{debug, info, trace};
+
+use crate::compress::{copy_bidirectional_with_compression, CompressAlgo};
+use crate::counters::{CountersRegistry, TunnelCounters};
+use crate::events;
+use crate::net::tcp_socket_for;
+use crate::quic::ClientEndpoint;
+use crate::resolve::{reconnect, RemoteAddr};
+use crate::shutdown::{default_drain_timeout, drain, ShutdownHandle};
+use crate::stream::Stream;
+
+/// The nearby service that a reverse proxy client forwards to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReverseProxyTarget {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ReverseProxyTarget {
+    /// for command line options and config files that have one field for each kind
+    pub fn new(
+        tcp_connect: Option<SocketAddr>,
+        unix_connect: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        match (tcp_connect, unix_connect) {
+            (Some(x), None) => Ok(Self::Tcp(x)),
+            (None, Some(x)) => Ok(Self::Unix(x)),
+            _ => anyhow::bail!("specify either tcp_connect or unix_connect. not none. not both"),
+        }
+    }
+}
+
+/// The private end of a reverse proxy. Connects out to the server so that a service behind NAT can be reached.
+pub struct ReverseProxyClient {
+    endpoint: ClientEndpoint,
+    remote: RemoteAddr,
+    server_name: Option<String>,
+    target: ReverseProxyTarget,
+    compress: CompressAlgo,
+    counts: Arc<CountersRegistry>,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl ReverseProxyClient {
+    /// `server_name` is the name on the remote server's certificate. None uses the hostname in `remote`.
+    pub fn new(
+        endpoint: ClientEndpoint,
+        remote: impl Into<RemoteAddr>,
+        server_name: Option<String>,
+        target: ReverseProxyTarget,
+    ) -> Self {
+        let counts = CountersRegistry::new(endpoint.timeouts().udp_flow_idle);
+
+        Self {
+            endpoint,
+            remote: remote.into(),
+            server_name,
+            target,
+            compress: CompressAlgo::None,
+            counts,
+            drain_timeout: default_drain_timeout(),
+            shutdown: Default::default(),
+        }
+    }
+
+    /// Must match the server. Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
+    pub fn with_compress(mut self, compress: CompressAlgo) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// how long to wait for open streams to finish after shutdown
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn counts(&self) -> &Arc<CountersRegistry> {
+        &self.counts
+    }
+
+    /// clone this to reload the certificates while the tunnel runs
+    pub fn endpoint(&self) -> &ClientEndpoint {
+        &self.endpoint
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Connect to the server and forward its streams until the shutdown handle is used. Reconnects if the connection closes.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let connect = || {
+            self.endpoint
+                .connect_remote(&self.remote, self.server_name.as_deref())
+        };
+
+        let mut connected = connect().await?;
+
+        let x = loop {
+            let (remote, zero_rtt) = connected;
+
+            info!("connected to QUIC server at {}", remote.remote_address());
+
+            events::connection_opened(&remote, zero_rtt);
+
+            let connection_counts = self.counts.connection(&remote).await;
+
+            let x = select! {
+                x = forward_streams(&remote, &connection_counts, &self.target, self.compress) => x,
+                _ = self.shutdown.wait() => break Ok(()),
+            };
+
+            // other errors, like the nearby service being down, still stop the tunnel
+            if remote.close_reason().is_none() {
+                break x;
+            }
+
+            self.counts.remove_connection(&remote).await;
+
+            info!("reconnecting to {}", self.remote);
+
+            connected = select! {
+                x = reconnect(&connect) => x,
+                _ = self.shutdown.wait() => break Ok(()),
+            };
+        };
+
+        drain(&self.endpoint, &self.counts, self.drain_timeout).await;
+
+        x
+    }
+}
+
+/// each stream the server opens is connected to a new nearby stream
+pub async fn forward_streams(
+    remote: &Connection,
+    connection_counts: &Arc<TunnelCounters>,
+    target: &ReverseProxyTarget,
+    compress: CompressAlgo,
+) -> anyhow::Result<()> {
+    loop {
+        // TODO: connection pool for re-using these streams
+        let stream = match target {
+            ReverseProxyTarget::Tcp(tcp_connect) => {
+                let tcp_socket = tcp_socket_for(*tcp_connect)?;
+
+                trace!(?tcp_socket, "new socket for {}", tcp_connect);
+
+                let nearby_tcp_stream = tcp_socket.connect(*tcp_connect).await?;
+
+                debug!(
+                    "connected to nearby tcp server at {}",
+                    nearby_tcp_stream.peer_addr().unwrap()
+                );
+
+                Stream::Tcp(nearby_tcp_stream)
+            }
+            ReverseProxyTarget::Unix(unix_connect) => {
+                debug!("connecting to unix socket at {}", unix_connect.display());
+
+                let unix_stream = UnixStream::connect(unix_connect).await?;
+
+                Stream::Unix(unix_stream)
+            }
+        };
+
+        let (remote_tx, remote_rx) = match remote.accept_bi().await {
+            Ok(x) => x,
+            Err(err) => {
+                events::connection_closed(remote, &err);
+                return Err(err.into());
+            }
+        };
+
+        debug!("reverse proxy server connected to us");
+
+        let stream_id = remote_tx.id();
+
+        events::stream_opened(remote, stream_id);
+
+        let stream_counts = connection_counts.child();
+        stream_counts.stream_opened();
+
+        let f = copy_bidirectional_with_compression(
+            compress,
+            remote_rx,
+            remote_tx,
+            stream,
+            stream_counts.clone(),
+        );
+
+        let remote = remote.clone();
+        let start = Instant::now();
+
+        tokio::spawn(
+            f.inspect_err(|err| debug!(?err, "reverse proxy client error"))
+                .then(move |x| async move {
+                    stream_counts.stream_closed();
+
+                    events::stream_closed(
+                        &remote,
+                        stream_id,
+                        stream_counts.snapshot(),
+                        start.elapsed(),
+                    );
+
+                    x
+                }),
+        );
+    }
+}
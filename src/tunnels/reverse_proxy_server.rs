@@ -0,0 +1,296 @@
+//! Listen for users and forward them to any connected reverse proxy clients.
+
+use flume::{Receiver, Sender};
+use futures::{FutureExt, TryFutureExt};
+use quinn::{Connection, ConnectionError};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UnixListener;
+use tokio::select;
+use tracing::{debug, error, info, trace};
+
+use crate::compress::{copy_bidirectional_with_compression, CompressAlgo};
+use crate::counters::CountersRegistry;
+use crate::events;
+use crate::net::bind_tokio_tcp_listener;
+use crate::quic::{accept, ServerEndpoint};
+use crate::shutdown::{default_drain_timeout, drain, ShutdownHandle};
+use crate::stream::Stream;
+
+/// The public end of a reverse proxy. Users connect over TCP or a Unix socket and get a new stream on a connected client.
+pub struct ReverseProxyServer {
+    endpoint: ServerEndpoint,
+    tcp_listen: Option<SocketAddr>,
+    unix_listen: Option<PathBuf>,
+    compress: CompressAlgo,
+    counts: Arc<CountersRegistry>,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl ReverseProxyServer {
+    /// Needs `tcp_listen` or `unix_listen` or both.
+    pub fn new(
+        endpoint: ServerEndpoint,
+        tcp_listen: Option<SocketAddr>,
+        unix_listen: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        if tcp_listen.is_none() && unix_listen.is_none() {
+            anyhow::bail!("specify tcp_listen or unix_listen or both");
+        }
+
+        let counts = CountersRegistry::new(endpoint.timeouts().udp_flow_idle);
+
+        Ok(Self {
+            endpoint,
+            tcp_listen,
+            unix_listen,
+            compress: CompressAlgo::None,
+            counts,
+            drain_timeout: default_drain_timeout(),
+            shutdown: Default::default(),
+        })
+    }
+
+    /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
+    pub fn with_compress(mut self, compress: CompressAlgo) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// how long to wait for open streams to finish after shutdown
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn counts(&self) -> &Arc<CountersRegistry> {
+        &self.counts
+    }
+
+    /// clone this to reload the certificates while the tunnel runs
+    pub fn endpoint(&self) -> &ServerEndpoint {
+        &self.endpoint
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept clients and users until the shutdown handle is used. Then drain their streams.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let (stream_sender, stream_receiver) = flume::unbounded::<Stream>();
+
+        info!("QUIC listening on {}", self.endpoint.local_addr()?);
+
+        let handshake_timeout = self.endpoint.handshake_timeout();
+
+        // the tunnel handle listens on quic and forwards messages from a channel for tcp
+        // TODO: better name
+        let mut quic_endpoint_handle = {
+            let endpoint = self.endpoint.clone();
+            let stream_receiver = stream_receiver.clone();
+            let compression_mode = self.compress;
+            let counts = self.counts.clone();
+
+            let f = async move {
+                while let Some(conn) = endpoint.accept().await {
+                    let stream_receiver = stream_receiver.clone();
+                    let counts = counts.clone();
+
+                    let f = async move {
+                        let (conn, zero_rtt) = accept(conn, handshake_timeout).await?;
+
+                        handle_quic_connection(
+                            conn,
+                            zero_rtt,
+                            stream_receiver,
+                            compression_mode,
+                            counts,
+                        )
+                        .await
+                    };
+
+                    // spawn to handle multiple connections at once? we only have one listener right now
+                    tokio::spawn(f.inspect_err(|err| trace!(?err, "reverse proxy tunnel closed")));
+                }
+            };
+
+            // this handle isn't needed. errors are logged elsewhere
+            tokio::spawn(f)
+        };
+
+        let mut tcp_listener_handle = spawn_tcp_listener(self.tcp_listen, stream_sender.clone());
+
+        let mut unix_listener_handle = spawn_unix_listener(self.unix_listen, stream_sender);
+
+        select! {
+            x = &mut quic_endpoint_handle => {
+                info!(?x, "tunnel task finished");
+            }
+            x = &mut tcp_listener_handle => {
+                info!(?x, "tcp task finished");
+            }
+            x = &mut unix_listener_handle => {
+                info!(?x, "unix task finished");
+            }
+            _ = self.shutdown.wait() => {}
+        }
+
+        // stop accepting users. streams that are already open get to finish
+        tcp_listener_handle.abort();
+        unix_listener_handle.abort();
+
+        drain(&self.endpoint, &self.counts, self.drain_timeout).await;
+
+        quic_endpoint_handle.abort();
+
+        Ok(())
+    }
+}
+
+/// listens on tcp and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
+///
+/// If `listen_addr` is None, the task never finishes.
+pub fn spawn_tcp_listener(
+    listen_addr: Option<SocketAddr>,
+    stream_sender: Sender<Stream>,
+) -> tokio::task::JoinHandle<anyhow::Result<()>> {
+    if let Some(listen_addr) = listen_addr {
+        let f = async move {
+            // TODO: wait until at least one client has connected to the quic endpoint?
+
+            let tcp_listener = bind_tokio_tcp_listener(listen_addr)?;
+            info!("TCP listening on {}", tcp_listener.local_addr()?);
+
+            loop {
+                match tcp_listener.accept().await {
+                    Ok((stream, _)) => {
+                        // send the stream to a channel. one of multiple connections might handle it
+                        stream_sender.send_async(Stream::Tcp(stream)).await?
+                    }
+                    Err(err) => error!(?err, "tcp accept failed"),
+                }
+            }
+        };
+
+        tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
+    } else {
+        let f = std::future::pending::<anyhow::Result<()>>();
+
+        tokio::spawn(f)
+    }
+}
+
+/// listens on unix socket and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
+///
+/// If `unix_listen_path` is None, the task never finishes.
+pub fn spawn_unix_listener(
+    unix_listen_path: Option<PathBuf>,
+    stream_sender: Sender<Stream>,
+) -> tokio::task::JoinHandle<anyhow::Result<()>> {
+    if let Some(unix_listen_path) = unix_listen_path {
+        let f = async move {
+            // TODO: wait until at least one client has connected to the quic endpoint?
+
+            info!("UNIX listening at {}", unix_listen_path.display());
+            let listener = UnixListener::bind(unix_listen_path)?;
+
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        // send the stream to a channel. one of multiple connections might handle it
+                        stream_sender.send_async(Stream::Unix(stream)).await?
+                    }
+                    Err(err) => error!(?err, "tcp accept failed"),
+                }
+            }
+        };
+
+        tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
+    } else {
+        let f = std::future::pending::<anyhow::Result<()>>();
+
+        tokio::spawn(f)
+    }
+}
+
+pub async fn handle_quic_connection(
+    conn_a: Connection,
+    zero_rtt: bool,
+    rx_b: Receiver<Stream>,
+    compress_algo: CompressAlgo,
+    counts: Arc<CountersRegistry>,
+) -> anyhow::Result<()> {
+    events::connection_opened(&conn_a, zero_rtt);
+
+    // TODO: look at the handshake data to figure out what client connected? that way we know what TcpListener to connect it to?
+
+    let connection_counts = counts.connection(&conn_a).await;
+
+    let x = async {
+        while let Ok(stream_b) = rx_b.recv_async().await {
+            debug!(?stream_b, "user connected");
+
+            // each new TCP stream gets a new QUIC stream
+            let (tx_a, rx_a) = conn_a.open_bi().await?;
+
+            trace!("reverse proxy stream opened");
+
+            let stream_id = tx_a.id();
+
+            events::stream_opened(&conn_a, stream_id);
+
+            let stream_counts = connection_counts.child();
+            stream_counts.stream_opened();
+
+            let f = copy_bidirectional_with_compression(
+                compress_algo,
+                rx_a,
+                tx_a,
+                stream_b,
+                stream_counts.clone(),
+            );
+
+            let conn_a = conn_a.clone();
+            let start = Instant::now();
+
+            // spawn to handle multiple requests at once
+            tokio::spawn(
+                f.inspect_err(|e| {
+                    error!("failed: {}", e);
+                })
+                .inspect_ok(|(a_to_b, b_to_a)| trace!(%a_to_b, %b_to_a, "success"))
+                .then(move |x| async move {
+                    stream_counts.stream_closed();
+
+                    events::stream_closed(
+                        &conn_a,
+                        stream_id,
+                        stream_counts.snapshot(),
+                        start.elapsed(),
+                    );
+
+                    x
+                }),
+            );
+        }
+
+        // every listener is gone. the tunnel was removed or we are shutting down
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    counts.remove_connection(&conn_a).await;
+
+    // if the connection is still open, it will be closed when we drop it
+    let reason = conn_a
+        .close_reason()
+        .unwrap_or(ConnectionError::LocallyClosed);
+
+    events::connection_closed(&conn_a, &reason);
+
+    x
+}
@@ -0,0 +1,362 @@
+//! Listen for UDP locally and forward each flow through its own QUIC stream.
+//!
+//! TODO: helper for setting routes so that the WireGuard VPN doesn't try to take over the udp tunnel.
+
+use anyhow::Context;
+use moka::future::CacheBuilder;
+use quinn::Connection;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, select, sync::Mutex, time::interval};
+use tracing::{debug, error, info, trace};
+
+use crate::counters::CountersRegistry;
+use crate::net::bind_tokio_udp;
+use crate::quic::ClientEndpoint;
+use crate::resolve::{reconnect, RemoteAddr};
+use crate::shutdown::{default_drain_timeout, drain, ShutdownHandle};
+use crate::{events, TunnelCache, TunnelCacheKey};
+
+/// The client end of a UDP tunnel. For improving connections with packet loss, this tunnels the WireGuard connection to the server.
+pub struct UdpTunnelClient {
+    endpoint: ClientEndpoint,
+    local_addr: SocketAddr,
+    remote: RemoteAddr,
+    server_name: Option<String>,
+    counts: Arc<CountersRegistry>,
+    cache: TunnelCache,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl UdpTunnelClient {
+    /// `server_name` is the name on the remote server's certificate. None uses the hostname in `remote`.
+    ///
+    /// UDP flows are forgotten after the endpoint's `udp_flow_idle` timeout.
+    pub fn new(
+        endpoint: ClientEndpoint,
+        local_addr: SocketAddr,
+        remote: impl Into<RemoteAddr>,
+        server_name: Option<String>,
+    ) -> Self {
+        let udp_flow_idle = endpoint.timeouts().udp_flow_idle;
+
+        // DNS flows are done after one response. WireGuard flows should stay for minutes
+        let cache: TunnelCache = CacheBuilder::new(10_000)
+            .time_to_idle(udp_flow_idle)
+            .build();
+
+        Self {
+            endpoint,
+            local_addr,
+            remote: remote.into(),
+            server_name,
+            counts: CountersRegistry::new(udp_flow_idle),
+            cache,
+            drain_timeout: default_drain_timeout(),
+            shutdown: Default::default(),
+        }
+    }
+
+    /// how long to wait for open streams to finish after shutdown
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn counts(&self) -> &Arc<CountersRegistry> {
+        &self.counts
+    }
+
+    /// the UDP flows. give this to [`crate::admin::AdminState`] to be able to flush them
+    pub fn cache(&self) -> &TunnelCache {
+        &self.cache
+    }
+
+    /// clone this to reload the certificates while the tunnel runs
+    pub fn endpoint(&self) -> &ClientEndpoint {
+        &self.endpoint
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Connect to the server and forward UDP until the shutdown handle is used. Reconnects if the connection closes.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let connect = || {
+            self.endpoint
+                .connect_remote(&self.remote, self.server_name.as_deref())
+        };
+
+        let mut connected = connect().await?;
+
+        // TODO: this connection doesn't seem to have keep alive even though I turned it on in the server endpoint.
+        // TODO: if this connection isn't used soon, the
+
+        // listen on UDP
+        let local_socket = Arc::new(bind_tokio_udp(self.local_addr)?);
+
+        trace!(?local_socket);
+
+        let mut evict_handle = tokio::spawn(evict_idle_flows(self.cache.clone()));
+
+        // TODO: if our network changes, rebind the endpoint to a new udp socket
+
+        let remote = loop {
+            let (remote, zero_rtt) = connected;
+
+            events::connection_opened(&remote, zero_rtt);
+
+            info!(
+                "Forwarding {} through QUIC tunnel at {}",
+                local_socket.local_addr()?,
+                remote.remote_address()
+            );
+
+            let mut tunnel_handle = tokio::spawn(tunnel_udp_to_endpoint(
+                local_socket.clone(),
+                remote.clone(),
+                self.cache.clone(),
+                self.counts.clone(),
+            ));
+
+            let reason = select! {
+                x = &mut tunnel_handle => {
+                    info!(?x, "local task finished");
+                    None
+                }
+                x = &mut evict_handle => {
+                    info!(?x, "evict task finished");
+                    None
+                }
+                reason = remote.closed() => Some(reason),
+                _ = self.shutdown.wait() => None,
+            };
+
+            tunnel_handle.abort();
+
+            let Some(reason) = reason else {
+                break remote;
+            };
+
+            events::connection_closed(&remote, &reason);
+
+            self.counts.remove_connection(&remote).await;
+
+            // their streams were on the old connection
+            self.cache.invalidate_all();
+
+            info!("reconnecting to {}", self.remote);
+
+            connected = select! {
+                x = reconnect(&connect) => x,
+                _ = self.shutdown.wait() => {
+                    evict_handle.abort();
+                    drain(&self.endpoint, &self.counts, self.drain_timeout).await;
+                    return Ok(());
+                }
+            };
+        };
+
+        evict_handle.abort();
+
+        finish_flows(&self.cache).await;
+
+        drain(&self.endpoint, &self.counts, self.drain_timeout).await;
+
+        let reason = remote.closed().await;
+
+        events::connection_closed(&remote, &reason);
+
+        Ok(())
+    }
+}
+
+/// tell the server that no more packets are coming on any flow.
+/// the server closes each stream once it has seen that.
+pub async fn finish_flows(cache: &TunnelCache) {
+    // expired flows aren't in the iterator. evicting them drops their streams which finishes them
+    cache.run_pending_tasks().await;
+
+    for (_, (tx, _)) in cache.iter() {
+        // don't wait for the server to acknowledge. drain waits for the streams to close
+        tokio::spawn(async move {
+            if let Err(err) = tx.lock().await.finish().await {
+                trace!(?err, "failed finishing flow");
+            }
+        });
+    }
+
+    cache.invalidate_all();
+}
+
+/// moka only evicts expired flows when the cache is used. Without this, a flow's stream stays open long after `udp_flow_idle`.
+///
+/// Evicting a flow drops its send stream. That finishes the stream and the server closes its socket.
+pub async fn evict_idle_flows(cache: TunnelCache) -> anyhow::Result<()> {
+    let mut i = interval(Duration::from_secs(1));
+
+    loop {
+        i.tick().await;
+
+        cache.run_pending_tasks().await;
+    }
+}
+
+/// copy things on socket to endpoint and save the from address.
+/// then spawn a task that reads from the endpoint and sends everything to socket_a and the saved from address.
+pub async fn tunnel_udp_to_endpoint(
+    socket_a: Arc<UdpSocket>,
+    connection_b: Connection,
+    cache: TunnelCache,
+    counts: Arc<CountersRegistry>,
+) -> anyhow::Result<()> {
+    let connection_counts = counts.connection(&connection_b).await;
+
+    loop {
+        socket_a.readable().await?;
+
+        let max_size = connection_b.max_datagram_size().unwrap_or(8096);
+
+        // The buffer is **not** included in the async task and will only exist on the stack.
+        // TODO: what size should this buffer be?
+        // TODO: do this without allocating
+        let mut data = Vec::with_capacity(max_size);
+
+        match socket_a.try_recv_buf_from(&mut data) {
+            Ok((n, from)) => {
+                let addr_a = socket_a.local_addr().unwrap();
+                let addr_b = connection_b.remote_address();
+
+                debug!("sending {n} bytes from {from} @ {addr_a:?} over QUIC tunnel to {addr_b}");
+
+                // don't bind every time, re-use existing sockets if they are for the local and remote addresses
+                let cache_key = TunnelCacheKey {
+                    addr_a,
+                    from,
+                    addr_b,
+                };
+
+                let open = connection_b.clone();
+
+                let (tx_b, rx_b) = cache
+                    .try_get_with(cache_key, async move {
+                        let (tx_b, rx_b) = open.open_bi().await?;
+
+                        let tx_b = Arc::new(Mutex::new(tx_b));
+                        let rx_b = Arc::new(Mutex::new(Some(rx_b)));
+
+                        Ok::<_, anyhow::Error>((tx_b, rx_b))
+                    })
+                    .await
+                    .map_err(|e| anyhow::anyhow!("cache error: {}", e))?;
+
+                let mut lock_tx_b = tx_b.lock().await;
+
+                // TODO: we don't actually take advantage of quic's multiplexing. this could add the destination address and the server could have a mapping
+                // TODO: we would probably want to be able to listen on multiple ports then too
+                let tx = lock_tx_b.write_all(&data[..n]).await;
+
+                drop(lock_tx_b);
+
+                let flow_counts = counts.flow(cache_key, &connection_counts).await;
+
+                match tx {
+                    Ok(()) => {
+                        flow_counts.sent(n, 0);
+                        let socket_a = socket_a.clone();
+
+                        // we only need to rx once
+                        if let Some(mut rx) = rx_b.lock().await.take() {
+                            let stream_id = rx.id();
+
+                            events::stream_opened(&connection_b, stream_id);
+                            flow_counts.flow_stream_opened();
+
+                            let connection_b = connection_b.clone();
+                            let cache = cache.clone();
+                            let tx_b = tx_b.clone();
+
+                            // wait for socket_b to receive something or close
+                            tokio::spawn(async move {
+                                let start = Instant::now();
+
+                                // TODO: we need tokio_util::UdpFramed for this
+                                // io::copy(&mut rx, &mut socket_a).await?;
+
+                                let mut buf = [0; 8096];
+
+                                loop {
+                                    // TODO: what should udp timeout be?
+                                    // TODO: what should the max size be?
+                                    match rx.read(&mut buf).await {
+                                        Ok(Some(n)) => {
+                                            debug!("received {n} bytes from {addr_b} for {from} @ {addr_a:?}");
+
+                                            if let Err(e) = socket_a
+                                                .send_to(&buf[..n], from)
+                                                .await
+                                                .context("unable to send")
+                                            {
+                                                error!(
+                                                        "error from {addr_b} for {from} @ {addr_a:?}: {e}"
+                                                    );
+                                                break;
+                                            }
+
+                                            flow_counts.recv(n, 0);
+                                        }
+                                        Ok(None) => {
+                                            trace!("connection closed");
+                                            break;
+                                        }
+                                        Err(e) => {
+                                            error!(
+                                                "error from {addr_b} for {from} @ {addr_a:?}: {e}"
+                                            );
+                                            break;
+                                        }
+                                    };
+                                }
+
+                                // the server closes the stream after the flow is evicted or finished here, or when it's flushed there
+                                flow_counts.flow_stream_closed();
+
+                                // the next packet opens a new stream. a newer stream for the same flow is left alone
+                                if let Some((x, _)) = cache.get(&cache_key).await {
+                                    if Arc::ptr_eq(&x, &tx_b) {
+                                        cache.invalidate(&cache_key).await;
+                                    }
+                                }
+
+                                events::stream_closed(
+                                    &connection_b,
+                                    stream_id,
+                                    flow_counts.snapshot(),
+                                    start.elapsed(),
+                                );
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        error!("failed to write to QUIC stream: {}", err);
+
+                        // the server closed the stream. the next packet opens a new one
+                        cache.invalidate(&cache_key).await;
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock => {
+                // False-positive, continue
+            }
+            Err(e) => {
+                // Actual error. Return it
+                return Err(e.into());
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Forward UDP from QUIC clients to a remote address.
+
+use futures::TryFutureExt;
+use quinn::{Connection, ConnectionError};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{debug, error, info, trace};
+
+use crate::counters::{CountersRegistry, TunnelCounters};
+use crate::net::matching_bind_address;
+use crate::quic::{accept, ServerEndpoint};
+use crate::shutdown::{default_drain_timeout, drain, ShutdownHandle};
+use crate::{events, TunnelCacheKey};
+
+/// The server end of a UDP tunnel. For improving connections with packet loss, this runs on a server with a good Internet connection.
+pub struct UdpTunnelServer {
+    endpoint: ServerEndpoint,
+    forward_addr: SocketAddr,
+    counts: Arc<CountersRegistry>,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl UdpTunnelServer {
+    /// Every stream from every client gets its own UDP socket connected to `forward_addr`.
+    pub fn new(endpoint: ServerEndpoint, forward_addr: SocketAddr) -> Self {
+        let counts = CountersRegistry::new(endpoint.timeouts().udp_flow_idle);
+
+        Self {
+            endpoint,
+            forward_addr,
+            counts,
+            drain_timeout: default_drain_timeout(),
+            shutdown: Default::default(),
+        }
+    }
+
+    /// how long to wait for open streams to finish after shutdown
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn counts(&self) -> &Arc<CountersRegistry> {
+        &self.counts
+    }
+
+    /// clone this to reload the certificates while the tunnel runs
+    pub fn endpoint(&self) -> &ServerEndpoint {
+        &self.endpoint
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept clients until the shutdown handle is used. Then drain their streams.
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(
+            "QUIC listening on {} and forwarding to {}",
+            self.endpoint.local_addr()?,
+            self.forward_addr,
+        );
+
+        let handshake_timeout = self.endpoint.handshake_timeout();
+
+        let mut tunnel_handle = {
+            let endpoint = self.endpoint.clone();
+            let addr_b = self.forward_addr;
+            let counts = self.counts.clone();
+
+            tokio::spawn(async move {
+                while let Some(conn) = endpoint.accept().await {
+                    let counts = counts.clone();
+
+                    let f = async move {
+                        let (conn, zero_rtt) = accept(conn, handshake_timeout).await?;
+
+                        handle_connection(conn, zero_rtt, addr_b, counts).await
+                    };
+
+                    // spawn to handle multiple connections at once
+                    tokio::spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
+                }
+            })
+        };
+
+        select! {
+            x = &mut tunnel_handle => {
+                info!(?x, "tunnel task finished");
+            }
+            _ = self.shutdown.wait() => {}
+        }
+
+        tunnel_handle.abort();
+
+        drain(&self.endpoint, &self.counts, self.drain_timeout).await;
+
+        Ok(())
+    }
+}
+
+pub async fn handle_connection(
+    conn_a: Connection,
+    zero_rtt: bool,
+    addr_b: SocketAddr,
+    counts: Arc<CountersRegistry>,
+) -> anyhow::Result<()> {
+    events::connection_opened(&conn_a, zero_rtt);
+
+    // TODO: look at the handshake data to figure out what client connected. that way we know what TcpListener to connect it to
+    // conn.handshake_data()
+
+    let connection_counts = counts.connection(&conn_a).await;
+
+    let x = handle_streams(&conn_a, addr_b, &counts, &connection_counts).await;
+
+    counts.remove_connection(&conn_a).await;
+
+    // if the connection is still open, it will be closed when we drop it
+    let reason = conn_a
+        .close_reason()
+        .unwrap_or(ConnectionError::LocallyClosed);
+
+    events::connection_closed(&conn_a, &reason);
+
+    x
+}
+
+async fn handle_streams(
+    conn_a: &quinn::Connection,
+    addr_b: SocketAddr,
+    counts: &Arc<CountersRegistry>,
+    connection_counts: &Arc<TunnelCounters>,
+) -> anyhow::Result<()> {
+    loop {
+        // each new QUIC stream gets a new UDP socket
+        let stream_a = conn_a.accept_bi().await;
+
+        // the client might have connected over IPv6 to forward to an IPv4 address
+        let bind_b = matching_bind_address(addr_b)?;
+
+        let socket_b = UdpSocket::bind(bind_b).await?;
+        socket_b.connect(addr_b).await?;
+
+        let socket_b = Arc::new(socket_b);
+
+        let (tx_a, rx_a) = match stream_a {
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed");
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+            Ok(s) => s,
+        };
+
+        // the server doesn't know where the client's packets came from. the socket we forward them from is unique to the flow
+        let flow_key = TunnelCacheKey {
+            addr_a: conn_a.remote_address(),
+            from: socket_b.local_addr()?,
+            addr_b,
+        };
+
+        // the flow lasts as long as the stream. the client decides when it's idle
+        let close = Arc::new(Notify::new());
+
+        let flow_counts = counts
+            .stream_flow(flow_key, connection_counts, close.clone())
+            .await;
+
+        let stream_id = tx_a.id();
+
+        events::stream_opened(conn_a, stream_id);
+        flow_counts.flow_stream_opened();
+
+        let f = handle_request(tx_a, rx_a, socket_b, flow_counts.clone(), close);
+
+        let conn_a = conn_a.clone();
+        let counts = counts.clone();
+
+        // spawn to handle multiple requests at once
+        tokio::spawn(async move {
+            let start = Instant::now();
+
+            if let Err(e) = f.await {
+                error!("failed: {reason}", reason = e.to_string());
+            }
+
+            flow_counts.flow_stream_closed();
+
+            counts.remove_flow(&flow_key).await;
+
+            events::stream_closed(&conn_a, stream_id, flow_counts.snapshot(), start.elapsed());
+        });
+    }
+}
+
+/// TODO: i think if we use UdpFramed, we can use tokio::io::copy
+async fn handle_request(
+    mut tx_a: quinn::SendStream,
+    mut rx_a: quinn::RecvStream,
+    socket_b: Arc<UdpSocket>,
+    counts: Arc<TunnelCounters>,
+    close: Arc<Notify>,
+) -> anyhow::Result<()> {
+    // listen on rx. when anything arrives, forward it to socket_b
+    let read_f = {
+        let socket_b = socket_b.clone();
+        let counts = counts.clone();
+
+        async move {
+            // let max_size = rx_a.max_datagram_size().unwrap_or(8096);
+            let max_size = 8096;
+
+            let mut buf = Vec::with_capacity(max_size);
+
+            loop {
+                buf.clear();
+
+                let n = rx_a.read_buf(&mut buf).await?;
+
+                if n == 0 {
+                    trace!("rx_a finished");
+                    return Ok(());
+                }
+
+                trace!("rx_a -> socket_b = {}", n);
+
+                socket_b.send(&buf[..n]).await?;
+
+                counts.recv(n, 0);
+            }
+        }
+    };
+    // TODO: log errors and return ()
+    let mut read_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(read_f);
+
+    let write_f = async move {
+        loop {
+            socket_b.readable().await?;
+
+            let mut buf = [0; 8096];
+
+            match socket_b.recv(&mut buf).await {
+                Ok(n) => {
+                    trace!("socket_b -> tx_a = {}", n);
+
+                    tx_a.write_all(&buf[..n]).await?;
+
+                    counts.sent(n, 0);
+                }
+                Err(e) => {
+                    error!("failed to read from socket: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    };
+
+    // TODO: log errors and return ()
+    let mut write_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(write_f);
+
+    select! {
+        x = &mut read_f => {
+            trace!("read_f finished: {:?}", x);
+        }
+        x = &mut write_f => {
+            trace!("write_f finished: {:?}", x);
+        }
+        _ = close.notified() => {
+            debug!("flow flushed");
+        }
+    }
+
+    read_f.abort();
+    write_f.abort();
+
+    info!("request finished");
+
+    Ok(())
+}